@@ -0,0 +1,252 @@
+extern crate separator;
+extern crate traffic_lib;
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::process;
+
+use separator::Separatable;
+
+use traffic_lib::export::{read_records, ExportRecord, RecordData};
+use traffic_lib::ExportFormat;
+
+const USAGE: &str = "usage: analyzer <file> [--format Csv|JsonLines|Bincode] [--cpu-clock <Hz>]
+                [--cause <release cause>] [--target <index>] [--from <ms>] [--to <ms>] [--states <state>,<state>,...]
+
+re-evaluates connection records exported by the TrafficEngine. The filters select client connections: --cause by the
+release cause, --target by the index of the target, --from and --to by the time of the first state in millis after
+the first state of the earliest connection (requires --cpu-clock), --states by a sequence of states, which must occur
+consecutively. Server records, which are not paired with a client record, are not filtered.";
+
+/// selects client records
+#[derive(Default)]
+struct Filter {
+    cause: Option<String>,
+    target: Option<u64>,
+    /// time window in cycles relative to the earliest first stamp
+    window: (Option<u64>, Option<u64>),
+    states: Vec<String>,
+}
+
+impl Filter {
+    fn matches(&self, c: &RecordData, base_stamp: u64) -> bool {
+        if self.cause.as_ref().map_or(false, |cause| *cause != c.release_cause) {
+            return false;
+        }
+        if self.target.map_or(false, |target| target != c.server_index) {
+            return false;
+        }
+        if self.window.0.is_some() || self.window.1.is_some() {
+            let t = match c.first_stamp {
+                Some(stamp) => stamp - base_stamp,
+                None => return false,
+            };
+            if self.window.0.map_or(false, |from| t < from) || self.window.1.map_or(false, |to| t >= to) {
+                return false;
+            }
+        }
+        self.states.is_empty() || c.states.windows(self.states.len()).any(|w| w == &self.states[..])
+    }
+}
+
+struct Arguments {
+    file: String,
+    format: ExportFormat,
+    cpu_clock: Option<u64>,
+    filter: Filter,
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    process::exit(2);
+}
+
+fn parse_format(s: &str) -> ExportFormat {
+    match s.to_lowercase().as_str() {
+        "csv" => ExportFormat::Csv,
+        "jsonlines" | "jsonl" => ExportFormat::JsonLines,
+        "bincode" | "bin" => ExportFormat::Bincode,
+        _ => usage_error(&format!("unknown format {}", s)),
+    }
+}
+
+fn parse_number(option: &str, value: &str) -> u64 {
+    value
+        .parse()
+        .unwrap_or_else(|_| usage_error(&format!("{}: {} is not a number", option, value)))
+}
+
+fn parse_arguments() -> Arguments {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut file = None;
+    let mut format = None;
+    let mut cpu_clock = None;
+    let mut filter = Filter::default();
+    let mut window_ms = (None, None);
+    let mut i = 0;
+    while i < args.len() {
+        let option = args[i].as_str();
+        if !option.starts_with("--") {
+            file = Some(args[i].clone());
+            i += 1;
+            continue;
+        }
+        let value = match args.get(i + 1) {
+            Some(value) => value.as_str(),
+            None => usage_error(&format!("missing value of {}", option)),
+        };
+        match option {
+            "--format" => format = Some(parse_format(value)),
+            "--cpu-clock" => cpu_clock = Some(parse_number(option, value)),
+            "--cause" => filter.cause = Some(value.to_string()),
+            "--target" => filter.target = Some(parse_number(option, value)),
+            "--from" => window_ms.0 = Some(parse_number(option, value)),
+            "--to" => window_ms.1 = Some(parse_number(option, value)),
+            "--states" => filter.states = value.split(',').map(|s| s.trim().to_string()).collect(),
+            _ => usage_error(&format!("unknown option {}", option)),
+        }
+        i += 2;
+    }
+    let file = file.unwrap_or_else(|| usage_error("missing file"));
+    // without format we go by the extension of the file
+    let format = format.unwrap_or_else(|| parse_format(file.rsplit('.').next().unwrap_or("")));
+    if window_ms.0.is_some() || window_ms.1.is_some() {
+        let clock = cpu_clock.unwrap_or_else(|| usage_error("--from and --to require --cpu-clock"));
+        filter.window = (
+            window_ms.0.map(|ms| ms * clock / 1000),
+            window_ms.1.map(|ms| ms * clock / 1000),
+        );
+    }
+    Arguments {
+        file,
+        format,
+        cpu_clock,
+        filter,
+    }
+}
+
+fn is_client(c: &RecordData) -> bool {
+    c.role == "Client"
+}
+
+/// same criteria as evaluate_records of the TrafficEngine
+fn completed_client(c: &RecordData) -> bool {
+    (c.release_cause == "PassiveClose" || c.release_cause == "ActiveClose")
+        && c.states.last().map_or(false, |s| s == "Closed" || s == "TimeWait")
+}
+
+fn completed_server(c: &RecordData) -> bool {
+    c.release_cause == "ActiveClose" && c.states.last().map_or(false, |s| s == "Closed")
+}
+
+fn evaluate(records: Vec<ExportRecord>, filter: &Filter, cpu_clock: Option<u64>) {
+    // streamed records are not paired, we pair all client and server records by uuid
+    let mut clients = Vec::new();
+    let mut by_uuid = HashMap::new();
+    for r in records {
+        if let Some(server) = r.server {
+            by_uuid.insert(server.uid, (r.pipeline.clone(), server));
+        }
+        if is_client(&r.record) {
+            clients.push((r.pipeline, r.record));
+        } else {
+            by_uuid.insert(r.record.uid, (r.pipeline, r.record));
+        }
+    }
+    let base_stamp = clients.iter().filter_map(|(_, c)| c.first_stamp).min().unwrap_or(0);
+
+    // per pipeline: selected connections, completed connections, first and last stamp
+    let mut pipelines: BTreeMap<String, (usize, usize, u64, u64)> = BTreeMap::new();
+    let mut completed_count_s = 0;
+    let mut syn_histogram: Vec<(usize, usize)> = Vec::new();
+    for (p, c) in clients.iter() {
+        let c_server = by_uuid.remove(&c.uid);
+        if !filter.matches(c, base_stamp) {
+            continue;
+        }
+        if c_server.map_or(false, |(_, c_server)| completed_server(&c_server)) {
+            completed_count_s += 1;
+        }
+        let syns = c.states.iter().filter(|s| *s == "SynSent").count();
+        if syns > 0 {
+            if syn_histogram.len() < syns {
+                syn_histogram.resize(syns, (0, 0));
+            }
+            if c.states.iter().any(|s| s == "Established") {
+                syn_histogram[syns - 1].0 += 1;
+            } else {
+                syn_histogram[syns - 1].1 += 1;
+            }
+        }
+        let entry = pipelines.entry(p.clone()).or_insert((0, 0, u64::max_value(), 0));
+        entry.0 += 1;
+        if completed_client(c) {
+            entry.1 += 1;
+        }
+        if let Some(first) = c.first_stamp {
+            entry.2 = entry.2.min(first);
+        }
+        if let Some(last) = c.last_stamp() {
+            entry.3 = entry.3.max(last);
+        }
+    }
+
+    // unbound server records are not filtered
+    completed_count_s += by_uuid.values().filter(|(_, c)| completed_server(c)).count();
+
+    let mut total_connections = 0;
+    let (mut min_total, mut max_total) = (u64::max_value(), 0);
+    for (p, (count, completed, min, max)) in &pipelines {
+        total_connections += count;
+        min_total = min_total.min(*min);
+        max_total = max_total.max(*max);
+        if max > min {
+            println!(
+                "{} total used cycles = {}, per connection = {}",
+                p,
+                (max - min).separated_string(),
+                ((max - min) / *count as u64).separated_string()
+            );
+        }
+        println!("{} completed client connections = {} of {}", p, completed, count);
+    }
+
+    println!("total completed server connections = {}", completed_count_s);
+
+    println!("client connections by number of SYNs sent (established / not established):");
+    for (i, (established, failed)) in syn_histogram.iter().enumerate() {
+        println!("{:>8} SYNs = {:9} / {:9}", i + 1, established, failed);
+    }
+
+    println!("unbound server-side connections = {}", by_uuid.len());
+
+    if total_connections > 0 && max_total > min_total {
+        let total = max_total - min_total;
+        match cpu_clock {
+            Some(cpu_clock) => println!(
+                "max used cycles over all pipelines = {}, per connection = {} ({} cps)",
+                total.separated_string(),
+                (total / (total_connections as u64)).separated_string(),
+                cpu_clock / (total / (total_connections as u64 + 1)),
+            ),
+            None => println!(
+                "max used cycles over all pipelines = {}, per connection = {} (cps requires --cpu-clock)",
+                total.separated_string(),
+                (total / (total_connections as u64)).separated_string(),
+            ),
+        }
+    }
+}
+
+pub fn main() {
+    let args = parse_arguments();
+    let records = match read_records(&args.file, args.format) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("cannot read records from {}: {}", args.file, e);
+            process::exit(1);
+        }
+    };
+    println!("{} records read from {}", records.len().separated_string(), args.file);
+    evaluate(records, &args.filter, args.cpu_clock);
+}
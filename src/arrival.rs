@@ -0,0 +1,216 @@
+use std::cmp;
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use {ArrivalConfig, ArrivalProcess};
+
+/// SYNs per packet batch of the injector
+const INJECTOR_BATCH_SIZE: u64 = 32;
+/// open-loop processes: the injector delivers at least this multiple of the mean rate, so that a burst takes at most a
+/// quarter of the burst interval and arrivals which fell due are caught up
+const INJECTOR_HEADROOM: u64 = 4;
+/// open-loop processes: batches per second of the injector, if the batches are not needed for the rate
+const INJECTOR_BATCHES_PER_SEC: u64 = 10000;
+
+/// decides when the next client connection is opened. With the exception of Batched, the arrivals follow their own
+/// clock: arrivals which fall due while the pipeline is busy are caught up, so that the mean rate equals cps_limit.
+/// Batched follows the clock of Constant only, if its rate is lowered below the rate of the injector.
+pub struct ArrivalModel {
+    process: ArrivalProcess,
+    cpu_clock: u64,
+    /// mean cycles between two arrivals, infinite if no connections are opened
+    mean_gap: f64,
+    /// mean gap at the highest rate
+    min_gap: f64,
+    /// cycles between two packet batches of the injector
+    injector_interval: u64,
+    burst_size: usize,
+    /// arrivals left in the current burst
+    left_in_burst: usize,
+    /// TSC of the next arrival, zero before the first arrival
+    next: f64,
+    rng: StdRng,
+}
+
+impl ArrivalModel {
+    /// max_cps is the highest mean rate of a pipeline, which is also the initial rate
+    pub fn new(config: &ArrivalConfig, max_cps: u64, cpu_clock: u64) -> ArrivalModel {
+        let min_gap = cpu_clock as f64 / cmp::max(max_cps, 1) as f64;
+        let injector_interval = match config.process() {
+            ArrivalProcess::Batched => cpu_clock / cmp::max(max_cps, 1) * INJECTOR_BATCH_SIZE,
+            // one batch per mean gap at low rates, batches which carry several arrivals at high rates
+            _ => {
+                let interval = min_gap.max((cpu_clock / INJECTOR_BATCHES_PER_SEC) as f64);
+                let max_interval = min_gap * (INJECTOR_BATCH_SIZE / INJECTOR_HEADROOM) as f64;
+                cmp::max(interval.min(max_interval) as u64, 1)
+            }
+        };
+        ArrivalModel {
+            process: config.process(),
+            cpu_clock,
+            mean_gap: min_gap,
+            min_gap,
+            injector_interval,
+            burst_size: config.burst_size(),
+            left_in_burst: config.burst_size(),
+            next: 0.0,
+            rng: match config.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+        }
+    }
+
+    /// cycles between two packet batches of the SYN injector. Batched uses one SYN per injected packet. The other
+    /// processes use the packets of a batch for the arrivals, which fell due since the last batch, unused packets are
+    /// dropped. Their batches follow each other by a mean gap, but not more often than INJECTOR_BATCHES_PER_SEC,
+    /// unless the rate requires it.
+    pub fn injector_interval(&self) -> u64 {
+        self.injector_interval
+    }
+
+    /// changes the mean rate at now, the rate is limited to the initial rate, zero stops the arrivals
+    pub fn set_rate(&mut self, cps: f64, now: u64) {
+        self.mean_gap = if cps <= 0.0 {
+            f64::INFINITY
+        } else {
+            (self.cpu_clock as f64 / cps).max(self.min_gap)
+        };
+        // a rising rate must not wait for an arrival, which was scheduled with the lower rate
+        let latest = now as f64 + self.mean_gap;
+        if self.next > latest {
+            self.next = latest;
+        }
+    }
+
+    /// false for Batched, whose connections are opened, when the pipeline can take them
+    pub fn open_loop(&self) -> bool {
+        self.process != ArrivalProcess::Batched
+    }
+
+    /// true, if the next connection is due at now, the arrival is then consumed
+    #[inline]
+    pub fn due(&mut self, now: u64) -> bool {
+        if self.mean_gap.is_infinite() {
+            // the clock restarts with the next rate
+            self.next = 0.0;
+            return false;
+        }
+        if self.process == ArrivalProcess::Batched && self.mean_gap <= self.min_gap {
+            self.next = 0.0;
+            return true;
+        }
+        let now = now as f64;
+        if self.next == 0.0 {
+            self.next = now;
+        }
+        if now < self.next {
+            return false;
+        }
+        self.next += match self.process {
+            ArrivalProcess::Poisson => {
+                // inverse transform sampling of the exponential distribution
+                let u: f64 = self.rng.gen();
+                -(1.0 - u).ln() * self.mean_gap
+            }
+            ArrivalProcess::OnOff => {
+                self.left_in_burst -= 1;
+                if self.left_in_burst == 0 {
+                    // the next burst starts burst_size mean gaps after the start of this burst
+                    self.left_in_burst = self.burst_size;
+                    self.burst_size as f64 * self.mean_gap
+                } else {
+                    0.0
+                }
+            }
+            _ => self.mean_gap,
+        };
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPU_CLOCK: u64 = 1_000_000_000;
+
+    fn model(process: ArrivalProcess, burst_size: usize, max_cps: u64) -> ArrivalModel {
+        let config = ArrivalConfig {
+            process: Some(process),
+            burst_size: Some(burst_size),
+            seed: Some(7),
+        };
+        ArrivalModel::new(&config, max_cps, CPU_CLOCK)
+    }
+
+    /// arrivals, when due is polled every step cycles for seconds
+    fn arrivals(model: &mut ArrivalModel, step: u64, seconds: u64) -> Vec<u64> {
+        (1..seconds * CPU_CLOCK / step)
+            .map(|i| i * step)
+            .filter(|now| model.due(*now))
+            .collect()
+    }
+
+    #[test]
+    fn constant_keeps_the_mean_rate() {
+        let mut constant = model(ArrivalProcess::Constant, 32, 1000);
+        let times = arrivals(&mut constant, 1000, 1);
+        assert_eq!(times.len(), 1000);
+        assert!(times.windows(2).all(|pair| pair[1] - pair[0] == CPU_CLOCK / 1000));
+    }
+
+    #[test]
+    fn poisson_keeps_the_mean_rate() {
+        let mut poisson = model(ArrivalProcess::Poisson, 32, 1000);
+        let n = arrivals(&mut poisson, 1000, 10).len();
+        assert!(n > 9700 && n < 10300, "{} arrivals", n);
+    }
+
+    #[test]
+    fn on_off_sends_bursts() {
+        let mut on_off = model(ArrivalProcess::OnOff, 500, 1000);
+        // all arrivals of a burst are due at once
+        assert_eq!((0..600).filter(|_| on_off.due(1)).count(), 500);
+        // the next burst starts burst_size mean gaps later
+        assert!(!on_off.due(1 + 500 * CPU_CLOCK / 1000 - 1));
+        assert_eq!((0..600).filter(|_| on_off.due(1 + 500 * CPU_CLOCK / 1000)).count(), 500);
+        let mut on_off = model(ArrivalProcess::OnOff, 500, 1000);
+        assert_eq!(arrivals(&mut on_off, 1000, 10).len(), 10000);
+    }
+
+    #[test]
+    fn due_arrivals_are_caught_up() {
+        let mut constant = model(ArrivalProcess::Constant, 32, 1000);
+        let gap = CPU_CLOCK / 1000;
+        assert!(constant.due(1));
+        assert_eq!((0..20).filter(|_| constant.due(1 + 10 * gap)).count(), 10);
+        // no arrivals without a rate
+        constant.set_rate(0.0, 1 + 10 * gap);
+        assert!(!constant.due(1 + 100 * gap));
+    }
+
+    #[test]
+    fn injector_delivers_the_bursts() {
+        // one batch per mean gap at low rates, at most INJECTOR_BATCHES_PER_SEC at medium rates
+        let constant = |max_cps| model(ArrivalProcess::Constant, 32, max_cps).injector_interval();
+        assert_eq!(constant(1000), CPU_CLOCK / 1000);
+        assert_eq!(constant(50_000), CPU_CLOCK / 10000);
+        assert_eq!(constant(100_000), 8 * CPU_CLOCK / 100_000);
+        for &max_cps in &[1000, 100_000, 10_000_000] {
+            for &burst_size in &[1, 32, 500] {
+                let interval = model(ArrivalProcess::OnOff, burst_size, max_cps).injector_interval();
+                assert!(INJECTOR_BATCH_SIZE * CPU_CLOCK / interval >= INJECTOR_HEADROOM * max_cps);
+                // the batches of a burst take at most a quarter of the burst interval, rounded up to a batch
+                let batches = (burst_size as f64 / INJECTOR_BATCH_SIZE as f64).ceil() as u64;
+                let burst_interval = burst_size as u64 * CPU_CLOCK / max_cps;
+                assert!(batches * interval <= burst_interval / INJECTOR_HEADROOM + interval);
+            }
+        }
+        assert_eq!(
+            model(ArrivalProcess::Batched, 32, 1000).injector_interval(),
+            CPU_CLOCK / 1000 * INJECTOR_BATCH_SIZE
+        );
+    }
+}
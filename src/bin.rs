@@ -19,7 +19,6 @@ use e2d2::scheduler::StandaloneScheduler;
 
 use netfcts::comm::{MessageFrom, MessageTo};
 use netfcts::comm::PipelineId;
-use netfcts::system::get_mac_from_ifname;
 use netfcts::io::print_tcp_counters;
 use netfcts::conrecord::{ConRecord, HasTcpState, HasConData};
 #[cfg(feature = "profiling")]
@@ -32,19 +31,19 @@ use netfcts::RunTime;
 
 use traffic_lib::{setup_pipelines, Connection, Configuration};
 
-use traffic_lib::L234Data;
+use traffic_lib::Targets;
+use traffic_lib::export::RecordWriter;
+use traffic_lib::replay::{load_conversations, set_replay_payload};
+use traffic_lib::nftraffic::{check_services, run_windows_finished};
 use traffic_lib::ReleaseCause;
 use traffic_lib::TcpState;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::RecvTimeoutError;
 use std::thread;
-use std::time::Duration;
-use std::io::{Write, BufWriter};
-use std::fs::File;
-use std::net::{SocketAddrV4, Ipv4Addr};
+use std::time::{Duration, Instant};
 use std::mem;
 use std::cmp;
 
@@ -52,6 +51,8 @@ use bincode::serialize_into;
 use separator::Separatable;
 use e2d2::native::zcsi::rte_ethdev_api::{rte_eth_stats_get, rte_eth_stats};
 
+/// millis, by which a run with a duration may exceed its run time, until all pipelines have finished their run
+const RUN_TIME_GRACE_MS: u64 = 1000;
 
 fn print_performance_from_stamps(cpu_clock: u64, nr_connections: usize, start_stop_stamps: HashMap<PipelineId, (u64, u64)>) {
     println!("\nperformance data derived from time stamps sent by pipelines:");
@@ -99,22 +100,82 @@ fn print_performance_from_stamps(cpu_clock: u64, nr_connections: usize, start_st
     );
 }
 
+/// counts of the connection records, which are reported at the end of a run
+#[derive(Default)]
+struct RecordSummary {
+    /// completed client connections by pipeline
+    completed_c: Vec<(PipelineId, usize)>,
+    completed_s: usize,
+    /// number of client connections by SYNs sent, (established, not established)
+    syn_histogram: Vec<(usize, usize)>,
+    /// server records without a client record
+    unbound_s: usize,
+}
+
+impl RecordSummary {
+    fn completed_c_mut(&mut self, p: &PipelineId) -> &mut usize {
+        let i = match self.completed_c.iter().position(|(id, _)| id == p) {
+            Some(i) => i,
+            None => {
+                self.completed_c.push((p.clone(), 0));
+                self.completed_c.len() - 1
+            }
+        };
+        &mut self.completed_c[i].1
+    }
+
+    fn add_client(&mut self, p: &PipelineId, c: &ConRecord) {
+        // each SYN is recorded as state SynSent
+        let syns = c.states().iter().filter(|s| **s == TcpState::SynSent).count();
+        if syns > 0 {
+            if self.syn_histogram.len() < syns {
+                self.syn_histogram.resize(syns, (0, 0));
+            }
+            if c.states().contains(&TcpState::Established) {
+                self.syn_histogram[syns - 1].0 += 1;
+            } else {
+                self.syn_histogram[syns - 1].1 += 1;
+            }
+        }
+        if (c.release_cause() == ReleaseCause::PassiveClose || c.release_cause() == ReleaseCause::ActiveClose)
+            && (c.states().last().unwrap() == &TcpState::Closed || c.states().last().unwrap() == &TcpState::TimeWait)
+        {
+            *self.completed_c_mut(p) += 1;
+        }
+    }
+
+    fn add_server(&mut self, c: &ConRecord) {
+        if c.release_cause() == ReleaseCause::ActiveClose && c.states().last().unwrap() == &TcpState::Closed {
+            self.completed_s += 1
+        };
+    }
+
+    fn print(&self) {
+        for (p, completed) in &self.completed_c {
+            println!("{} completed client connections = {}", p, completed);
+        }
+        println!("total completed server connections = {}", self.completed_s);
+        println!("client connections by number of SYNs sent (established / not established):");
+        for (i, (established, failed)) in self.syn_histogram.iter().enumerate() {
+            println!("{:>8} SYNs = {:9} / {:9}", i + 1, established, failed);
+        }
+        println!("unbound server-side connections = {}", self.unbound_s);
+    }
+}
+
 fn evaluate_records(
     con_records_c: &mut Vec<(PipelineId, RecordStore<ConRecord>)>,
     con_records_s: &mut Vec<(PipelineId, RecordStore<ConRecord>)>,
     cpu_clock: u64,
+    writer: &mut RecordWriter,
 ) {
     println!("\nperformance data derived from connection records:");
-    let file = match File::create("c_records.txt") {
-        Err(why) => panic!("couldn't create c_records.txt: {}", why),
-        Ok(file) => file,
-    };
-    let mut f = BufWriter::new(file);
 
     //we are searching for the most extreme time stamps over all pipes
     let mut min_total;
     let mut max_total;
     let mut total_connections = 0;
+    let mut summary = RecordSummary::default();
     {
         let cc = &(con_records_c[0].1);
         min_total = cc.iter().last().unwrap().clone();
@@ -123,20 +184,15 @@ fn evaluate_records(
 
     // a hash map of all server side records by uuid
     let mut by_uuid = HashMap::with_capacity(con_records_s[0].1.len() * con_records_s.len());
-    let mut completed_count_s = 0;
-    for (_p, c_records_server) in con_records_s {
+    for (p, c_records_server) in con_records_s.iter() {
         c_records_server.iter().enumerate().for_each(|(_i, c)| {
-            if c.release_cause() == ReleaseCause::ActiveClose && c.states().last().unwrap() == &TcpState::Closed {
-                completed_count_s += 1
-            };
-            by_uuid.insert(c.uid(), c);
+            summary.add_server(c);
+            by_uuid.insert(c.uid(), (p, c));
         });
     }
 
     for (p, c_records_client) in con_records_c {
-        f.write_all(format!("Pipeline {}:\n", p).as_bytes())
-            .expect("cannot write c_records");
-        let mut completed_count_c = 0;
+        summary.completed_c_mut(p);
         c_records_client.sort_by(|a, b| a.port().cmp(&b.port()));
         if c_records_client.len() > 0 {
             total_connections += c_records_client.len();
@@ -144,40 +200,9 @@ fn evaluate_records(
             let mut max = min;
             c_records_client.iter().enumerate().for_each(|(i, c)| {
                 let uuid = c.uid();
-                let c_server = by_uuid.remove(&uuid);
-                let line = format!("{:6}: {}\n", i, c);
-                f.write_all(line.as_bytes()).expect("cannot write c_records");
-                if c_server.is_some() {
-                    let c_server = c_server.unwrap();
-                    let line = format!(
-                        "        ({:?}, {:21}, {:6}, {:3}, {:7}, {:7}, {:?}, {:?}, +{}, {:?})\n",
-                        c_server.role(),
-                        if c_server.sock().0 != 0 {
-                            let s = c_server.sock();
-                            SocketAddrV4::new(Ipv4Addr::from(s.0), s.1).to_string()
-                        } else {
-                            "none".to_string()
-                        },
-                        c_server.port(),
-                        c_server.server_index(),
-                        c_server.sent_payload_packets(),
-                        c_server.recv_payload_packets(),
-                        c_server.states(),
-                        c_server.release_cause(),
-                        (c_server.get_first_stamp().unwrap() - c.get_first_stamp().unwrap()).separated_string(),
-                        c_server
-                            .deltas_to_base_stamp()
-                            .iter()
-                            .map(|u| u.separated_string())
-                            .collect::<Vec<_>>(),
-                    );
-                    f.write_all(line.as_bytes()).expect("cannot write c_records");
-                }
-                if (c.release_cause() == ReleaseCause::PassiveClose || c.release_cause() == ReleaseCause::ActiveClose)
-                    && c.states().last().unwrap() == &TcpState::Closed
-                {
-                    completed_count_c += 1
-                }
+                let c_server = by_uuid.remove(&uuid).map(|(_, c_server)| c_server);
+                writer.write(p, c, c_server).expect("cannot write c_records");
+                summary.add_client(p, c);
                 if c.get_first_stamp().unwrap_or(u64::MAX) < min.get_first_stamp().unwrap_or(u64::MAX) {
                     min = c
                 }
@@ -201,15 +226,13 @@ fn evaluate_records(
                 max_total = max.clone()
             }
         }
-
-        println!("{} completed client connections = {}", p, completed_count_c);
     }
 
-    println!("total completed server connections = {}", completed_count_s);
-
-    println!("unbound server-side connections = {}", by_uuid.len());
-    by_uuid.iter().enumerate().for_each(|(i, (_, c))| {
+    summary.unbound_s = by_uuid.len();
+    summary.print();
+    by_uuid.iter().enumerate().for_each(|(i, (_, (p, c)))| {
         debug!("{:6}: {}", i, c);
+        writer.write(p, c, None).expect("cannot write c_records");
     });
 
 
@@ -223,7 +246,171 @@ fn evaluate_records(
         );
     }
 
-    f.flush().expect("cannot flush BufWriter");
+    writer.flush().expect("cannot flush c_records");
+}
+
+/// a streamed record, which waits for the record of the other side of its connection
+struct WaitingRecord {
+    pipeline_id: PipelineId,
+    record: ConRecord,
+    /// position in the order of arrival
+    seqn: u64,
+}
+
+/// the waiting records of one side by uuid, in the order of their arrival
+struct WaitingRecords {
+    by_uuid: HashMap<u64, WaitingRecord>,
+    /// (uuid, seqn), entries of records which are no longer waiting are skipped
+    order: VecDeque<(u64, u64)>,
+    next_seqn: u64,
+}
+
+impl WaitingRecords {
+    fn new() -> WaitingRecords {
+        WaitingRecords {
+            by_uuid: HashMap::new(),
+            order: VecDeque::new(),
+            next_seqn: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.by_uuid.len()
+    }
+
+    /// returns the record with the same uuid, which waited before
+    fn insert(&mut self, pipeline_id: &PipelineId, c: &ConRecord) -> Option<WaitingRecord> {
+        let seqn = self.next_seqn;
+        self.next_seqn += 1;
+        self.order.push_back((c.uid(), seqn));
+        self.by_uuid.insert(
+            c.uid(),
+            WaitingRecord {
+                pipeline_id: pipeline_id.clone(),
+                record: c.clone(),
+                seqn,
+            },
+        )
+    }
+
+    fn remove(&mut self, uuid: u64) -> Option<WaitingRecord> {
+        let record = self.by_uuid.remove(&uuid);
+        if self.order.len() > 2 * self.by_uuid.len() + 1024 {
+            // drops the entries of the paired records
+            let by_uuid = &self.by_uuid;
+            self.order
+                .retain(|(uuid, seqn)| by_uuid.get(uuid).map_or(false, |r| r.seqn == *seqn));
+        }
+        record
+    }
+
+    /// removes the record, which waits longest
+    fn pop_oldest(&mut self) -> Option<WaitingRecord> {
+        while let Some((uuid, seqn)) = self.order.pop_front() {
+            if self.by_uuid.get(&uuid).map_or(false, |r| r.seqn == seqn) {
+                return self.by_uuid.remove(&uuid);
+            }
+        }
+        None
+    }
+}
+
+/// pairs the streamed client and server records by their uuid and writes them. A record waits for its counterpart,
+/// as long as less than max_waiting records of its side wait, otherwise the record which waits longest is written
+/// without counterpart.
+struct RecordPairing {
+    clients: WaitingRecords,
+    servers: WaitingRecords,
+    max_waiting: usize,
+    summary: RecordSummary,
+    /// client and server records written
+    written: (usize, usize),
+}
+
+impl RecordPairing {
+    fn new(max_waiting: usize) -> RecordPairing {
+        RecordPairing {
+            clients: WaitingRecords::new(),
+            servers: WaitingRecords::new(),
+            max_waiting: cmp::max(max_waiting, 1),
+            summary: RecordSummary::default(),
+            written: (0, 0),
+        }
+    }
+
+    /// adds the records of a shipment
+    fn add(
+        &mut self,
+        writer: &mut RecordWriter,
+        pipeline_id: &PipelineId,
+        c_records_client: Option<RecordStore<ConRecord>>,
+        c_records_server: Option<RecordStore<ConRecord>>,
+    ) {
+        if let Some(records) = c_records_client {
+            records.iter().for_each(|c| self.add_client(writer, pipeline_id, c));
+        }
+        if let Some(records) = c_records_server {
+            records.iter().for_each(|c| self.add_server(writer, pipeline_id, c));
+        }
+    }
+
+    fn add_client(&mut self, writer: &mut RecordWriter, pipeline_id: &PipelineId, c: &ConRecord) {
+        self.summary.add_client(pipeline_id, c);
+        match self.servers.remove(c.uid()) {
+            Some(server) => self.write(writer, pipeline_id, c, Some(&server.record)),
+            None => {
+                if let Some(older) = self.clients.insert(pipeline_id, c) {
+                    self.write(writer, &older.pipeline_id, &older.record, None);
+                }
+                if self.clients.len() > self.max_waiting {
+                    let oldest = self.clients.pop_oldest().unwrap();
+                    self.write(writer, &oldest.pipeline_id, &oldest.record, None);
+                }
+            }
+        }
+    }
+
+    fn add_server(&mut self, writer: &mut RecordWriter, pipeline_id: &PipelineId, c: &ConRecord) {
+        self.summary.add_server(c);
+        match self.clients.remove(c.uid()) {
+            Some(client) => self.write(writer, &client.pipeline_id, &client.record, Some(c)),
+            None => {
+                if let Some(older) = self.servers.insert(pipeline_id, c) {
+                    self.write_unbound(writer, older);
+                }
+                if self.servers.len() > self.max_waiting {
+                    let oldest = self.servers.pop_oldest().unwrap();
+                    self.write_unbound(writer, oldest);
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, writer: &mut RecordWriter, pipeline_id: &PipelineId, c: &ConRecord, server: Option<&ConRecord>) {
+        writer.write(pipeline_id, c, server).expect("cannot write c_records");
+        self.written.0 += 1;
+        if server.is_some() {
+            self.written.1 += 1;
+        }
+    }
+
+    fn write_unbound(&mut self, writer: &mut RecordWriter, server: WaitingRecord) {
+        writer
+            .write(&server.pipeline_id, &server.record, None)
+            .expect("cannot write c_records");
+        self.written.1 += 1;
+        self.summary.unbound_s += 1;
+    }
+
+    /// writes the records, which still wait for their counterpart
+    fn finish(&mut self, writer: &mut RecordWriter) {
+        while let Some(client) = self.clients.pop_oldest() {
+            self.write(writer, &client.pipeline_id, &client.record, None);
+        }
+        while let Some(server) = self.servers.pop_oldest() {
+            self.write_unbound(writer, server);
+        }
+    }
 }
 
 pub fn main() {
@@ -252,27 +439,23 @@ pub fn main() {
     })
     .expect("error setting Ctrl-C handler");
 
-    let l234data: Vec<L234Data> = run_configuration
-        .engine_configuration
-        .targets
-        .iter()
-        .enumerate()
-        .map(|(i, srv_cfg)| L234Data {
-            mac: srv_cfg
-                .mac
-                .unwrap_or_else(|| get_mac_from_ifname(srv_cfg.linux_if.as_ref().unwrap()).unwrap()),
-            ip: u32::from(srv_cfg.ip),
-            port: srv_cfg.port,
-            server_id: srv_cfg.id.clone(),
-            index: i,
-        })
-        .collect();
+    let targets = Targets::from_config(&run_configuration.engine_configuration.targets);
 
 
+    // the client payload may be replayed from a capture
+    let replay = run_configuration.engine_configuration.engine.replay.as_ref().map(|config| {
+        Arc::new(load_conversations(config).expect("cannot load the TCP streams to replay"))
+    });
+
     let fin_by_client_clone = fin_by_client.clone();
     let f_set_payload = Box::new(
         move |p: &mut Pdu, c: &mut Connection, cdata: Option<CData>, b_fin: &mut bool| {
+            if let Some(ref conversations) = replay {
+                return set_replay_payload(p, c, conversations, b_fin);
+            }
             let pp = c.sent_payload_pkts();
+            // a load series may set the number of payloads per connection
+            let fin_by_client = c.payload_limit().unwrap_or(fin_by_client_clone);
             if pp < 1 {
                 // this is the first payload packet sent by client, headers are already prepared with client and server addresses and ports
                 let sz;
@@ -288,11 +471,11 @@ pub fn main() {
                 p.add_to_payload_tail(sz).expect("insufficient tail room");
                 p.copy_payload_from_u8_slice(&buf, 2); // 2 -> tcp_payload
                 return tcp_payload_size(p);
-            } else if pp == fin_by_client_clone && c.state() < TcpState::CloseWait {
+            } else if pp == fin_by_client && c.state() < TcpState::CloseWait {
                 strip_payload(p);
                 *b_fin = true;
                 return 0;
-            } else if pp < fin_by_client_clone && c.state() < TcpState::CloseWait {
+            } else if pp < fin_by_client && c.state() < TcpState::CloseWait {
                 strip_payload(p);
                 let stamp = unsafe { _rdtsc() };
                 let buf = stamp.to_be_bytes();
@@ -306,7 +489,7 @@ pub fn main() {
         },
     );
 
-    let nr_connections = run_configuration.engine_configuration.test_size.unwrap_or(128);
+    let nr_connections = run_configuration.engine_configuration.test_size();
 
     run_time.start_schedulers().expect("cannot start schedulers");
 
@@ -320,7 +503,7 @@ pub fn main() {
                     pmd_ports,
                     s,
                     run_configuration_cloned.clone(),
-                    l234data.clone(),
+                    targets.clone(),
                     f_set_payload.clone(),
                 );
             },
@@ -335,14 +518,95 @@ pub fn main() {
     // give threads some time to do initialization work
     thread::sleep(Duration::from_millis(1000 as u64));
 
+    check_services(&run_configuration.engine_configuration.engine);
+
     let (mtx, reply_mrx) = run_time.get_main_channel().expect("cannot get main channel");
     // start generator by setting all tasks on scheduler threads to ready state
     mtx.send(MessageFrom::StartEngine).unwrap();
 
+    let detailed_records = run_configuration
+        .engine_configuration
+        .engine
+        .detailed_records
+        .unwrap_or(false);
+    let record_export = run_configuration
+        .engine_configuration
+        .engine
+        .record_export
+        .clone()
+        .unwrap_or_default();
+    let mut record_writer = if detailed_records {
+        match RecordWriter::create(&record_export) {
+            Err(why) => panic!("couldn't create {}: {}", record_export.file(), why),
+            Ok(writer) => Some(writer),
+        }
+    } else {
+        None
+    };
+    // with streaming the pipelines ship their records while running, we write them to disk as they arrive
+    let streaming = detailed_records && run_configuration.engine_configuration.engine.record_streaming.is_some();
+    // streamed records wait for the record of the other side of their connection, at most as many per side as the
+    // pipelines may buffer
+    let mut pairing = run_configuration
+        .engine_configuration
+        .engine
+        .record_streaming
+        .as_ref()
+        .map(|s| RecordPairing::new(2 * s.buffers() * s.buffer_size()));
+
+    let mut tcp_counters_to = HashMap::new();
+    let mut tcp_counters_from = HashMap::new();
+    let mut start_stop_stamps: HashMap<PipelineId, (u64, u64)> = HashMap::new();
+    let mut con_records_s = Vec::with_capacity(64);
+    let mut con_records_c = Vec::with_capacity(64);
+
+    // a run with a duration ends by itself
+    let run_time_ms = run_configuration.engine_configuration.run_time();
+    let run_start = Instant::now();
+    let max_run_time = run_time_ms.map(|ms| Duration::from_millis(ms + RUN_TIME_GRACE_MS));
+
     //main loop
-    println!("press ctrl-c to terminate TrafficEngine ...");
+    match run_time_ms {
+        Some(ms) => println!(
+            "running TrafficEngine for {} ms (warm-up {} ms, cool-down {} ms), press ctrl-c to terminate earlier ...",
+            ms,
+            run_configuration.engine_configuration.warm_up(),
+            run_configuration.engine_configuration.cool_down(),
+        ),
+        None => println!("press ctrl-c to terminate TrafficEngine ..."),
+    }
     while running.load(Ordering::SeqCst) {
-        thread::sleep(Duration::from_millis(200 as u64)); // Sleep for a bit
+        // the pipelines measure the run from their first SYN, which comes after the start of the engine
+        if run_time_ms.is_some() && run_windows_finished() {
+            info!("run time elapsed");
+            break;
+        }
+        if max_run_time.map_or(false, |max| run_start.elapsed() >= max) {
+            warn!("run time elapsed, but not all pipelines have finished their run, as they started late or not at all");
+            break;
+        }
+        if !streaming {
+            thread::sleep(Duration::from_millis(200 as u64)); // Sleep for a bit
+            continue;
+        }
+        match reply_mrx.recv_timeout(Duration::from_millis(200)) {
+            Ok(MessageTo::CRecords(pipeline_id, c_records_client, c_records_server)) => {
+                let writer = record_writer.as_mut().unwrap();
+                pairing
+                    .as_mut()
+                    .unwrap()
+                    .add(writer, &pipeline_id, c_records_client, c_records_server);
+            }
+            Ok(MessageTo::TimeStamps(p, t_start, t_stop)) => {
+                start_stop_stamps.insert(p.clone(), (t_start, t_stop));
+            }
+            Ok(_m) => error!("illegal MessageTo received from reply_to_main channel"),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(e) => {
+                error!("error receiving from reply_to_main channel (reply_mrx): {}", e);
+                break;
+            }
+        }
     }
 
     // request performance data
@@ -351,21 +615,10 @@ pub fn main() {
     // request counters
     mtx.send(MessageFrom::FetchCounter).unwrap();
     // request connection records
-    if run_configuration
-        .engine_configuration
-        .engine
-        .detailed_records
-        .unwrap_or(false)
-    {
+    if detailed_records {
         mtx.send(MessageFrom::FetchCRecords).unwrap();
     }
 
-    let mut tcp_counters_to = HashMap::new();
-    let mut tcp_counters_from = HashMap::new();
-    let mut start_stop_stamps: HashMap<PipelineId, (u64, u64)> = HashMap::new();
-    let mut con_records_s = Vec::with_capacity(64);
-    let mut con_records_c = Vec::with_capacity(64);
-
     // loop for replies
     loop {
         match reply_mrx.recv_timeout(Duration::from_millis(1000)) {
@@ -376,9 +629,21 @@ pub fn main() {
                 tcp_counters_to.insert(pipeline_id.clone(), tcp_counter_to);
                 tcp_counters_from.insert(pipeline_id, tcp_counter_from);
             }
-            Ok(MessageTo::CRecords(pipeline_id, Some(c_records_client), Some(c_records_server))) => {
-                con_records_c.push((pipeline_id.clone(), c_records_client));
-                con_records_s.push((pipeline_id, c_records_server));
+            Ok(MessageTo::CRecords(pipeline_id, c_records_client, c_records_server)) => {
+                if streaming {
+                    let writer = record_writer.as_mut().unwrap();
+                    pairing
+                        .as_mut()
+                        .unwrap()
+                        .add(writer, &pipeline_id, c_records_client, c_records_server);
+                } else {
+                    if let Some(c_records_client) = c_records_client {
+                        con_records_c.push((pipeline_id.clone(), c_records_client));
+                    }
+                    if let Some(c_records_server) = c_records_server {
+                        con_records_s.push((pipeline_id, c_records_server));
+                    }
+                }
             }
             Ok(MessageTo::TimeStamps(p, t_start, t_stop)) => {
                 start_stop_stamps.insert(p.clone(), (t_start, t_stop));
@@ -410,16 +675,24 @@ pub fn main() {
         print_performance_from_stamps(run_configuration.system_data.cpu_clock, nr_connections, start_stop_stamps);
     }
 
-    if run_configuration
-        .engine_configuration
-        .engine
-        .detailed_records
-        .unwrap_or(false)
-    {
+    if streaming {
+        let writer = record_writer.as_mut().unwrap();
+        let pairing = pairing.as_mut().unwrap();
+        pairing.finish(writer);
+        writer.flush().expect("cannot flush c_records");
+        println!(
+            "\nconnection records written to {}: {} client side, {} server side",
+            record_export.file(),
+            pairing.written.0.separated_string(),
+            pairing.written.1.separated_string()
+        );
+        pairing.summary.print();
+    } else if detailed_records {
         evaluate_records(
             &mut con_records_c,
             &mut con_records_s,
             run_configuration.system_data.cpu_clock,
+            record_writer.as_mut().unwrap(),
         );
     }
 
@@ -1,7 +1,5 @@
 use std::net::Ipv4Addr;
 use std::collections::VecDeque;
-//use std::collections::HashMap;
-//use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicUsize, Ordering, };
 use std::fmt;
 use std::mem;
@@ -9,35 +7,299 @@ use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
+use std::arch::x86_64::_rdtsc;
 use e2d2::interface::{PortQueue, L4Flow};
+use eui48::MacAddress;
 use netfcts::timer_wheel::TimerWheel;
 use PipelineId;
+use {RetransmissionConfig, PortAllocation, PortAllocationConfig, RecordOverflow};
+use congestion::CongestionState;
+use tcp_options::NegotiatedOptions;
 
 use netfcts::tcp_common::*;
 use netfcts::conrecord::{ConRecord, HasTcpState};
 use netfcts::utils::shuffle_ports;
 use netfcts::{RecordStore, ConRecordOperations};
 use netfcts::recstore::TEngineStore;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+
+/// retransmission parameters in millis, derived once from the RetransmissionConfig
+#[derive(Debug, Clone, Copy)]
+pub struct RtoParameters {
+    pub initial: u32,
+    pub min: u32,
+    pub max: u32,
+    pub max_retries: u8,
+    pub max_syn_retries: u8,
+}
+
+impl RtoParameters {
+    pub fn from_config(config: &RetransmissionConfig) -> RtoParameters {
+        RtoParameters {
+            initial: config.initial_rto() as u32,
+            min: config.min_rto() as u32,
+            max: config.max_rto() as u32,
+            max_retries: config.max_retries(),
+            max_syn_retries: config.syn_retries(),
+        }
+    }
+}
+
+/// timeouts of the connection phases in cycles, derived once from the StateTimeoutsConfig
+#[derive(Debug, Clone, Copy)]
+pub struct StateTimeouts {
+    pub handshake: u64,
+    /// refreshed by each segment received in sequence
+    pub idle: u64,
+    pub fin_wait: u64,
+    pub last_ack: u64,
+}
+
+impl StateTimeouts {
+    /// None for states, which are not guarded by a state timeout
+    #[inline]
+    pub fn for_state(&self, state: TcpState) -> Option<u64> {
+        match state {
+            TcpState::SynSent | TcpState::SynReceived => Some(self.handshake),
+            TcpState::Established | TcpState::CloseWait => Some(self.idle),
+            TcpState::FinWait1 | TcpState::FinWait2 | TcpState::Closing => Some(self.fin_wait),
+            TcpState::LastAck => Some(self.last_ack),
+            _ => None,
+        }
+    }
+}
+
+/// clock granularity used in the rto calculation (RFC 6298), equals the timer wheel resolution
+const RTO_CLOCK_GRANULARITY_MS: u32 = 10;
+
+/// a is before b in sequence space
+#[inline]
+pub fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[inline]
+pub fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+/// a sent segment, kept until it is acknowledged by the peer
+#[derive(Debug)]
+pub struct Segment {
+    pub seqn: u32,
+    pub syn: bool,
+    pub fin: bool,
+    pub payload: Vec<u8>,
+    /// options of a SYN, respectively SYN-ACK
+    pub options: Vec<u8>,
+    /// the server side does not know its peers in advance, so we note down the mac of the DUT
+    pub peer_mac: MacAddress,
+    /// our ip address as seen by the peer
+    pub local_ip: u32,
+    /// tsc when the segment was sent the first time
+    sent_at: u64,
+    retransmissions: u8,
+}
+
+impl Segment {
+    /// sequence no following the segment
+    #[inline]
+    pub fn seqn_end(&self) -> u32 {
+        self.seqn
+            .wrapping_add(self.payload.len() as u32)
+            .wrapping_add(self.syn as u32)
+            .wrapping_add(self.fin as u32)
+    }
 
+    #[inline]
+    pub fn retransmissions(&self) -> u8 {
+        self.retransmissions
+    }
+}
+
+/// segments in flight, oldest first. Buffers of acknowledged segments are kept for re-use.
+#[derive(Debug)]
+struct RetransmissionQueue {
+    segments: VecDeque<Segment>,
+    spares: Vec<Segment>,
+}
+
+impl RetransmissionQueue {
+    fn new() -> RetransmissionQueue {
+        RetransmissionQueue {
+            segments: VecDeque::with_capacity(4),
+            spares: Vec::with_capacity(4),
+        }
+    }
+
+    #[inline]
+    fn clear(&mut self) {
+        while let Some(segment) = self.segments.pop_front() {
+            self.spares.push(segment);
+        }
+    }
+}
+
+/// maximum number of separate sequence ranges, which are held back for reassembly
+const REASSEMBLY_QUEUE_SIZE: usize = 8;
+
+/// received out-of-order data, which is not yet acknowledged
+#[derive(Debug, Clone, Copy)]
+struct OutOfOrderRange {
+    seqn: u32,
+    seqn_end: u32,
+    /// number of segments merged into this range
+    segments: u16,
+    /// the last segment carries a FIN, which follows seqn_end
+    fin: bool,
+}
+
+/// out-of-order segments, sorted by sequence no. As the engine does not evaluate the payload of subsequent segments, only
+/// the sequence ranges are kept.
+#[derive(Debug)]
+struct ReassemblyQueue {
+    ranges: VecDeque<OutOfOrderRange>,
+}
+
+impl ReassemblyQueue {
+    fn new() -> ReassemblyQueue {
+        ReassemblyQueue {
+            ranges: VecDeque::with_capacity(REASSEMBLY_QUEUE_SIZE),
+        }
+    }
+
+    /// inserts a segment and merges overlapping or adjacent ranges, returns false if the queue is full
+    fn insert(&mut self, seqn: u32, seqn_end: u32, fin: bool) -> bool {
+        let pos = self
+            .ranges
+            .iter()
+            .position(|r| seq_le(seqn, r.seqn_end))
+            .unwrap_or(self.ranges.len());
+        if pos < self.ranges.len() && seq_le(self.ranges[pos].seqn, seqn_end) {
+            // overlaps with or touches the range at pos
+            {
+                let r = &mut self.ranges[pos];
+                if seq_le(r.seqn, seqn) && seq_le(seqn_end, r.seqn_end) {
+                    // a duplicate, which may carry the FIN
+                    r.fin |= fin && seqn_end == r.seqn_end;
+                    return true;
+                }
+                if seq_lt(seqn, r.seqn) {
+                    r.seqn = seqn;
+                }
+                if seq_lt(r.seqn_end, seqn_end) {
+                    r.seqn_end = seqn_end;
+                    r.fin = fin;
+                } else if seqn_end == r.seqn_end {
+                    r.fin |= fin;
+                }
+                r.segments = r.segments.saturating_add(1);
+            }
+            while pos + 1 < self.ranges.len() && seq_le(self.ranges[pos + 1].seqn, self.ranges[pos].seqn_end) {
+                let next = self.ranges.remove(pos + 1).unwrap();
+                let r = &mut self.ranges[pos];
+                if seq_lt(r.seqn_end, next.seqn_end) {
+                    r.seqn_end = next.seqn_end;
+                    r.fin = next.fin;
+                } else if next.seqn_end == r.seqn_end {
+                    r.fin |= next.fin;
+                }
+                r.segments = r.segments.saturating_add(next.segments);
+            }
+            true
+        } else if self.ranges.len() < REASSEMBLY_QUEUE_SIZE {
+            self.ranges.insert(
+                pos,
+                OutOfOrderRange {
+                    seqn,
+                    seqn_end,
+                    segments: 1,
+                    fin,
+                },
+            );
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// what is needed to reset a connection, after it was timed out and released
+#[derive(Debug, Clone, Copy)]
+pub struct PendingRst {
+    /// client side: our port, server side: socket of the DUT
+    pub sock: (u32, u16),
+    /// client side only: the target
+    pub server_index: usize,
+    /// server side only: mac of the DUT, our ip as seen by the DUT and the listen port
+    pub peer_mac: MacAddress,
+    pub local_ip: u32,
+    pub local_port: u16,
+    pub seqn: u32,
+    /// None, if the connection was not yet synchronized
+    pub ackn: Option<u32>,
+}
 
 //#[repr(align(64))]
 #[derive(Debug)]
 pub struct Connection {
     record: Option<Box<DetailedRecord>>,
     pub wheel_slot_and_index: (u16, u16),
+    pub rto_slot_and_index: (u16, u16),
     /// next client side sequence no towards DUT
     pub seqn_nxt: u32,
-    // oldest unacknowledged sequence no
-    //    pub seqn_una: u32,
+    /// congestion window in bytes, unlimited without congestion control
+    pub cwnd: u32,
+    /// oldest unacknowledged sequence no (SND.UNA)
+    pub seqn_una: u32,
     /// current ack no towards DUT (expected seqn)
     pub ackn_nxt: u32,
+    /// client side: sequence no of the first payload byte towards and from the DUT, i.e. the initial sequence no + 1
+    pub seqn_initial: u32,
+    pub ackn_initial: u32,
+    /// send window advertised by the peer (SND.WND), and seqn and ackn of the segment which updated it (SND.WL1, SND.WL2)
+    snd_wnd: u32,
+    snd_wl1: u32,
+    snd_wl2: u32,
+    /// largest send window the peer has advertised
+    max_snd_wnd: u32,
+    unacked: Option<Box<RetransmissionQueue>>,
+    reassembly: Option<Box<ReassemblyQueue>>,
+    /// payload beyond the MSS of the peer, which is sent in further segments
+    unsent: Option<Box<Vec<u8>>>,
+    congestion: CongestionState,
+    negotiated: NegotiatedOptions,
+    /// smoothed round trip time, its variation and the current retransmission timeout, all in millis
+    srtt: u32,
+    rttvar: u32,
+    rto: u32,
+    rtt_measured: bool,
+    rto_armed: bool,
+    /// sending of payload is paused, until the peer opens its window
+    blocked: bool,
+    /// the retransmission timer is used as persist timer for zero window probes
+    persist: bool,
+    /// the payload function has more payload to send, without waiting for the DUT
+    payload_pending: bool,
+    /// client side: payloads to send before the FIN, if it differs from fin_by_client
+    payload_limit: Option<usize>,
+    /// server side only: mac of the DUT, our ip as seen by the DUT and the listen port
+    peer_mac: MacAddress,
+    local_ip: u32,
+    local_port: u16,
+    /// tsc of the last segment received in sequence
+    last_activity: u64,
     /// either our IP, if we are client, or IP of DUT if we are server
     client_ip: u32,
     sent_payload_packets: u16,
     recv_payload_packets: u16,
     /// either our port, if we are client, or port of DUT if we are server
     client_port: u16,
-    server_index: u8,
+    /// client side only: index of client_ip in the source address pool
+    pool_index: u16,
+    server_index: u32,
     state: TcpState,
 }
 
@@ -47,12 +309,45 @@ impl Connection {
     #[inline]
     fn initialize(&mut self, client_sock: Option<(u32, u16)>, role: TcpRole) {
         self.seqn_nxt = 0;
-        //self.seqn_una = 0;
+        self.cwnd = u32::max_value();
+        self.congestion = CongestionState::new();
+        self.negotiated = NegotiatedOptions::new();
+        self.seqn_una = 0;
         self.ackn_nxt = 0;
+        self.seqn_initial = 0;
+        self.ackn_initial = 0;
         let s = client_sock.unwrap_or((0, 0));
         self.client_ip = s.0;
         self.client_port = s.1;
+        self.pool_index = 0;
         self.wheel_slot_and_index = (0, 0);
+        self.rto_slot_and_index = (0, 0);
+        if self.unacked.is_some() {
+            self.unacked.as_mut().unwrap().clear();
+        }
+        if self.reassembly.is_some() {
+            self.reassembly.as_mut().unwrap().ranges.clear();
+        }
+        if self.unsent.is_some() {
+            self.unsent.as_mut().unwrap().clear();
+        }
+        self.srtt = 0;
+        self.rttvar = 0;
+        self.rto = 0;
+        self.rtt_measured = false;
+        self.rto_armed = false;
+        self.snd_wnd = 0;
+        self.snd_wl1 = 0;
+        self.snd_wl2 = 0;
+        self.max_snd_wnd = 0;
+        self.blocked = false;
+        self.persist = false;
+        self.payload_pending = false;
+        self.payload_limit = None;
+        self.peer_mac = MacAddress::nil();
+        self.local_ip = 0;
+        self.local_port = 0;
+        self.last_activity = 0;
         self.server_index = 0;
         self.sent_payload_packets = 0;
         self.recv_payload_packets = 0;
@@ -60,8 +355,21 @@ impl Connection {
     }
 
     #[inline]
-    fn initialize_with_details(&mut self, sock: Option<(u32, u16)>, role: TcpRole, store: Rc<RefCell<TEngineStore>>) {
+    /// without store the connection is not recorded, e.g. due to an overflow of the record buffers
+    fn initialize_with_details(
+        &mut self,
+        sock: Option<(u32, u16)>,
+        role: TcpRole,
+        store: Option<Rc<RefCell<TEngineStore>>>,
+    ) {
         self.initialize(sock, role);
+        let store = match store {
+            Some(store) => store,
+            None => {
+                self.record = None;
+                return;
+            }
+        };
         if self.record.is_none() {
             self.record = Some(Box::new(DetailedRecord::new(store)));
         } else {
@@ -74,11 +382,38 @@ impl Connection {
     fn new() -> Connection {
         Connection {
             seqn_nxt: 0, //next seqn towards DUT
-            //seqn_una: 0, // acked by DUT
+            cwnd: u32::max_value(),
+            seqn_una: 0, // acked by DUT
             ackn_nxt: 0, //next ackn towards DUT
+            seqn_initial: 0,
+            ackn_initial: 0,
             wheel_slot_and_index: (0, 0),
+            rto_slot_and_index: (0, 0),
+            unacked: None,
+            reassembly: None,
+            unsent: None,
+            congestion: CongestionState::new(),
+            negotiated: NegotiatedOptions::new(),
+            srtt: 0,
+            rttvar: 0,
+            rto: 0,
+            rtt_measured: false,
+            rto_armed: false,
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            max_snd_wnd: 0,
+            blocked: false,
+            persist: false,
+            payload_pending: false,
+            payload_limit: None,
+            peer_mac: MacAddress::nil(),
+            local_ip: 0,
+            local_port: 0,
+            last_activity: 0,
             client_port: 0,
             client_ip: 0,
+            pool_index: 0,
             server_index: 0,
             sent_payload_packets: 0,
             recv_payload_packets: 0,
@@ -130,6 +465,24 @@ impl Connection {
         self.client_port
     }
 
+    #[inline]
+    pub fn client_ip(&self) -> u32 {
+        self.client_ip
+    }
+
+    /// server side only: identifies the connection in the connection manager and on the timer wheels
+    #[inline]
+    pub fn server_key(&self) -> ServerKey {
+        (self.client_ip, self.client_port, self.local_port)
+    }
+
+    /// client side only: identifies the connection in the connection manager and on the timer wheels, the upper 16 bits
+    /// are the index of the source address in the address pool, the lower 16 bits the port
+    #[inline]
+    pub fn key(&self) -> u32 {
+        (self.pool_index as u32) << 16 | self.client_port as u32
+    }
+
     #[inline]
     fn in_use(&self) -> bool {
         self.client_port != 0
@@ -145,7 +498,7 @@ impl Connection {
         if self.record.is_some() {
             self.record.as_mut().unwrap().set_server_index(index)
         }
-        self.server_index = index as u8;
+        self.server_index = index as u32;
     }
 
 
@@ -184,6 +537,102 @@ impl Connection {
         self.recv_payload_packets as usize
     }
 
+    /// client side: payload bytes sent to the DUT, including a FIN once sent
+    #[inline]
+    pub fn sent_payload_bytes(&self) -> u32 {
+        self.seqn_nxt.wrapping_sub(self.seqn_initial)
+    }
+
+    /// client side: payload bytes received in sequence from the DUT
+    #[inline]
+    pub fn recv_payload_bytes(&self) -> u32 {
+        self.ackn_nxt.wrapping_sub(self.ackn_initial)
+    }
+
+    /// to be set by the payload function, if the connection shall send its next payload without waiting for the DUT
+    #[inline]
+    pub fn set_payload_pending(&mut self, pending: bool) {
+        self.payload_pending = pending;
+    }
+
+    /// true, if the payload function has more payload or the last payload exceeded the MSS of the peer
+    #[inline]
+    pub fn payload_pending(&self) -> bool {
+        self.payload_pending || self.has_unsent()
+    }
+
+    /// takes up the payload, which does not fit into the current segment
+    #[inline]
+    pub fn unsent_mut(&mut self) -> &mut Vec<u8> {
+        if self.unsent.is_none() {
+            self.unsent = Some(Box::new(Vec::new()));
+        }
+        self.unsent.as_mut().unwrap()
+    }
+
+    #[inline]
+    pub fn unsent(&self) -> &[u8] {
+        match self.unsent {
+            Some(ref unsent) => &unsent[..],
+            None => &[],
+        }
+    }
+
+    #[inline]
+    pub fn has_unsent(&self) -> bool {
+        self.unsent.as_ref().map_or(false, |unsent| unsent.len() > 0)
+    }
+
+    /// removes n bytes, which were sent, from the front of the unsent payload
+    #[inline]
+    pub fn consume_unsent(&mut self, n: usize) {
+        if let Some(ref mut unsent) = self.unsent {
+            unsent.drain(..n);
+        }
+    }
+
+    #[inline]
+    pub fn clear_unsent(&mut self) {
+        if let Some(ref mut unsent) = self.unsent {
+            unsent.clear();
+        }
+    }
+
+    #[inline]
+    pub fn set_payload_limit(&mut self, limit: Option<usize>) {
+        self.payload_limit = limit;
+    }
+
+    /// payloads to send before the FIN, None if the payload function applies fin_by_client
+    #[inline]
+    pub fn payload_limit(&self) -> Option<usize> {
+        self.payload_limit
+    }
+
+    /// notes down the addresses of a server connection, which are needed to send packets without a trigger from the DUT
+    #[inline]
+    pub fn set_peer_addresses(&mut self, peer_mac: MacAddress, local_ip: u32) {
+        self.peer_mac = peer_mac;
+        self.local_ip = local_ip;
+    }
+
+    /// a connection which timed out is reset, unless it is already closed
+    #[inline]
+    pub fn pending_rst(&self) -> Option<PendingRst> {
+        match self.state {
+            TcpState::Listen | TcpState::Closed => None,
+            state => Some(PendingRst {
+                sock: (self.client_ip, self.client_port),
+                server_index: self.server_index as usize,
+                peer_mac: self.peer_mac,
+                local_ip: self.local_ip,
+                local_port: self.local_port,
+                seqn: self.seqn_nxt,
+                ackn: if state == TcpState::SynSent { None } else { Some(self.ackn_nxt) },
+            }),
+        }
+    }
+
     #[inline]
     fn release(&mut self) {
         self.client_port = 0;
@@ -193,6 +642,357 @@ impl Connection {
     }
 
 
+    /// appends a sent segment to the retransmission queue, the buffer of a former segment is re-used
+    #[inline]
+    pub fn push_unacked(
+        &mut self,
+        seqn: u32,
+        syn: bool,
+        fin: bool,
+        payload: &[u8],
+        options: &[u8],
+        now: u64,
+    ) -> &mut Segment {
+        if self.unacked.is_none() {
+            self.unacked = Some(Box::new(RetransmissionQueue::new()));
+        }
+        let queue = self.unacked.as_mut().unwrap();
+        let mut segment = queue.spares.pop().unwrap_or_else(|| Segment {
+            seqn,
+            syn,
+            fin,
+            payload: Vec::with_capacity(payload.len()),
+            options: Vec::new(),
+            peer_mac: MacAddress::nil(),
+            local_ip: 0,
+            sent_at: now,
+            retransmissions: 0,
+        });
+        segment.seqn = seqn;
+        segment.syn = syn;
+        segment.fin = fin;
+        segment.payload.clear();
+        segment.payload.extend_from_slice(payload);
+        segment.options.clear();
+        segment.options.extend_from_slice(options);
+        segment.sent_at = now;
+        segment.retransmissions = 0;
+        queue.segments.push_back(segment);
+        queue.segments.back_mut().unwrap()
+    }
+
+    /// the oldest segment waiting for its acknowledgement, if any
+    #[inline]
+    pub fn unacked(&self) -> Option<&Segment> {
+        match self.unacked {
+            Some(ref queue) => queue.segments.front(),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn unacked_mut(&mut self) -> Option<&mut Segment> {
+        match self.unacked {
+            Some(ref mut queue) => queue.segments.front_mut(),
+            _ => None,
+        }
+    }
+
+    /// current retransmission timeout in millis
+    #[inline]
+    pub fn rto(&self, params: &RtoParameters) -> u32 {
+        if self.rto == 0 {
+            params.initial
+        } else {
+            self.rto
+        }
+    }
+
+    /// smoothed round trip time in millis, 0 if not yet measured
+    #[inline]
+    pub fn srtt(&self) -> u32 {
+        self.srtt
+    }
+
+    #[inline]
+    pub fn rto_armed(&self) -> bool {
+        self.rto_armed
+    }
+
+    /// processes the ack no of a received segment, returns true if at least one unacked segment became acknowledged
+    #[inline]
+    pub fn ack_received(&mut self, ack_num: u32, now: u64, cpu_clock: u64, params: &RtoParameters) -> bool {
+        if seq_lt(self.seqn_una, ack_num) {
+            self.seqn_una = ack_num;
+        }
+        let mut rtt_sample = None;
+        let mut acked = false;
+        if let Some(ref mut queue) = self.unacked {
+            while queue.segments.front().map_or(false, |s| seq_le(s.seqn_end(), ack_num)) {
+                let segment = queue.segments.pop_front().unwrap();
+                acked = true;
+                // Karn's algorithm: no samples from retransmitted segments
+                if segment.retransmissions == 0 {
+                    rtt_sample = Some(((now - segment.sent_at) * 1000 / cpu_clock) as u32);
+                }
+                queue.spares.push(segment);
+            }
+        }
+        if let Some(rtt) = rtt_sample {
+            self.update_rto(rtt, params);
+        }
+        acked
+    }
+
+    /// RFC 6298 estimator, rtt in millis
+    #[inline]
+    fn update_rto(&mut self, rtt: u32, params: &RtoParameters) {
+        if !self.rtt_measured {
+            self.srtt = rtt;
+            self.rttvar = rtt / 2;
+            self.rtt_measured = true;
+        } else {
+            let delta = if self.srtt > rtt { self.srtt - rtt } else { rtt - self.srtt };
+            self.rttvar = (3 * self.rttvar + delta) / 4;
+            self.srtt = (7 * self.srtt + rtt) / 8;
+        }
+        let rto = self.srtt + cmp::max(RTO_CLOCK_GRANULARITY_MS, 4 * self.rttvar);
+        self.rto = cmp::min(cmp::max(rto, params.min), params.max);
+    }
+
+    /// takes over the window of an acceptable segment (RFC 793, 3.9) and advances SND.UNA
+    #[inline]
+    pub fn update_send_window(&mut self, seqn: u32, ackn: u32, window: u32) {
+        if seq_le(self.seqn_una, ackn) && seq_le(ackn, self.seqn_nxt) {
+            if seq_lt(self.snd_wl1, seqn) || (self.snd_wl1 == seqn && seq_le(self.snd_wl2, ackn)) {
+                self.snd_wnd = window;
+                self.snd_wl1 = seqn;
+                self.snd_wl2 = ackn;
+                self.max_snd_wnd = cmp::max(self.max_snd_wnd, window);
+            }
+            self.seqn_una = ackn;
+        }
+    }
+
+    /// initializes the send window from the SYN, respectively SYN-ACK, of the peer
+    #[inline]
+    pub fn init_send_window(&mut self, seqn: u32, ackn: u32, window: u32) {
+        self.snd_wnd = window;
+        self.snd_wl1 = seqn;
+        self.snd_wl2 = ackn;
+        self.max_snd_wnd = window;
+    }
+
+    #[inline]
+    pub fn send_window(&self) -> u32 {
+        self.snd_wnd
+    }
+
+    /// number of bytes, which we may send without overrunning the window of the peer or the congestion window
+    #[inline]
+    pub fn usable_window(&self) -> u32 {
+        let window = cmp::min(self.snd_wnd, self.cwnd);
+        let in_flight = self.flight_size();
+        if window > in_flight {
+            window - in_flight
+        } else {
+            0
+        }
+    }
+
+    /// number of bytes of a segment of size bytes, which we send now, avoiding the silly window syndrome on the sender
+    /// side (RFC 9293, 3.8.6.2.1): a segment which fits into the usable window is sent completely. Otherwise a part of it
+    /// is sent, if this is at least half of the largest window the peer has advertised or if nothing is in flight, as
+    /// then no ACK will open the window further. Returns 0, if the segment must wait.
+    #[inline]
+    pub fn sendable(&self, size: u32) -> u32 {
+        let usable = self.usable_window();
+        if size <= usable {
+            size
+        } else if usable > 0 && (usable >= self.max_snd_wnd / 2 || self.flight_size() == 0) {
+            usable
+        } else {
+            0
+        }
+    }
+
+    #[inline]
+    pub fn negotiated(&self) -> &NegotiatedOptions {
+        &self.negotiated
+    }
+
+    #[inline]
+    pub fn set_negotiated(&mut self, negotiated: NegotiatedOptions) {
+        self.negotiated = negotiated;
+    }
+
+    /// maximum segment size of the peer, if options were negotiated
+    #[inline]
+    pub fn snd_mss(&self) -> Option<u16> {
+        if self.negotiated.snd_mss > 0 {
+            Some(self.negotiated.snd_mss)
+        } else {
+            None
+        }
+    }
+
+    /// number of bytes sent, but not yet acknowledged
+    #[inline]
+    pub fn flight_size(&self) -> u32 {
+        self.seqn_nxt.wrapping_sub(self.seqn_una)
+    }
+
+    /// the congestion window together with the state of the congestion control
+    #[inline]
+    pub fn cwnd_and_congestion_mut(&mut self) -> (&mut u32, &mut CongestionState) {
+        (&mut self.cwnd, &mut self.congestion)
+    }
+
+    /// marks the oldest unacked segment as retransmitted without backing off the timer, e.g. for a fast retransmit
+    #[inline]
+    pub fn note_retransmission(&mut self) {
+        if let Some(segment) = self.unacked_mut() {
+            segment.retransmissions = segment.retransmissions.saturating_add(1);
+        }
+    }
+
+    #[inline]
+    pub fn touch(&mut self, now: u64) {
+        self.last_activity = now;
+    }
+
+    /// if the idle timer expired although the connection was active meanwhile, the remaining idle time in cycles
+    #[inline]
+    fn idle_remaining(&self, now: u64, timeouts: &StateTimeouts) -> Option<u64> {
+        match self.state {
+            TcpState::Established | TcpState::CloseWait if self.in_use() => {
+                let idle = now.saturating_sub(self.last_activity);
+                if idle < timeouts.idle {
+                    Some(timeouts.idle - idle)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// holds back a segment of len payload bytes, which arrived ahead of ackn_nxt, together with its FIN flag. Returns
+    /// false, if the segment is dropped because the reassembly queue is full.
+    #[inline]
+    pub fn buffer_out_of_order(&mut self, seqn: u32, len: u32, fin: bool) -> bool {
+        if self.reassembly.is_none() {
+            self.reassembly = Some(Box::new(ReassemblyQueue::new()));
+        }
+        self.reassembly.as_mut().unwrap().insert(seqn, seqn.wrapping_add(len), fin)
+    }
+
+    /// advances ackn_nxt over buffered segments, which became contiguous. Returns the number of these segments and
+    /// true, if the last of them carries a FIN. The FIN itself is not included in ackn_nxt.
+    #[inline]
+    pub fn reassemble(&mut self) -> (u16, bool) {
+        let mut delivered = 0;
+        let mut fin = false;
+        if let Some(ref mut queue) = self.reassembly {
+            while !queue.ranges.is_empty() && seq_le(queue.ranges[0].seqn, self.ackn_nxt) {
+                let r = queue.ranges.pop_front().unwrap();
+                if seq_lt(self.ackn_nxt, r.seqn_end) {
+                    self.ackn_nxt = r.seqn_end;
+                }
+                fin |= r.fin && r.seqn_end == self.ackn_nxt;
+                delivered += r.segments;
+            }
+        }
+        (delivered, fin)
+    }
+
+    /// true if all sent data is acknowledged
+    #[inline]
+    pub fn all_acked(&self) -> bool {
+        self.seqn_una == self.seqn_nxt
+    }
+
+    #[inline]
+    pub fn blocked(&self) -> bool {
+        self.blocked
+    }
+
+    #[inline]
+    pub fn set_blocked(&mut self, blocked: bool) {
+        self.blocked = blocked;
+    }
+
+    #[inline]
+    pub fn in_persist(&self) -> bool {
+        self.persist
+    }
+
+    #[inline]
+    pub fn set_persist(&mut self, persist: bool) {
+        self.persist = persist;
+    }
+
+    /// doubles the interval of zero window probes
+    #[inline]
+    pub fn persist_backoff(&mut self, params: &RtoParameters) {
+        let rto = self.rto(params);
+        self.rto = cmp::min(rto.saturating_mul(2), params.max);
+    }
+
+    /// doubles the retransmission timeout, returns false if the unacked segment exceeded its retransmissions
+    #[inline]
+    pub fn backoff(&mut self, params: &RtoParameters) -> bool {
+        let rto = self.rto(params);
+        self.rto = cmp::min(rto.saturating_mul(2), params.max);
+        match self.unacked_mut() {
+            Some(segment) if segment.syn && segment.retransmissions < params.max_syn_retries => {
+                segment.retransmissions += 1;
+                true
+            }
+            Some(segment) if !segment.syn && segment.retransmissions < params.max_retries => {
+                segment.retransmissions += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// (re-)starts the retransmission timer of a client connection, which is keyed by the port
+    #[inline]
+    pub fn arm_rto_c(&mut self, wheel: &mut TimerWheel<u32>, cpu_clock: u64, params: &RtoParameters) {
+        self.disarm_rto_c(wheel);
+        let timeout = self.rto(params) as u64 * cpu_clock / 1000;
+        self.rto_slot_and_index = wheel.schedule(&timeout, self.key());
+        self.rto_armed = true;
+    }
+
+    #[inline]
+    pub fn disarm_rto_c(&mut self, wheel: &mut TimerWheel<u32>) {
+        if self.rto_armed {
+            let old = wheel.replace(self.rto_slot_and_index, 0);
+            assert_eq!(old.unwrap(), self.key());
+            self.rto_armed = false;
+        }
+    }
+
+    /// (re-)starts the retransmission timer of a server connection, which is keyed by the socket of the DUT
+    #[inline]
+    pub fn arm_rto_s(&mut self, wheel: &mut TimerWheel<ServerKey>, cpu_clock: u64, params: &RtoParameters) {
+        self.disarm_rto_s(wheel);
+        let timeout = self.rto(params) as u64 * cpu_clock / 1000;
+        self.rto_slot_and_index = wheel.schedule(&timeout, self.server_key());
+        self.rto_armed = true;
+    }
+
+    #[inline]
+    pub fn disarm_rto_s(&mut self, wheel: &mut TimerWheel<ServerKey>) {
+        if self.rto_armed {
+            let old = wheel.replace(self.rto_slot_and_index, (0, 0, 0));
+            assert_eq!(old.unwrap(), self.server_key());
+            self.rto_armed = false;
+        }
+    }
+
     #[inline]
     pub fn set_uid(&mut self, uid: u64) {
         if self.record.is_some() {
@@ -236,124 +1036,412 @@ impl DetailedRecord {
         }
     }
 
-    fn re_new(&mut self, store: Rc<RefCell<TEngineStore>>) {
-        let con_rec = store.borrow_mut().get_next_slot();
-        self.con_rec = Some(con_rec);
-        self.store = Some(store);
+    fn re_new(&mut self, store: Rc<RefCell<TEngineStore>>) {
+        let con_rec = store.borrow_mut().get_next_slot();
+        self.con_rec = Some(con_rec);
+        self.store = Some(store);
+    }
+
+    #[inline]
+    pub fn push_state(&mut self, state: TcpState) {
+        self.store().borrow_mut().get_mut(self.con_rec()).push_state(state);
+    }
+
+    #[inline]
+    fn release(&mut self) {
+        //trace!("releasing con record on port {}", self.port());
+        self.con_rec = None;
+        self.store = None;
+    }
+}
+
+
+impl fmt::Debug for DetailedRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "DetailedRecord= {:?}", self.store().borrow().get(self.con_rec()))
+    }
+}
+
+
+impl ConRecordOperations<TEngineStore> for DetailedRecord {
+    #[inline]
+    fn store(&self) -> &Rc<RefCell<TEngineStore>> {
+        self.store.as_ref().unwrap()
+    }
+
+    #[inline]
+    fn con_rec(&self) -> usize {
+        self.con_rec.expect(ERR_NO_CON_RECORD)
+    }
+
+    #[inline]
+    fn in_use(&self) -> bool {
+        self.store.is_some()
+    }
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connection(sock={:?}, state={:?})", self.sock(), self.state(),)
+    }
+}
+
+/// key of a server connection: the socket of the DUT and our listen port
+pub type ServerKey = (u32, u16, u16);
+
+pub static GLOBAL_MANAGER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// the unused client sockets, given by their keys (see Connection::key), the order of allocation depends on the strategy.
+/// Keys, which were never used, are generated on demand from the ports and the size of the pool, only released keys are
+/// stored.
+struct FreeSocks {
+    /// the ports in the order of their first allocation
+    ports: Vec<u16>,
+    n_ips: usize,
+    /// number of keys generated so far, the next one is ports[fresh / n_ips] with pool index fresh % n_ips
+    fresh: usize,
+    /// released keys
+    keys: VecDeque<u32>,
+    /// strategy Lru only: tsc of the release of each key in keys
+    released: VecDeque<u64>,
+    strategy: PortAllocation,
+    rng: Option<StdRng>,
+    /// in cycles
+    min_reuse_age: u64,
+}
+
+impl FreeSocks {
+    fn new(ports: Vec<u16>, n_ips: usize, config: &PortAllocationConfig, cpu_clock: u64) -> FreeSocks {
+        let strategy = config.strategy();
+        let mut ports = ports;
+        if strategy == PortAllocation::Sequential || strategy == PortAllocation::Lifo {
+            ports.sort_unstable();
+        }
+        let rng = if strategy == PortAllocation::Random {
+            Some(match config.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            })
+        } else {
+            None
+        };
+        FreeSocks {
+            ports,
+            n_ips: cmp::max(n_ips, 1),
+            fresh: 0,
+            keys: VecDeque::new(),
+            released: VecDeque::new(),
+            strategy,
+            rng,
+            min_reuse_age: config.min_reuse_age() * cpu_clock / 1000,
+        }
+    }
+
+    /// number of keys, which were never used
+    #[inline]
+    fn fresh_len(&self) -> usize {
+        self.ports.len() * self.n_ips - self.fresh
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.fresh_len() + self.keys.len()
+    }
+
+    /// removes all keys with this port, must be called before the first allocation
+    fn remove_port(&mut self, port: u16) {
+        self.ports.retain(|p| *p != port);
+        self.keys.retain(|k| *k as u16 != port);
+        self.released.truncate(self.keys.len());
+    }
+
+    /// the next key, which was never used, consecutive keys use the same port with the next source address of the pool
+    #[inline]
+    fn pop_fresh(&mut self) -> Option<u32> {
+        if self.fresh_len() > 0 {
+            let port = self.ports[self.fresh / self.n_ips];
+            let key = ((self.fresh % self.n_ips) as u32) << 16 | port as u32;
+            self.fresh += 1;
+            Some(key)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Option<u32> {
+        match self.strategy {
+            PortAllocation::Sequential | PortAllocation::Shuffled => self.pop_fresh().or_else(|| self.keys.pop_front()),
+            PortAllocation::Lifo => match self.keys.pop_back() {
+                Some(key) => Some(key),
+                None => self.pop_fresh(),
+            },
+            PortAllocation::Random => {
+                // keys, which were never used, are drawn in the shuffled order of the ports
+                let len = self.len();
+                if len == 0 {
+                    None
+                } else {
+                    let i = self.rng.as_mut().unwrap().gen_range(0..len);
+                    if i < self.keys.len() {
+                        self.keys.swap_remove_back(i)
+                    } else {
+                        self.pop_fresh()
+                    }
+                }
+            }
+            PortAllocation::Lru => match self.pop_fresh() {
+                Some(key) => Some(key),
+                None => match self.released.front() {
+                    Some(released) if *released + self.min_reuse_age <= unsafe { _rdtsc() } => {
+                        self.released.pop_front();
+                        self.keys.pop_front()
+                    }
+                    _ => None,
+                },
+            },
+        }
+    }
+
+    #[inline]
+    fn push(&mut self, key: u32) {
+        if self.strategy == PortAllocation::Lru {
+            self.released.push_back(unsafe { _rdtsc() });
+        }
+        self.keys.push_back(key);
+    }
+}
+
+/// double-buffered store of connection records: new records go into the active buffer; a full buffer is retired and
+/// can be shipped, as soon as all its connections are released. The number of buffers is limited, when all are in
+/// use, the overflow policy applies.
+pub struct RecordBuffers {
+    active: Rc<RefCell<RecordStore<ConRecord>>>,
+    retired: VecDeque<Rc<RefCell<RecordStore<ConRecord>>>>,
+    buffer_size: usize,
+    max_buffers: usize,
+    overflow: RecordOverflow,
+    sample_rate: usize,
+    /// connections arriving during an overflow, for sampling
+    overflow_count: usize,
+    /// connections not recorded, respectively refused, due to an overflow
+    dropped: usize,
+    blocked: usize,
+}
+
+impl RecordBuffers {
+    pub fn new(buffer_size: usize, max_buffers: usize, overflow: RecordOverflow, sample_rate: usize) -> RecordBuffers {
+        RecordBuffers {
+            active: Rc::new(RefCell::new(RecordStore::with_capacity(buffer_size))),
+            retired: VecDeque::new(),
+            buffer_size,
+            max_buffers,
+            overflow,
+            sample_rate,
+            overflow_count: 0,
+            dropped: 0,
+            blocked: 0,
+        }
+    }
+
+    /// a single buffer of MAX_RECORDS, which is fetched at the end of the test
+    pub fn unbuffered() -> RecordBuffers {
+        RecordBuffers::new(MAX_RECORDS, 1, RecordOverflow::Drop, 1)
     }
 
     #[inline]
-    pub fn push_state(&mut self, state: TcpState) {
-        self.store().borrow_mut().get_mut(self.con_rec()).push_state(state);
+    fn retire(&mut self) {
+        let new_store = Rc::new(RefCell::new(RecordStore::with_capacity(self.buffer_size)));
+        let store = mem::replace(&mut self.active, new_store);
+        self.retired.push_back(store);
     }
 
     #[inline]
-    fn release(&mut self) {
-        //trace!("releasing con record on port {}", self.port());
-        self.con_rec = None;
-        self.store = None;
+    fn active_full(&self) -> bool {
+        self.active.borrow().len() >= self.buffer_size
     }
-}
 
+    /// true, if all buffers are in use and the active one is full
+    #[inline]
+    fn exhausted(&self) -> bool {
+        self.retired.len() + 1 >= self.max_buffers && self.active_full()
+    }
 
-impl fmt::Debug for DetailedRecord {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "DetailedRecord= {:?}", self.store().borrow().get(self.con_rec()))
+    /// true, if new connections are refused due to the overflow policy Block
+    #[inline]
+    pub fn blocking(&self) -> bool {
+        self.overflow == RecordOverflow::Block && self.exhausted()
     }
-}
 
+    /// to be checked before a new connection is opened, counts the refused connections
+    #[inline]
+    pub fn refuse(&mut self) -> bool {
+        if self.blocking() {
+            self.blocked += 1;
+            true
+        } else {
+            false
+        }
+    }
 
-impl ConRecordOperations<TEngineStore> for DetailedRecord {
+    /// the store for the record of a new connection, None if the connection is not recorded
     #[inline]
-    fn store(&self) -> &Rc<RefCell<TEngineStore>> {
-        self.store.as_ref().unwrap()
+    pub fn next_store(&mut self) -> Option<Rc<RefCell<RecordStore<ConRecord>>>> {
+        let in_use = self.buffers_in_use();
+        if in_use <= self.max_buffers && !self.active_full() {
+            return Some(Rc::clone(&self.active));
+        }
+        if in_use < self.max_buffers {
+            self.retire();
+            return Some(Rc::clone(&self.active));
+        }
+        match self.overflow {
+            RecordOverflow::Sample => {
+                self.overflow_count += 1;
+                // sampled records may exceed the buffer limit, but not twice the limit
+                let room = !self.active_full() || in_use < 2 * self.max_buffers;
+                if self.overflow_count % self.sample_rate == 0 && room {
+                    if self.active_full() {
+                        self.retire();
+                    }
+                    Some(Rc::clone(&self.active))
+                } else {
+                    self.dropped += 1;
+                    None
+                }
+            }
+            // Block: only if the connection was not checked by refuse before
+            RecordOverflow::Drop | RecordOverflow::Block => {
+                self.dropped += 1;
+                None
+            }
+        }
+    }
+
+    /// removes the retired buffers, which are not referenced by connections any more. If flush is true, the active
+    /// buffer is retired before, e.g. when the test ends.
+    pub fn ship(&mut self, flush: bool) -> Vec<RecordStore<ConRecord>> {
+        if flush || (self.active_full() && self.buffers_in_use() < self.max_buffers) {
+            self.retire();
+        }
+        let mut shipped = Vec::new();
+        let mut pending = VecDeque::with_capacity(self.retired.len());
+        while let Some(store) = self.retired.pop_front() {
+            match Rc::try_unwrap(store) {
+                Ok(store) => shipped.push(store.into_inner()),
+                Err(store) => pending.push_back(store),
+            }
+        }
+        self.retired = pending;
+        if self.buffers_in_use() < self.max_buffers {
+            self.overflow_count = 0;
+        }
+        shipped
     }
 
+    /// number of buffers in use, including the active one
     #[inline]
-    fn con_rec(&self) -> usize {
-        self.con_rec.expect(ERR_NO_CON_RECORD)
+    pub fn buffers_in_use(&self) -> usize {
+        self.retired.len() + 1
     }
 
     #[inline]
-    fn in_use(&self) -> bool {
-        self.store.is_some()
+    pub fn dropped(&self) -> usize {
+        self.dropped
     }
-}
 
-impl fmt::Display for Connection {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Connection(sock={:?}, state={:?})", self.sock(), self.state(),)
+    #[inline]
+    pub fn blocked(&self) -> usize {
+        self.blocked
     }
 }
 
-pub static GLOBAL_MANAGER_COUNT: AtomicUsize = AtomicUsize::new(0);
-
 pub struct ConnectionManagerC {
-    c_record_store: Option<Rc<RefCell<RecordStore<ConRecord>>>>,
-    free_ports: VecDeque<u16>,
-    ready: VecDeque<u16>,
-    /// min number of free ports
-    min_free_ports: usize,
-    // ports of connections with data to send and in state Established when enqueued
-    port2con: Vec<Connection>,
+    /// None, if connections are not recorded
+    records: Option<RecordBuffers>,
+    free_socks: FreeSocks,
+    ready: VecDeque<u32>,
+    /// min number of free sockets
+    min_free_socks: usize,
+    // keys of connections with data to send and in state Established when enqueued
+    /// maps the keys of all sockets, which are not free, to their connection
+    key2index: FnvHashMap<u32, u32>,
+    /// a slab of connections, which grows up to max_slots
+    connections: Vec<Connection>,
+    free_slots: VecDeque<u32>,
+    max_slots: usize,
     pci: PortQueue,
     // the PortQueue for which connections are managed
     pipeline_id: PipelineId,
     tcp_port_base: u16,
-    available_ports_count: usize,
+    available_socks_count: usize,
     // e.g. used as a listen port, not assigned by create
     listen_port: u16,
-    ip: u32,
-    // ip address to use for connections of this manager
-    /// with or without recording of connections
-    detailed_records: bool,
+    // ip addresses to use for connections of this manager, the first one is used if no pool is configured
+    ips: Vec<u32>,
 }
 
 const MAX_CONNECTIONS: usize = 0xFFFF as usize;
 const MAX_RECORDS: usize = 0x3FFFF as usize;
+/// the pool index is encoded into the upper 16 bits of the key of a client connection
+pub const MAX_IP_POOL_SIZE: usize = 0x10000;
 
 impl ConnectionManagerC {
-    pub fn new(pipeline_id: PipelineId, pci: PortQueue, l4flow: &L4Flow, detailed_records: bool) -> ConnectionManagerC {
+    /// ip_pool are the source addresses of the client connections, if it is empty, the ip of the l4flow is used. The
+    /// connection table starts with one connection per port and grows on demand.
+    pub fn new(
+        pipeline_id: PipelineId,
+        pci: PortQueue,
+        l4flow: &L4Flow,
+        ip_pool: Vec<u32>,
+        port_allocation: &PortAllocationConfig,
+        cpu_clock: u64,
+        records: Option<RecordBuffers>,
+    ) -> ConnectionManagerC {
         let old_manager_count: u16 = GLOBAL_MANAGER_COUNT.fetch_add(1, Ordering::SeqCst) as u16;
-        let (ip, tcp_port_base) = (l4flow.ip, l4flow.port);
+        let tcp_port_base = l4flow.port;
+        let ips = if ip_pool.is_empty() { vec![l4flow.ip] } else { ip_pool };
+        assert!(ips.len() <= MAX_IP_POOL_SIZE, "ip pool exceeds {} addresses", MAX_IP_POOL_SIZE);
         let port_mask = pci.port.get_tcp_dst_port_mask();
         let max_tcp_port: u16 = tcp_port_base + !port_mask;
-        let store = if detailed_records {
-            Some(Rc::new(RefCell::new(RecordStore::with_capacity(MAX_RECORDS))))
-        } else {
-            None
-        };
-        let avail_ports;
+        let ports_per_ip = !port_mask as usize + 1;
+        // port 0 is reserved and not usable for us, by default ports are shuffled for better load sharing in DUTs
+        // max_tcp_port itself is reserved for the server side for listening
+        let ports = shuffle_ports(if tcp_port_base == 0 { 1 } else { tcp_port_base }, max_tcp_port - 1);
+        let free_socks = FreeSocks::new(ports, ips.len(), port_allocation, cpu_clock);
+        let avail_socks = free_socks.len();
+        let capacity = cmp::min(ports_per_ip, avail_socks);
         let cm = ConnectionManagerC {
-            c_record_store: store,
-            port2con: vec![Connection::new(); (!port_mask + 1) as usize],
-            // port 0 is reserved and not usable for us, ports are shuffled for better load sharing in DUTs
-            // max_tcp_port itself is reserved for the server side for listening
-            free_ports: {
-                let vec = shuffle_ports(if tcp_port_base == 0 { 1 } else { tcp_port_base }, max_tcp_port - 1);
-                //let vec = (if tcp_port_base == 0 { 1 } else { tcp_port_base }.. max_tcp_port - 1).collect();
-                avail_ports = vec.len();
-                VecDeque::<u16>::from(vec)
-            },
-            available_ports_count: avail_ports,
+            records,
+            free_socks,
+            available_socks_count: avail_socks,
             ready: VecDeque::with_capacity(MAX_CONNECTIONS), // connections which became Established (but may not longer be)
-            min_free_ports: !port_mask as usize + 1,
+            min_free_socks: avail_socks,
+            key2index: FnvHashMap::with_capacity_and_hasher(capacity, Default::default()),
+            connections: vec![Connection::new(); capacity],
+            free_slots: (0..capacity as u32).collect(),
+            max_slots: cmp::max(avail_socks, 1),
             pci,
             pipeline_id,
             tcp_port_base,
             listen_port: max_tcp_port,
-            ip,
-            detailed_records,
+            ips,
         };
-        // we use the port max_tcp_port for returning traffic to us, do not add it to free_ports
+        // we use the port max_tcp_port for returning traffic to us, do not add it to free_socks
         info!(
-            "created ConnectionManager {} for port {}, rxq {}, ip= {}, tcp ports {} - {}",
+            "created ConnectionManager {} for port {}, rxq {}, ip= {}{}, tcp ports {} - {}, allocation {:?}",
             old_manager_count,
             cm.pci.port_id(),
             cm.pci.rxq(),
-            Ipv4Addr::from(ip),
+            Ipv4Addr::from(cm.ips[0]),
+            if cm.ips.len() > 1 {
+                format!(" - {}", Ipv4Addr::from(cm.ips[cm.ips.len() - 1]))
+            } else {
+                String::new()
+            },
             if tcp_port_base == 0 { 1 } else { tcp_port_base },
             max_tcp_port - 1,
+            port_allocation.strategy(),
         );
         cm
     }
@@ -361,44 +1449,89 @@ impl ConnectionManagerC {
     #[inline]
     pub fn max_concurrent_connections(&self) -> usize {
         //        (!self.pci.port.get_tcp_dst_port_mask() - (if self.tcp_port_base == 0 { 1 } else { 0 })) as usize
-        self.available_ports_count - self.min_free_ports
+        self.available_socks_count - self.min_free_socks
     }
 
     #[inline]
     pub fn concurrent_connections(&self) -> usize {
-        self.available_ports_count - self.free_ports.len()
+        self.available_socks_count - self.free_socks.len()
     }
 
+    /// number of (ip, port) pairs available for client connections
     #[inline]
-    pub fn available_ports_count(&self) -> usize {
-        self.available_ports_count
+    pub fn available_socks_count(&self) -> usize {
+        self.available_socks_count
+    }
+
+    /// doubles the number of slots, returns false if the table reached its maximum capacity
+    fn grow(&mut self) -> bool {
+        let len = self.connections.len();
+        let new_len = cmp::min(cmp::max(len * 2, 1), self.max_slots);
+        if new_len > len {
+            self.connections.resize_with(new_len, Connection::new);
+            self.free_slots.extend(len as u32..new_len as u32);
+            info!("{}: client connection table grown to {} connections", self.pipeline_id, new_len);
+            true
+        } else {
+            false
+        }
     }
 
+    /// the connection of a socket, which is not free
+    #[inline]
+    fn get_mut_con(&mut self, key: &u32) -> Option<&mut Connection> {
+        match self.key2index.get(key) {
+            Some(index) => Some(&mut self.connections[*index as usize]),
+            None => None,
+        }
+    }
 
+    /// returns the socket and the slot of a released connection to the free sockets and the free slots
     #[inline]
-    fn get_mut_con(&mut self, p: &u16) -> &mut Connection {
-        &mut self.port2con[(p - self.tcp_port_base) as usize]
+    fn free_key(&mut self, key: u32) {
+        if let Some(index) = self.key2index.remove(&key) {
+            self.free_slots.push_back(index);
+        }
+        self.free_socks.push(key);
     }
 
     // create a new connection, if out of resources return None
     #[inline]
     pub fn create(&mut self, role: TcpRole) -> Option<&mut Connection> {
-        let opt_port = self.free_ports.pop_front();
-        if opt_port.is_some() {
-            let port = opt_port.unwrap();
-            {
-                let sock = (self.ip, port);
-                if self.detailed_records {
-                    let store = Rc::clone(self.c_record_store.as_ref().unwrap());
-                    self.get_mut_con(&port).initialize_with_details(Some(sock), role, store);
-                } else {
-                    self.get_mut_con(&port).initialize(Some(sock), role);
+        if self.records.as_mut().map_or(false, |r| r.refuse()) {
+            return None;
+        }
+        let opt_key = self.free_socks.pop();
+        if opt_key.is_some() {
+            let key = opt_key.unwrap();
+            if self.free_slots.is_empty() {
+                self.grow();
+            }
+            let index = match self.free_slots.pop_front() {
+                Some(index) => index,
+                None => {
+                    // cannot happen, the table holds a connection for each socket
+                    self.free_socks.push(key);
+                    return None;
                 }
+            };
+            self.key2index.insert(key, index);
+            self.min_free_socks = cmp::min(self.min_free_socks, self.free_socks.len());
+            let sock = (self.ips[(key >> 16) as usize], key as u16);
+            let store = self.records.as_mut().map(|r| r.next_store());
+            let c = &mut self.connections[index as usize];
+            match store {
+                Some(store) => c.initialize_with_details(Some(sock), role, store),
+                None => c.initialize(Some(sock), role),
             }
-            self.min_free_ports = cmp::min(self.min_free_ports, self.free_ports.len());
-            Some(self.get_mut_con(&port))
+            c.pool_index = (key >> 16) as u16;
+            Some(c)
         } else {
-            warn!("out of ports");
+            if self.free_socks.len() > 0 {
+                debug!("no free port has reached the minimum reuse age");
+            } else {
+                warn!("out of ports");
+            }
             None
         }
     }
@@ -408,14 +1541,50 @@ impl ConnectionManagerC {
         tcp_port & self.pci.port.get_tcp_dst_port_mask() == self.tcp_port_base
     }
 
+    /// the index of ip in the address pool, the pool is a contiguous address range
+    #[inline]
+    fn pool_index(&self, ip: u32) -> Option<usize> {
+        let i = ip.wrapping_sub(self.ips[0]) as usize;
+        if i < self.ips.len() && self.ips[i] == ip {
+            Some(i)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn owns_ip(&self, ip: u32) -> bool {
+        self.pool_index(ip).is_some()
+    }
+
+    /// the key of the connection for our socket sock, see Connection::key
+    #[inline]
+    pub fn key(&self, sock: &(u32, u16)) -> Option<u32> {
+        if self.owns_tcp_port(sock.1) {
+            self.pool_index(sock.0).map(|i| (i as u32) << 16 | sock.1 as u32)
+        } else {
+            None
+        }
+    }
+
+    /// excludes a port of our range from client connections, e.g. because it is used as a listen port
+    pub fn reserve_port(&mut self, port: u16) {
+        if self.owns_tcp_port(port) {
+            self.free_socks.remove_port(port);
+            self.min_free_socks = cmp::min(self.min_free_socks, self.free_socks.len());
+            self.available_socks_count = self.free_socks.len();
+        }
+    }
+
     #[inline]
     pub fn tcp_port_base(&self) -> u16 {
         self.tcp_port_base
     }
 
+    /// the first address of the pool
     #[inline]
     pub fn ip(&self) -> u32 {
-        self.ip
+        self.ips[0]
     }
 
     #[inline]
@@ -423,33 +1592,48 @@ impl ConnectionManagerC {
         self.listen_port
     }
 
+    /// the connection for our socket sock
     #[inline]
-    pub fn get_mut_by_port(&mut self, port: u16) -> Option<&mut Connection> {
-        if self.owns_tcp_port(port) {
-            let c = self.get_mut_con(&port);
-            // check if c is in use
-            if c.in_use() {
-                Some(c)
-            } else {
-                None
-            }
-        } else {
-            None
+    pub fn get_mut_by_sock(&mut self, sock: &(u32, u16)) -> Option<&mut Connection> {
+        match self.key(sock) {
+            Some(key) => self.get_mut_by_key(key),
+            None => None,
+        }
+    }
+
+    #[inline]
+    pub fn get_mut_by_key(&mut self, key: u32) -> Option<&mut Connection> {
+        // check if c is in use, a reset connection keeps its socket until the RST is sent
+        match self.get_mut_con(&key) {
+            Some(c) if c.in_use() => Some(c),
+            _ => None,
         }
     }
 
-    //TODO allow for more precise time out conditions, currently whole TCP connections are timed out, also we should send a RST
-    pub fn release_timeouts(&mut self, now: &u64, wheel: &mut TimerWheel<u16>) {
+    /// releases connections with expired state timeout, if resets is given, a RST is noted down for each connection which
+    /// is not yet closed. The idle timer of connections, which were active meanwhile, is restarted for the remaining time.
+    pub fn release_timeouts(
+        &mut self,
+        now: &u64,
+        wheel: &mut TimerWheel<u32>,
+        wheel_rto: &mut TimerWheel<u32>,
+        timeouts: &StateTimeouts,
+        mut resets: Option<&mut VecDeque<PendingRst>>,
+    ) {
+        let mut refreshed = Vec::new();
         loop {
             match wheel.tick(now) {
                 (Some(mut drain), more) => {
-                    let mut port = drain.next();
-                    while port.is_some() {
-                        let p = port.unwrap();
-                        if p != 0 {
-                            self.timeout(p);
+                    let mut key = drain.next();
+                    while key.is_some() {
+                        let k = key.unwrap();
+                        if k != 0 {
+                            match self.get_mut_con(&k).and_then(|c| c.idle_remaining(*now, timeouts)) {
+                                Some(remaining) => refreshed.push((k, remaining)),
+                                None => self.timeout(k, wheel_rto, resets.as_mut().map(|r| &mut **r)),
+                            }
                         }
-                        port = drain.next();
+                        key = drain.next();
                     }
                     if !more {
                         break;
@@ -462,65 +1646,140 @@ impl ConnectionManagerC {
                 }
             }
         }
+        for (key, remaining) in refreshed {
+            if let Some(c) = self.get_mut_con(&key) {
+                c.wheel_slot_and_index = wheel.schedule(&remaining, key);
+            }
+        }
     }
 
+    /// a socket, whose connection is reset, returns to the free sockets only with free_sock, after the RST was sent.
+    /// Otherwise the RST could hit a new connection on the same socket.
     #[inline]
-    fn timeout(&mut self, port: u16) {
+    fn timeout(&mut self, key: u32, wheel_rto: &mut TimerWheel<u32>, resets: Option<&mut VecDeque<PendingRst>>) {
         // the borrow checker makes things a little bit cumbersome:
         let mut in_use = false;
-        {
-            let c = self.get_mut_con(&port);
-            if c.in_use() {
+        if let Some(c) = self.get_mut_con(&key) {
+            if c.in_use() && c.state() == TcpState::TimeWait {
+                // 2MSL expired, the connection itself was closed regularly
                 in_use = true;
-                c.set_release_cause(ReleaseCause::Timeout);
+                c.release();
+            } else if c.in_use() {
+                in_use = true;
+                match (resets, c.pending_rst()) {
+                    (Some(resets), Some(rst)) => {
+                        resets.push_back(rst);
+                        c.set_release_cause(ReleaseCause::ActiveRst);
+                        // see free_sock
+                        in_use = false;
+                    }
+                    _ => c.set_release_cause(ReleaseCause::Timeout),
+                }
                 c.push_state(TcpState::Closed);
-                debug!("timing out port {} at {:?}", port, c.wheel_slot_and_index);
+                debug!("timing out {:?} at {:?}", c.sock(), c.wheel_slot_and_index);
+                c.disarm_rto_c(wheel_rto);
                 // now we release the connection inline (cannot call self.release)
                 c.release();
             }
         }
         if in_use {
-            self.free_ports.push_back(port);
+            self.free_key(key);
         }
     }
 
+    /// returns the socket of a timed out connection to the free sockets, after its RST was sent
     #[inline]
-    pub fn release(&mut self, port: u16, wheel: &mut TimerWheel<u16>) {
-        let c = &mut self.port2con[(port - self.tcp_port_base) as usize];
-        // only if it is in use, i.e. it has been not released already
-        if c.in_use() {
-            self.free_ports.push_back(port);
-            c.release();
-            //remove port from timer wheel by overwriting it
-            let old = wheel.replace(c.wheel_slot_and_index, 0);
-            assert_eq!(old.unwrap(), port);
-            // we keep unused connection in port2con table
+    pub fn free_sock(&mut self, sock: &(u32, u16)) {
+        if let Some(key) = self.key(sock) {
+            self.free_key(key);
+        }
+    }
+
+    /// collects the keys of connections with an expired retransmission timer
+    pub fn rto_timeouts(&mut self, now: &u64, wheel_rto: &mut TimerWheel<u32>, expired: &mut VecDeque<u32>) {
+        loop {
+            match wheel_rto.tick(now) {
+                (Some(mut drain), more) => {
+                    let mut key = drain.next();
+                    while key.is_some() {
+                        let k = key.unwrap();
+                        if k != 0 {
+                            if let Some(c) = self.get_mut_con(&k) {
+                                if c.in_use() && c.rto_armed {
+                                    c.rto_armed = false;
+                                    expired.push_back(k);
+                                }
+                            }
+                        }
+                        key = drain.next();
+                    }
+                    if !more {
+                        break;
+                    }
+                }
+                (None, more) => {
+                    if !more {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    #[inline]
+    pub fn release(&mut self, key: u32, wheel: &mut TimerWheel<u32>, wheel_rto: &mut TimerWheel<u32>) {
+        let mut in_use = false;
+        if let Some(c) = self.get_mut_con(&key) {
+            // only if it is in use, i.e. it has been not released already
+            if c.in_use() {
+                in_use = true;
+                c.disarm_rto_c(wheel_rto);
+                c.release();
+                //remove key from timer wheel by overwriting it
+                let old = wheel.replace(c.wheel_slot_and_index, 0);
+                assert_eq!(old.unwrap(), key);
+            }
+        }
+        if in_use {
+            self.free_key(key);
+        }
+    }
+
+    /// replaces the timeout of a connection, e.g. after a state transition
+    #[inline]
+    pub fn reschedule(&mut self, key: u32, wheel: &mut TimerWheel<u32>, timeout: u64) {
+        if let Some(c) = self.get_mut_con(&key) {
+            if c.in_use() {
+                let old = wheel.replace(c.wheel_slot_and_index, 0);
+                assert_eq!(old.unwrap(), key);
+                c.wheel_slot_and_index = wheel.schedule(&timeout, key);
+            }
+        }
+    }
+
+    /// an actively closed connection stays in TIME_WAIT, its socket returns to the free sockets when the 2MSL timer
+    /// expires
+    pub fn time_wait(&mut self, key: u32, wheel: &mut TimerWheel<u32>, wheel_rto: &mut TimerWheel<u32>, timeout: u64) {
+        if let Some(c) = self.get_mut_con(&key) {
+            c.disarm_rto_c(wheel_rto);
         }
+        self.reschedule(key, wheel, timeout);
     }
 
     #[allow(dead_code)]
     pub fn dump_records(&mut self) {
-        if self.c_record_store.is_some() {
-            info!(
-                "{}: {:6} closed connections",
-                self.pipeline_id,
-                self.c_record_store.as_ref().unwrap().borrow().len()
-            );
-            self.c_record_store
-                .as_ref()
-                .unwrap()
-                .borrow()
-                .iter()
-                .enumerate()
-                .for_each(|(i, c)| debug!("{:6}: {}", i, c));
+        if self.records.is_some() {
+            let active = Rc::clone(&self.records.as_ref().unwrap().active);
+            info!("{}: {:6} records in the active buffer", self.pipeline_id, active.borrow().len());
+            active.borrow().iter().enumerate().for_each(|(i, c)| debug!("{:6}: {}", i, c));
             info!(
                 "{}: {:6} open connections",
                 self.pipeline_id,
-                self.port2con.iter().filter(|c| c.port() != 0).collect::<Vec<_>>().len()
+                self.connections.iter().filter(|c| c.port() != 0).collect::<Vec<_>>().len()
             );
         }
         /*
-        self.port2con.iter().enumerate().for_each(|(i, c)| {
+        self.connections.iter().enumerate().for_each(|(i, c)| {
             if c.port() != 0 {
                 // info!("{:6}: {}", i, c.con_rec())
             }
@@ -528,32 +1787,36 @@ impl ConnectionManagerC {
         */
     }
 
-    pub fn fetch_c_records(&mut self) -> Option<RecordStore<ConRecord>> {
-        // we are "moving" the con_records out, and replace it with a new one
-        if self.c_record_store.is_some() {
-            let new_store = Rc::new(RefCell::new(RecordStore::with_capacity(MAX_RECORDS)));
-            let store = mem::replace(self.c_record_store.as_mut().unwrap(), new_store);
-            let strong_count = Rc::strong_count(&store);
-            debug!("cm_s.fetch_c_records: strong_count= { }", strong_count);
-            if strong_count > 1 {
-                for c in &mut self.port2con {
-                    c.release();
-                }
-            }
-            let unwrapped = Rc::try_unwrap(store);
-            if unwrapped.is_ok() {
-                Some(unwrapped.unwrap().into_inner())
-            } else {
-                None
+    /// the record buffers, which are ready for shipping
+    pub fn ship_c_records(&mut self) -> Vec<RecordStore<ConRecord>> {
+        match self.records.as_mut() {
+            Some(records) => records.ship(false),
+            None => Vec::new(),
+        }
+    }
+
+    /// all record buffers, open connections are released
+    pub fn fetch_c_records(&mut self) -> Vec<RecordStore<ConRecord>> {
+        if self.records.is_some() {
+            let in_use = self.records.as_ref().unwrap().buffers_in_use();
+            debug!("cm_c.fetch_c_records: buffers in use= {}", in_use);
+            for c in &mut self.connections {
+                c.release();
             }
+            self.records.as_mut().unwrap().ship(true)
         } else {
-            None
+            Vec::new()
         }
     }
 
     #[inline]
-    pub fn set_ready_connection(&mut self, port: u16, ready_flag: &Arc<AtomicBool>) {
-        self.ready.push_back(port);
+    pub fn records(&self) -> Option<&RecordBuffers> {
+        self.records.as_ref()
+    }
+
+    #[inline]
+    pub fn set_ready_connection(&mut self, key: u32, ready_flag: &Arc<AtomicBool>) {
+        self.ready.push_back(key);
         // if this is the first ready connection, we restart the injector, avoid accessing Atomic unnecessarily
         if self.ready_connections() == 1 {
             ready_flag.store(true, Ordering::SeqCst);
@@ -567,60 +1830,92 @@ impl ConnectionManagerC {
 
     #[inline]
     pub fn get_ready_connection(&mut self) -> Option<&mut Connection> {
-        let mut port_result = None;
-        while port_result.is_none() {
+        let mut key_result = None;
+        while key_result.is_none() {
             match self.ready.pop_front() {
-                Some(port) => {
-                    let c = &self.port2con[(port - self.tcp_port_base) as usize];
-                    //trace!("found ready connection {}", if c.in_use() { c.port() } else { 0 });
-                    if c.in_use() && c.state() == TcpState::Established {
-                        port_result = Some(port)
+                Some(key) => {
+                    if let Some(index) = self.key2index.get(&key) {
+                        let c = &self.connections[*index as usize];
+                        //trace!("found ready connection {}", if c.in_use() { c.port() } else { 0 });
+                        if c.in_use() && c.state() == TcpState::Established {
+                            key_result = Some(key)
+                        }
                     }
                 }
                 None => break, // ready queue is empty
             };
         }
         // borrow checker forces us this two-step way, cannot mutably borrow connection directly
-        if let Some(port) = port_result {
-            Some(&mut self.port2con[(port - self.tcp_port_base) as usize])
+        if let Some(key) = key_result {
+            self.get_mut_con(&key)
         } else {
             None
         }
     }
 }
 
-use netfcts::utils::Sock2Index as Sock2Index;
+use fnv::FnvHashMap;
 use std::cmp;
 
 pub struct ConnectionManagerS {
-    c_record_store: Option<Rc<RefCell<RecordStore<ConRecord>>>>,
-    sock2index: Sock2Index,
-    //sock2index: HashMap<(u32,u16), u16>,
-    //sock2index: BTreeMap<(u32,u16), u16>,
+    /// None, if connections are not recorded
+    records: Option<RecordBuffers>,
+    key2index: FnvHashMap<ServerKey, u32>,
+    /// a slab of connections, which grows up to max_slots
     connections: Vec<Connection>,
-    free_slots: VecDeque<usize>,
+    free_slots: VecDeque<u32>,
+    max_slots: usize,
+    /// high-water mark of concurrent connections
+    max_concurrent: usize,
 }
 
 impl ConnectionManagerS {
-    pub fn new(detailed_records: bool) -> ConnectionManagerS {
-        let store = if detailed_records {
-            Some(Rc::new(RefCell::new(RecordStore::with_capacity(MAX_RECORDS))))
-        } else {
-            None
-        };
+    /// capacity is the number of connections allocated at start, the table grows on demand up to max_capacity
+    pub fn new(records: Option<RecordBuffers>, capacity: usize, max_capacity: usize) -> ConnectionManagerS {
+        // we use index 0 to indicate unused slots
+        let slots = cmp::max(capacity, 1) + 1;
         ConnectionManagerS {
-            c_record_store: store,
-            sock2index: Sock2Index::new(),
-            //sock2index: HashMap::with_capacity(MAX_CONNECTIONS),
-            //sock2index: BTreeMap::new(),
-            connections: vec![Connection::new(); MAX_CONNECTIONS],
-            free_slots: (1..MAX_CONNECTIONS).collect(), // we use index 0 to indicate unused slots
+            records,
+            key2index: FnvHashMap::with_capacity_and_hasher(capacity, Default::default()),
+            connections: vec![Connection::new(); slots],
+            free_slots: (1..slots as u32).collect(),
+            max_slots: cmp::max(slots, cmp::min(max_capacity.saturating_add(1), u32::max_value() as usize)),
+            max_concurrent: 0,
+        }
+    }
+
+    /// doubles the number of slots, returns false if the table reached its maximum capacity
+    fn grow(&mut self) -> bool {
+        let len = self.connections.len();
+        let new_len = cmp::min(len * 2, self.max_slots);
+        if new_len > len {
+            self.connections.resize_with(new_len, Connection::new);
+            self.free_slots.extend(len as u32..new_len as u32);
+            info!("server connection table grown to {} connections", new_len - 1);
+            true
+        } else {
+            false
         }
     }
 
     #[inline]
-    pub fn get_mut(&mut self, sock: &(u32, u16)) -> Option<&mut Connection> {
-        let index = self.sock2index.get(sock);
+    pub fn concurrent_connections(&self) -> usize {
+        self.key2index.len()
+    }
+
+    #[inline]
+    pub fn max_concurrent_connections(&self) -> usize {
+        self.max_concurrent
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.connections.len() - 1
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, key: &ServerKey) -> Option<&mut Connection> {
+        let index = self.key2index.get(key);
         if index.is_some() {
             Some(&mut self.connections[*index.unwrap() as usize])
         } else {
@@ -629,32 +1924,43 @@ impl ConnectionManagerS {
     }
 
     #[inline]
-    pub fn get_mut_or_insert(&mut self, sock: &(u32, u16)) -> Option<&mut Connection> {
+    pub fn contains(&self, key: &ServerKey) -> bool {
+        self.key2index.get(key).is_some()
+    }
+
+    #[inline]
+    pub fn get_mut_or_insert(&mut self, key: &ServerKey) -> Option<&mut Connection> {
         {
-            let index = self.sock2index.get(sock);
+            let index = self.key2index.get(key);
             if index.is_some() {
                 return Some(&mut self.connections[*index.unwrap() as usize]);
             }
         }
         // create
-        self.insert(sock)
+        self.insert(key)
     }
 
+    /// returns None, if the table is full and cannot grow any more, or if the record buffers block new connections
     #[inline]
-    pub fn insert(&mut self, sock: &(u32, u16)) -> Option<&mut Connection> {
+    pub fn insert(&mut self, key: &ServerKey) -> Option<&mut Connection> {
+        if self.records.as_mut().map_or(false, |r| r.refuse()) {
+            return None;
+        }
+        if self.free_slots.is_empty() {
+            self.grow();
+        }
         let index = self.free_slots.pop_front();
         if index.is_some() {
-            self.sock2index.insert(*sock, index.unwrap() as u16);
-            let c = &mut self.connections[index.unwrap()];
-            if self.c_record_store.is_some() {
-                c.initialize_with_details(
-                    Some(*sock),
-                    TcpRole::Server,
-                    Rc::clone(&self.c_record_store.as_ref().unwrap()),
-                );
+            self.key2index.insert(*key, index.unwrap());
+            self.max_concurrent = cmp::max(self.max_concurrent, self.key2index.len());
+            let c = &mut self.connections[index.unwrap() as usize];
+            let sock = (key.0, key.1);
+            if self.records.is_some() {
+                c.initialize_with_details(Some(sock), TcpRole::Server, self.records.as_mut().unwrap().next_store());
             } else {
-                c.initialize(Some(*sock), TcpRole::Server)
+                c.initialize(Some(sock), TcpRole::Server)
             }
+            c.local_port = key.2;
             Some(c)
         } else {
             None // out of resources
@@ -662,34 +1968,50 @@ impl ConnectionManagerS {
     }
 
     #[inline]
-    pub fn release(&mut self, sock: &(u32, u16), wheel: &mut TimerWheel<(u32, u16)>) {
-        let index = self.sock2index.remove(sock);
+    pub fn release(
+        &mut self,
+        key: &ServerKey,
+        wheel: &mut TimerWheel<ServerKey>,
+        wheel_rto: &mut TimerWheel<ServerKey>,
+    ) {
+        let index = self.key2index.remove(key);
         if index.is_some() {
             let c = &mut self.connections[index.unwrap() as usize];
             if c.in_use() {
-                self.free_slots.push_back(index.unwrap() as usize);
+                self.free_slots.push_back(index.unwrap());
                 //remove port from timer wheel by overwriting it
-                let old = wheel.replace(c.wheel_slot_and_index, (0, 0));
-                assert_eq!(old.unwrap(), *sock);
+                let old = wheel.replace(c.wheel_slot_and_index, (0, 0, 0));
+                assert_eq!(old.unwrap(), *key);
+                c.disarm_rto_s(wheel_rto);
             }
             c.release();
-            // we keep unused connection in port2con table
         }
     }
 
-    //TODO allow for more precise time out conditions, currently whole TCP connections are timed out, also we should send a RST
-    pub fn release_timeouts(&mut self, now: &u64, wheel: &mut TimerWheel<(u32, u16)>) {
+    /// same as ConnectionManagerC::release_timeouts
+    pub fn release_timeouts(
+        &mut self,
+        now: &u64,
+        wheel: &mut TimerWheel<ServerKey>,
+        wheel_rto: &mut TimerWheel<ServerKey>,
+        timeouts: &StateTimeouts,
+        mut resets: Option<&mut VecDeque<PendingRst>>,
+    ) {
         //trace!("cm server side: release_timeouts");
+        let mut refreshed = Vec::new();
         loop {
             match wheel.tick(now) {
                 (Some(mut drain), more) => {
-                    let mut sock = drain.next();
-                    while sock.is_some() {
-                        let s = sock.unwrap();
-                        if s.1 != 0 {
-                            self.timeout(&s);
+                    let mut key = drain.next();
+                    while key.is_some() {
+                        let k = key.unwrap();
+                        if k.1 != 0 {
+                            match self.get_mut(&k).and_then(|c| c.idle_remaining(*now, timeouts)) {
+                                Some(remaining) => refreshed.push((k, remaining)),
+                                None => self.timeout(&k, wheel_rto, resets.as_mut().map(|r| &mut **r)),
+                            }
                         }
-                        sock = drain.next();
+                        key = drain.next();
                     }
                     if !more {
                         break;
@@ -702,49 +2024,277 @@ impl ConnectionManagerS {
                 }
             }
         }
+        for (key, remaining) in refreshed {
+            if let Some(c) = self.get_mut(&key) {
+                c.wheel_slot_and_index = wheel.schedule(&remaining, key);
+            }
+        }
+    }
+
+    /// same as ConnectionManagerC::reschedule
+    #[inline]
+    pub fn reschedule(&mut self, key: &ServerKey, wheel: &mut TimerWheel<ServerKey>, timeout: u64) {
+        if let Some(c) = self.get_mut(key) {
+            if c.in_use() {
+                let old = wheel.replace(c.wheel_slot_and_index, (0, 0, 0));
+                assert_eq!(old.unwrap(), *key);
+                c.wheel_slot_and_index = wheel.schedule(&timeout, *key);
+            }
+        }
     }
 
     #[inline]
-    fn timeout(&mut self, sock: &(u32, u16)) {
+    fn timeout(
+        &mut self,
+        key: &ServerKey,
+        wheel_rto: &mut TimerWheel<ServerKey>,
+        resets: Option<&mut VecDeque<PendingRst>>,
+    ) {
         // the borrow checker makes things a little bit cumbersome:
         let mut in_use = false;
         {
-            let opt_c = self.get_mut(sock);
+            let opt_c = self.get_mut(key);
             if let Some(c) = opt_c {
                 in_use = c.in_use();
                 if in_use {
-                    c.set_release_cause(ReleaseCause::Timeout);
+                    match (resets, c.pending_rst()) {
+                        (Some(resets), Some(rst)) => {
+                            resets.push_back(rst);
+                            c.set_release_cause(ReleaseCause::ActiveRst);
+                        }
+                        _ => c.set_release_cause(ReleaseCause::Timeout),
+                    }
                     c.push_state(TcpState::Closed);
+                    c.disarm_rto_s(wheel_rto);
                     c.release();
                 }
             }
         }
         if in_use {
-            let index = self.sock2index.remove(sock);
-            self.free_slots.push_back(index.unwrap() as usize);
+            let index = self.key2index.remove(key);
+            self.free_slots.push_back(index.unwrap());
         }
     }
 
-    pub fn fetch_c_records(&mut self) -> Option<RecordStore<ConRecord>> {
-        if self.c_record_store.is_some() {
-            // we are "moving" the con_records out, and replace it with a new one
-            let new_store = Rc::new(RefCell::new(RecordStore::with_capacity(MAX_RECORDS)));
-            let store = mem::replace(self.c_record_store.as_mut().unwrap(), new_store);
-            let strong_count = Rc::strong_count(&store);
-            debug!("cm_s.fetch_c_records: strong_count= { }", strong_count);
-            if strong_count > 1 {
-                for c in &mut self.connections {
-                    c.release();
+    /// collects the keys of connections with an expired retransmission timer
+    pub fn rto_timeouts(
+        &mut self,
+        now: &u64,
+        wheel_rto: &mut TimerWheel<ServerKey>,
+        expired: &mut VecDeque<ServerKey>,
+    ) {
+        loop {
+            match wheel_rto.tick(now) {
+                (Some(mut drain), more) => {
+                    let mut key = drain.next();
+                    while key.is_some() {
+                        let k = key.unwrap();
+                        if k.1 != 0 {
+                            if let Some(c) = self.get_mut(&k) {
+                                if c.in_use() && c.rto_armed {
+                                    c.rto_armed = false;
+                                    expired.push_back(k);
+                                }
+                            }
+                        }
+                        key = drain.next();
+                    }
+                    if !more {
+                        break;
+                    }
+                }
+                (None, more) => {
+                    if !more {
+                        break;
+                    }
                 }
             }
-            let unwrapped = Rc::try_unwrap(store);
-            if unwrapped.is_ok() {
-                Some(unwrapped.unwrap().into_inner())
-            } else {
-                None
+        }
+    }
+
+    /// same as ConnectionManagerC::ship_c_records
+    pub fn ship_c_records(&mut self) -> Vec<RecordStore<ConRecord>> {
+        match self.records.as_mut() {
+            Some(records) => records.ship(false),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn fetch_c_records(&mut self) -> Vec<RecordStore<ConRecord>> {
+        if self.records.is_some() {
+            let in_use = self.records.as_ref().unwrap().buffers_in_use();
+            debug!("cm_s.fetch_c_records: buffers in use= {}", in_use);
+            for c in &mut self.connections {
+                c.release();
             }
+            self.records.as_mut().unwrap().ship(true)
         } else {
-            None
+            Vec::new()
+        }
+    }
+
+    #[inline]
+    pub fn records(&self) -> Option<&RecordBuffers> {
+        self.records.as_ref()
+    }
+
+    /// true, if SYNs are dropped due to the overflow policy Block of the record buffers
+    #[inline]
+    pub fn records_blocking(&self) -> bool {
+        self.records.as_ref().map_or(false, |r| r.blocking())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        assert!(seq_lt(1, 2));
+        assert!(!seq_lt(2, 2));
+        assert!(seq_le(2, 2));
+        assert!(seq_lt(0xffff_fff0, 0x10));
+        assert!(!seq_lt(0x10, 0xffff_fff0));
+        assert!(seq_le(0xffff_ffff, 0));
+    }
+
+    #[test]
+    fn sendable_avoids_silly_windows() {
+        let mut c = Connection::new();
+        c.init_send_window(1000, 1, 4000);
+        c.seqn_una = 1;
+        c.seqn_nxt = 1;
+        assert_eq!(c.sendable(1460), 1460);
+        // 1000 bytes usable, less than half of the largest window
+        c.seqn_nxt = 3001;
+        assert_eq!(c.sendable(1460), 0);
+        assert_eq!(c.sendable(500), 500);
+        // all acknowledged, but the window shrank
+        c.update_send_window(1001, 3001, 600);
+        assert_eq!(c.sendable(1460), 600);
+        // at least half of the largest window is usable
+        c.seqn_nxt = 4001;
+        c.update_send_window(1002, 3001, 3000);
+        assert_eq!(c.sendable(2500), 2000);
+    }
+
+    fn free_socks(strategy: PortAllocation) -> FreeSocks {
+        let config = PortAllocationConfig {
+            strategy: Some(strategy),
+            seed: Some(1),
+            min_reuse_age: None,
+        };
+        FreeSocks::new(vec![12, 10, 11], 2, &config, 1000)
+    }
+
+    #[test]
+    fn free_socks_are_generated_on_demand() {
+        let mut socks = free_socks(PortAllocation::Sequential);
+        assert_eq!(socks.len(), 6);
+        assert_eq!(socks.pop(), Some(10));
+        assert_eq!(socks.pop(), Some(1 << 16 | 10));
+        socks.push(10);
+        assert_eq!(socks.len(), 5);
+        let keys: Vec<u32> = (0..5).map(|_| socks.pop().unwrap()).collect();
+        assert_eq!(keys, vec![11, 1 << 16 | 11, 12, 1 << 16 | 12, 10]);
+        assert_eq!(socks.pop(), None);
+    }
+
+    #[test]
+    fn lifo_reuses_the_last_released_sock() {
+        let mut socks = free_socks(PortAllocation::Lifo);
+        assert_eq!(socks.pop(), Some(10));
+        assert_eq!(socks.pop(), Some(1 << 16 | 10));
+        socks.push(10);
+        assert_eq!(socks.pop(), Some(10));
+        assert_eq!(socks.pop(), Some(11));
+    }
+
+    #[test]
+    fn removed_port_is_not_allocated() {
+        for strategy in &[
+            PortAllocation::Sequential,
+            PortAllocation::Shuffled,
+            PortAllocation::Lifo,
+            PortAllocation::Random,
+            PortAllocation::Lru,
+        ] {
+            let mut socks = free_socks(*strategy);
+            socks.remove_port(11);
+            assert_eq!(socks.len(), 4);
+            let mut keys: Vec<u32> = (0..4).map(|_| socks.pop().unwrap()).collect();
+            keys.sort();
+            assert_eq!(keys, vec![10, 12, 1 << 16 | 10, 1 << 16 | 12]);
+            assert_eq!(socks.pop(), None);
+        }
+    }
+
+    #[test]
+    fn reassembly_queue_merges_ranges() {
+        let mut queue = ReassemblyQueue::new();
+        assert!(queue.insert(300, 400, false));
+        assert!(queue.insert(100, 200, false));
+        assert_eq!(queue.ranges.len(), 2);
+        // adjacent to both ranges
+        assert!(queue.insert(200, 300, false));
+        assert_eq!(queue.ranges.len(), 1);
+        assert_eq!((queue.ranges[0].seqn, queue.ranges[0].seqn_end), (100, 400));
+        assert_eq!(queue.ranges[0].segments, 3);
+        // a duplicate is not counted
+        assert!(queue.insert(150, 250, false));
+        assert_eq!(queue.ranges[0].segments, 3);
+    }
+
+    #[test]
+    fn reassembly_queue_wraps_around() {
+        let mut queue = ReassemblyQueue::new();
+        assert!(queue.insert(0x10, 0x20, false));
+        assert!(queue.insert(0xffff_fff0, 0x10, false));
+        assert_eq!(queue.ranges.len(), 1);
+        assert_eq!((queue.ranges[0].seqn, queue.ranges[0].seqn_end), (0xffff_fff0, 0x20));
+    }
+
+    #[test]
+    fn reassembly_queue_is_limited() {
+        let mut queue = ReassemblyQueue::new();
+        for i in 0..REASSEMBLY_QUEUE_SIZE as u32 {
+            assert!(queue.insert(i * 200, i * 200 + 100, false));
         }
+        assert!(!queue.insert(REASSEMBLY_QUEUE_SIZE as u32 * 200, REASSEMBLY_QUEUE_SIZE as u32 * 200 + 100, false));
+        // merging needs no additional range
+        assert!(queue.insert(100, 200, false));
+        assert_eq!(queue.ranges.len(), REASSEMBLY_QUEUE_SIZE - 1);
+    }
+
+    #[test]
+    fn reassembly_queue_keeps_the_fin() {
+        let mut queue = ReassemblyQueue::new();
+        assert!(queue.insert(300, 400, true));
+        assert!(queue.insert(200, 300, false));
+        assert_eq!(queue.ranges.len(), 1);
+        assert!(queue.ranges[0].fin);
+        // a retransmission of the FIN segment
+        let mut queue = ReassemblyQueue::new();
+        assert!(queue.insert(200, 400, false));
+        assert!(queue.insert(300, 400, true));
+        assert!(queue.ranges[0].fin);
+        // a FIN without payload
+        let mut queue = ReassemblyQueue::new();
+        assert!(queue.insert(200, 300, false));
+        assert!(queue.insert(300, 300, true));
+        assert_eq!((queue.ranges[0].seqn_end, queue.ranges[0].fin), (300, true));
+        // a segment, which extends the FIN range to the left
+        let mut queue = ReassemblyQueue::new();
+        assert!(queue.insert(300, 400, false));
+        assert!(queue.insert(200, 400, true));
+        assert_eq!((queue.ranges[0].seqn, queue.ranges[0].fin), (200, true));
+        // a segment, which merges two ranges and ends with the FIN range
+        let mut queue = ReassemblyQueue::new();
+        assert!(queue.insert(100, 200, false));
+        assert!(queue.insert(300, 400, true));
+        assert!(queue.insert(150, 400, false));
+        assert_eq!(queue.ranges.len(), 1);
+        assert!(queue.ranges[0].fin);
     }
 }
@@ -0,0 +1,375 @@
+use std::cmp;
+
+use cmanager::seq_le;
+use CongestionControlType;
+
+/// duplicate ACKs which trigger a fast retransmit (RFC 5681)
+pub const DUP_ACK_THRESHOLD: u8 = 3;
+
+/// per connection state of the congestion control, apart from the congestion window itself (all windows in bytes)
+#[derive(Debug, Clone, Copy)]
+pub struct CongestionState {
+    pub ssthresh: u32,
+    pub dup_acks: u8,
+    pub in_recovery: bool,
+    /// highest sequence no sent, when fast recovery was entered (RFC 6582)
+    pub recover: u32,
+    /// CUBIC: window before the last reduction
+    pub w_max: u32,
+    /// CUBIC: tsc when the current congestion avoidance epoch started, 0 if no epoch is running
+    pub epoch_start: u64,
+    /// CUBIC: time in millis, after which the window reaches w_max again
+    pub k: u32,
+}
+
+impl CongestionState {
+    pub fn new() -> CongestionState {
+        CongestionState {
+            ssthresh: u32::max_value(),
+            dup_acks: 0,
+            in_recovery: false,
+            recover: 0,
+            w_max: 0,
+            epoch_start: 0,
+            k: 0,
+        }
+    }
+}
+
+/// the algorithm specific part of the congestion control, i.e. the window growth in congestion avoidance and the reduction
+/// after a loss. Slow start, fast retransmit and fast recovery are common to all algorithms.
+pub trait CongestionControl: Send {
+    fn name(&self) -> &'static str;
+
+    /// increases the window for `acked` new bytes in congestion avoidance, `now` in cycles, `srtt` in millis
+    fn congestion_avoidance(
+        &self,
+        cwnd: &mut u32,
+        state: &mut CongestionState,
+        acked: u32,
+        mss: u32,
+        now: u64,
+        cpu_clock: u64,
+        srtt: u32,
+    );
+
+    /// returns the slow start threshold after a loss was detected
+    fn ssthresh_after_loss(&self, cwnd: u32, state: &mut CongestionState, flight_size: u32, mss: u32) -> u32;
+}
+
+/// RFC 5681 and RFC 6582
+pub struct NewReno;
+
+impl CongestionControl for NewReno {
+    fn name(&self) -> &'static str {
+        "NewReno"
+    }
+
+    #[inline]
+    fn congestion_avoidance(
+        &self,
+        cwnd: &mut u32,
+        _state: &mut CongestionState,
+        _acked: u32,
+        mss: u32,
+        _now: u64,
+        _cpu_clock: u64,
+        _srtt: u32,
+    ) {
+        *cwnd = cwnd.saturating_add(cmp::max(1, mss * mss / *cwnd));
+    }
+
+    #[inline]
+    fn ssthresh_after_loss(&self, _cwnd: u32, _state: &mut CongestionState, flight_size: u32, mss: u32) -> u32 {
+        cmp::max(flight_size / 2, 2 * mss)
+    }
+}
+
+/// RFC 8312
+pub struct Cubic {
+    c: f64,
+    beta: f64,
+}
+
+impl Cubic {
+    pub fn new() -> Cubic {
+        Cubic { c: 0.4, beta: 0.7 }
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn name(&self) -> &'static str {
+        "CUBIC"
+    }
+
+    fn congestion_avoidance(
+        &self,
+        cwnd: &mut u32,
+        state: &mut CongestionState,
+        acked: u32,
+        mss: u32,
+        now: u64,
+        cpu_clock: u64,
+        srtt: u32,
+    ) {
+        let mss_f = mss as f64;
+        if state.epoch_start == 0 {
+            state.epoch_start = now;
+            if *cwnd < state.w_max {
+                state.k = (((state.w_max - *cwnd) as f64 / mss_f / self.c).cbrt() * 1000.0) as u32;
+            } else {
+                state.k = 0;
+                state.w_max = *cwnd;
+            }
+        }
+        // windows in segments, times in seconds
+        let t = (now - state.epoch_start) as f64 / cpu_clock as f64;
+        let rtt = srtt as f64 / 1000.0;
+        let w_max = state.w_max as f64 / mss_f;
+        let offset = t + rtt - state.k as f64 / 1000.0;
+        let w_cubic = self.c * offset * offset * offset + w_max;
+        // the window standard TCP would have reached in the same time (TCP-friendly region)
+        let w_est = if rtt > 0.0 {
+            w_max * self.beta + 3.0 * (1.0 - self.beta) / (1.0 + self.beta) * t / rtt
+        } else {
+            0.0
+        };
+        let target = w_cubic.max(w_est) * mss_f;
+        let current = *cwnd as f64;
+        let increase = if target > current {
+            (target - current) * cmp::min(acked, mss) as f64 / current
+        } else {
+            // minimal growth in the plateau around w_max
+            mss_f * mss_f / (100.0 * current)
+        };
+        *cwnd = cwnd.saturating_add(cmp::max(1, increase as u32));
+    }
+
+    fn ssthresh_after_loss(&self, cwnd: u32, state: &mut CongestionState, _flight_size: u32, mss: u32) -> u32 {
+        state.epoch_start = 0;
+        // fast convergence: release bandwidth for new flows
+        state.w_max = if cwnd < state.w_max {
+            (cwnd as f64 * (1.0 + self.beta) / 2.0) as u32
+        } else {
+            cwnd
+        };
+        cmp::max((cwnd as f64 * self.beta) as u32, 2 * mss)
+    }
+}
+
+pub fn new_congestion_control(cc_type: CongestionControlType) -> Box<dyn CongestionControl> {
+    match cc_type {
+        CongestionControlType::NewReno => Box::new(NewReno),
+        CongestionControlType::Cubic => Box::new(Cubic::new()),
+    }
+}
+
+/// processes an ACK which acknowledges `acked` new bytes. Returns true for a partial acknowledgement during fast
+/// recovery (RFC 6582), in this case the oldest unacknowledged segment is to be retransmitted.
+#[inline]
+pub fn on_new_ack(
+    cc: &dyn CongestionControl,
+    cwnd: &mut u32,
+    state: &mut CongestionState,
+    ackn: u32,
+    acked: u32,
+    flight_size: u32,
+    mss: u32,
+    now: u64,
+    cpu_clock: u64,
+    srtt: u32,
+) -> bool {
+    state.dup_acks = 0;
+    if state.in_recovery {
+        if seq_le(state.recover, ackn) {
+            // full acknowledgement: deflate the window
+            *cwnd = cmp::min(state.ssthresh, cmp::max(flight_size, mss) + mss);
+            state.in_recovery = false;
+            false
+        } else {
+            // partial acknowledgement: deflate by the amount of new data and add back one segment
+            *cwnd = cwnd.saturating_sub(acked);
+            if acked >= mss {
+                *cwnd += mss;
+            }
+            true
+        }
+    } else if *cwnd < state.ssthresh {
+        // slow start
+        *cwnd = cwnd.saturating_add(cmp::min(acked, mss));
+        false
+    } else {
+        cc.congestion_avoidance(cwnd, state, acked, mss, now, cpu_clock, srtt);
+        false
+    }
+}
+
+/// processes a duplicate ACK, returns true if the oldest unacknowledged segment is to be retransmitted (fast retransmit)
+#[inline]
+pub fn on_dup_ack(
+    cc: &dyn CongestionControl,
+    cwnd: &mut u32,
+    state: &mut CongestionState,
+    seqn_nxt: u32,
+    flight_size: u32,
+    mss: u32,
+) -> bool {
+    state.dup_acks = state.dup_acks.saturating_add(1);
+    if state.in_recovery {
+        // each duplicate ACK signals a segment which left the network
+        *cwnd = cwnd.saturating_add(mss);
+        false
+    } else if state.dup_acks == DUP_ACK_THRESHOLD {
+        state.ssthresh = cc.ssthresh_after_loss(*cwnd, state, flight_size, mss);
+        state.recover = seqn_nxt;
+        state.in_recovery = true;
+        *cwnd = state.ssthresh + 3 * mss;
+        true
+    } else {
+        false
+    }
+}
+
+/// collapses the window after a retransmission timeout, the threshold is only lowered for the first timeout of a segment
+#[inline]
+pub fn on_timeout(
+    cc: &dyn CongestionControl,
+    cwnd: &mut u32,
+    state: &mut CongestionState,
+    flight_size: u32,
+    mss: u32,
+    first: bool,
+) {
+    if first {
+        state.ssthresh = cc.ssthresh_after_loss(*cwnd, state, flight_size, mss);
+    }
+    state.in_recovery = false;
+    state.dup_acks = 0;
+    *cwnd = mss;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: u32 = 1000;
+    const CPU_CLOCK: u64 = 1_000_000_000;
+
+    fn new_ack(cc: &dyn CongestionControl, cwnd: &mut u32, state: &mut CongestionState, ackn: u32, acked: u32) -> bool {
+        on_new_ack(cc, cwnd, state, ackn, acked, 0, MSS, 0, CPU_CLOCK, 0)
+    }
+
+    #[test]
+    fn slow_start_grows_by_acked_bytes() {
+        let cc = NewReno;
+        let mut cwnd = 2 * MSS;
+        let mut state = CongestionState::new();
+        assert!(!new_ack(&cc, &mut cwnd, &mut state, 1000, 500));
+        assert_eq!(cwnd, 2 * MSS + 500);
+        // at most one segment per ACK
+        new_ack(&cc, &mut cwnd, &mut state, 4000, 3000);
+        assert_eq!(cwnd, 3 * MSS + 500);
+    }
+
+    #[test]
+    fn new_reno_congestion_avoidance() {
+        let cc = NewReno;
+        let mut cwnd = 10 * MSS;
+        let mut state = CongestionState::new();
+        state.ssthresh = 5 * MSS;
+        new_ack(&cc, &mut cwnd, &mut state, 1000, MSS);
+        assert_eq!(cwnd, 10 * MSS + MSS / 10);
+        assert_eq!(cc.ssthresh_after_loss(cwnd, &mut state, 10 * MSS, MSS), 5 * MSS);
+        assert_eq!(cc.ssthresh_after_loss(cwnd, &mut state, MSS, MSS), 2 * MSS);
+    }
+
+    #[test]
+    fn fast_retransmit_and_recovery() {
+        let cc = NewReno;
+        let mut cwnd = 10 * MSS;
+        let mut state = CongestionState::new();
+        let flight_size = 10 * MSS;
+        assert!(!on_dup_ack(&cc, &mut cwnd, &mut state, 20_000, flight_size, MSS));
+        assert!(!on_dup_ack(&cc, &mut cwnd, &mut state, 20_000, flight_size, MSS));
+        assert!(on_dup_ack(&cc, &mut cwnd, &mut state, 20_000, flight_size, MSS));
+        assert!(state.in_recovery);
+        assert_eq!(state.ssthresh, 5 * MSS);
+        assert_eq!(state.recover, 20_000);
+        assert_eq!(cwnd, 8 * MSS);
+        // window inflation
+        assert!(!on_dup_ack(&cc, &mut cwnd, &mut state, 20_000, flight_size, MSS));
+        assert_eq!(cwnd, 9 * MSS);
+        // a partial acknowledgement retransmits the next hole
+        assert!(new_ack(&cc, &mut cwnd, &mut state, 15_000, 2 * MSS));
+        assert!(state.in_recovery);
+        assert_eq!(cwnd, 8 * MSS);
+        assert_eq!(state.dup_acks, 0);
+        // the full acknowledgement ends the recovery and deflates the window
+        assert!(!new_ack(&cc, &mut cwnd, &mut state, 20_000, 5 * MSS));
+        assert!(!state.in_recovery);
+        assert_eq!(cwnd, 2 * MSS);
+    }
+
+    #[test]
+    fn recovery_point_wraps_around() {
+        let cc = NewReno;
+        let mut cwnd = 10 * MSS;
+        let mut state = CongestionState::new();
+        for _ in 0..DUP_ACK_THRESHOLD {
+            on_dup_ack(&cc, &mut cwnd, &mut state, 500, 10 * MSS, MSS);
+        }
+        assert!(new_ack(&cc, &mut cwnd, &mut state, 0xffff_ff00, MSS));
+        assert!(!new_ack(&cc, &mut cwnd, &mut state, 600, MSS));
+    }
+
+    #[test]
+    fn timeout_collapses_window() {
+        let cc = NewReno;
+        let mut cwnd = 20 * MSS;
+        let mut state = CongestionState::new();
+        on_timeout(&cc, &mut cwnd, &mut state, 20 * MSS, MSS, true);
+        assert_eq!(cwnd, MSS);
+        assert_eq!(state.ssthresh, 10 * MSS);
+        // a second timeout of the same segment keeps the threshold
+        on_timeout(&cc, &mut cwnd, &mut state, MSS, MSS, false);
+        assert_eq!(state.ssthresh, 10 * MSS);
+    }
+
+    #[test]
+    fn cubic_reduction_and_fast_convergence() {
+        let cc = Cubic::new();
+        let mut state = CongestionState::new();
+        assert_eq!(cc.ssthresh_after_loss(100 * MSS, &mut state, 0, MSS), 70 * MSS);
+        assert_eq!(state.w_max, 100 * MSS);
+        // a loss below the last maximum releases bandwidth
+        assert_eq!(cc.ssthresh_after_loss(80 * MSS, &mut state, 0, MSS), 56 * MSS);
+        assert_eq!(state.w_max, 68 * MSS);
+        assert_eq!(cc.ssthresh_after_loss(MSS, &mut state, 0, MSS), 2 * MSS);
+    }
+
+    #[test]
+    fn cubic_grows_back_to_w_max() {
+        let cc = Cubic::new();
+        let mut state = CongestionState::new();
+        let mut cwnd = 100 * MSS;
+        state.ssthresh = cc.ssthresh_after_loss(cwnd, &mut state, 0, MSS);
+        cwnd = state.ssthresh;
+        let srtt = 100;
+        let mut now = CPU_CLOCK;
+        cc.congestion_avoidance(&mut cwnd, &mut state, MSS, MSS, now, CPU_CLOCK, srtt);
+        assert_eq!(state.epoch_start, now);
+        // K = cbrt(w_max * (1 - beta) / C) seconds
+        assert_eq!(state.k, 4217);
+        let mut last = cwnd;
+        // one ACK per segment and round trip, for 4 seconds
+        for _ in 0..40 {
+            now += CPU_CLOCK / 10;
+            for _ in 0..cwnd / MSS {
+                cc.congestion_avoidance(&mut cwnd, &mut state, MSS, MSS, now, CPU_CLOCK, srtt);
+            }
+            assert!(cwnd >= last);
+            last = cwnd;
+        }
+        assert!(cwnd > 95 * MSS && cwnd < 101 * MSS, "cwnd = {}", cwnd);
+    }
+}
@@ -0,0 +1,398 @@
+use std::fs::File;
+use std::io::{self, Write, BufWriter, BufRead, BufReader, Read};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use bincode;
+use serde_json;
+use separator::Separatable;
+
+use netfcts::comm::PipelineId;
+use netfcts::conrecord::{ConRecord, HasTcpState, HasConData};
+
+use {ExportFormat, RecordExportConfig};
+
+/// all fields of a ConRecord, the states are given by their names, the time stamps of the states as deltas to the
+/// first stamp
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RecordData {
+    pub uid: u64,
+    pub role: String,
+    /// socket of the client side, (0, 0) if unknown
+    pub ip: u32,
+    pub sock_port: u16,
+    pub port: u16,
+    pub server_index: u64,
+    pub sent_payload_packets: u64,
+    pub recv_payload_packets: u64,
+    pub release_cause: String,
+    pub first_stamp: Option<u64>,
+    pub states: Vec<String>,
+    pub deltas: Vec<u64>,
+}
+
+impl RecordData {
+    pub fn from_con_record(c: &ConRecord) -> RecordData {
+        let sock = c.sock();
+        RecordData {
+            uid: c.uid(),
+            role: format!("{:?}", c.role()),
+            ip: sock.0,
+            sock_port: sock.1,
+            port: c.port(),
+            server_index: c.server_index() as u64,
+            sent_payload_packets: c.sent_payload_packets() as u64,
+            recv_payload_packets: c.recv_payload_packets() as u64,
+            release_cause: format!("{:?}", c.release_cause()),
+            first_stamp: c.get_first_stamp(),
+            states: c.states().iter().map(|s| format!("{:?}", s)).collect(),
+            deltas: c.deltas_to_base_stamp().iter().map(|d| *d as u64).collect(),
+        }
+    }
+
+    /// time stamp of the last state
+    pub fn last_stamp(&self) -> Option<u64> {
+        self.first_stamp.map(|first| first + self.deltas.last().cloned().unwrap_or(0))
+    }
+
+    /// the inverse of csv_fields, None if the fields are empty, i.e. there is no server record
+    fn from_csv_fields(fields: &[&str]) -> io::Result<Option<RecordData>> {
+        if fields.iter().all(|f| f.is_empty()) {
+            return Ok(None);
+        }
+        let split = |f: &str| -> Vec<String> {
+            if f.is_empty() {
+                Vec::new()
+            } else {
+                f.split(';').map(|s| s.to_string()).collect()
+            }
+        };
+        let ip: Ipv4Addr = fields[2].parse().map_err(invalid_data)?;
+        Ok(Some(RecordData {
+            uid: fields[0].parse().map_err(invalid_data)?,
+            role: fields[1].to_string(),
+            ip: u32::from(ip),
+            sock_port: fields[3].parse().map_err(invalid_data)?,
+            port: fields[4].parse().map_err(invalid_data)?,
+            server_index: fields[5].parse().map_err(invalid_data)?,
+            sent_payload_packets: fields[6].parse().map_err(invalid_data)?,
+            recv_payload_packets: fields[7].parse().map_err(invalid_data)?,
+            release_cause: fields[8].to_string(),
+            first_stamp: if fields[9].is_empty() {
+                None
+            } else {
+                Some(fields[9].parse().map_err(invalid_data)?)
+            },
+            states: split(fields[10]),
+            deltas: split(fields[11])
+                .iter()
+                .map(|d| d.parse().map_err(invalid_data))
+                .collect::<io::Result<Vec<u64>>>()?,
+        }))
+    }
+
+    fn csv_fields(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{}",
+            self.uid,
+            self.role,
+            Ipv4Addr::from(self.ip),
+            self.sock_port,
+            self.port,
+            self.server_index,
+            self.sent_payload_packets,
+            self.recv_payload_packets,
+            self.release_cause,
+            self.first_stamp.map(|s| s.to_string()).unwrap_or_default(),
+            self.states.join(";"),
+            self.deltas.iter().map(|d| d.to_string()).collect::<Vec<_>>().join(";"),
+        )
+    }
+}
+
+const CSV_FIELDS: [&str; 12] = [
+    "uid",
+    "role",
+    "ip",
+    "sock_port",
+    "port",
+    "server_index",
+    "sent_payload_packets",
+    "recv_payload_packets",
+    "release_cause",
+    "first_stamp",
+    "states",
+    "deltas",
+];
+
+/// a record together with its pipeline and, for client records, the record of the server side of the same connection
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExportRecord {
+    pub pipeline: String,
+    pub record: RecordData,
+    pub server: Option<RecordData>,
+}
+
+/// writes connection records in the configured format. Text is the human readable format of former versions, Csv has
+/// one line per record with the fields of the server record appended, JsonLines one JSON object per line, and Bincode
+/// frames each bincode encoded ExportRecord by its length as u32 in little endian.
+pub struct RecordWriter {
+    format: ExportFormat,
+    f: BufWriter<File>,
+    /// format Text: records are numbered per pipeline
+    pipeline: Option<PipelineId>,
+    index: usize,
+    count: usize,
+}
+
+impl RecordWriter {
+    pub fn create(config: &RecordExportConfig) -> io::Result<RecordWriter> {
+        let mut f = BufWriter::new(File::create(config.file())?);
+        if config.format() == ExportFormat::Csv {
+            let server_fields: Vec<String> = CSV_FIELDS.iter().map(|f| format!("server_{}", f)).collect();
+            writeln!(f, "pipeline,{},{}", CSV_FIELDS.join(","), server_fields.join(","))?;
+        }
+        Ok(RecordWriter {
+            format: config.format(),
+            f,
+            pipeline: None,
+            index: 0,
+            count: 0,
+        })
+    }
+
+    /// server is the paired server record of a client record
+    pub fn write(&mut self, pipeline_id: &PipelineId, c: &ConRecord, server: Option<&ConRecord>) -> io::Result<()> {
+        if self.format == ExportFormat::Text {
+            self.write_text(pipeline_id, c, server)?;
+            self.count += 1;
+            return Ok(());
+        }
+        self.write_record(&ExportRecord {
+            pipeline: pipeline_id.to_string(),
+            record: RecordData::from_con_record(c),
+            server: server.map(|s| RecordData::from_con_record(s)),
+        })
+    }
+
+    /// writes a record in one of the formats, which can be read by read_records
+    pub fn write_record(&mut self, record: &ExportRecord) -> io::Result<()> {
+        self.write_formatted(record)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn write_formatted(&mut self, record: &ExportRecord) -> io::Result<()> {
+        match self.format {
+            ExportFormat::Csv => {
+                let server_fields = match record.server {
+                    Some(ref s) => s.csv_fields(),
+                    None => vec![""; CSV_FIELDS.len()].join(","),
+                };
+                writeln!(self.f, "{},{},{}", record.pipeline, record.record.csv_fields(), server_fields)
+            }
+            ExportFormat::JsonLines => {
+                serde_json::to_writer(&mut self.f, record).map_err(io::Error::from)?;
+                writeln!(self.f)
+            }
+            ExportFormat::Bincode => {
+                let buf = bincode::serialize(record).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                self.f.write_all(&(buf.len() as u32).to_le_bytes())?;
+                self.f.write_all(&buf)
+            }
+            ExportFormat::Text => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "records in format Text are written from a ConRecord",
+            )),
+        }
+    }
+
+    fn write_text(&mut self, pipeline_id: &PipelineId, c: &ConRecord, server: Option<&ConRecord>) -> io::Result<()> {
+        if self.pipeline.as_ref() != Some(pipeline_id) {
+            writeln!(self.f, "Pipeline {}:", pipeline_id)?;
+            self.pipeline = Some(pipeline_id.clone());
+            self.index = 0;
+        }
+        writeln!(self.f, "{:6}: {}", self.index, c)?;
+        self.index += 1;
+        if let Some(c_server) = server {
+            writeln!(
+                self.f,
+                "        ({:?}, {:21}, {:6}, {:3}, {:7}, {:7}, {:?}, {:?}, +{}, {:?})",
+                c_server.role(),
+                if c_server.sock().0 != 0 {
+                    let s = c_server.sock();
+                    SocketAddrV4::new(Ipv4Addr::from(s.0), s.1).to_string()
+                } else {
+                    "none".to_string()
+                },
+                c_server.port(),
+                c_server.server_index(),
+                c_server.sent_payload_packets(),
+                c_server.recv_payload_packets(),
+                c_server.states(),
+                c_server.release_cause(),
+                (c_server.get_first_stamp().unwrap_or(0) as i64 - c.get_first_stamp().unwrap_or(0) as i64)
+                    .separated_string(),
+                c_server
+                    .deltas_to_base_stamp()
+                    .iter()
+                    .map(|u| u.separated_string())
+                    .collect::<Vec<_>>(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// number of records written
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.f.flush()
+    }
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// reads records written by a RecordWriter, the format Text cannot be read
+pub fn read_records(file: &str, format: ExportFormat) -> io::Result<Vec<ExportRecord>> {
+    let mut reader = BufReader::new(File::open(file)?);
+    let mut records = Vec::new();
+    match format {
+        ExportFormat::Text => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "records in format Text cannot be read",
+            ))
+        }
+        ExportFormat::Csv => {
+            for (i, line) in reader.lines().enumerate() {
+                let line = line?;
+                // the first line is the header
+                if i == 0 || line.is_empty() {
+                    continue;
+                }
+                let fields: Vec<&str> = line.split(',').collect();
+                if fields.len() != 1 + 2 * CSV_FIELDS.len() {
+                    return Err(invalid_data(format!("line {}: wrong number of fields", i + 1)));
+                }
+                let record = RecordData::from_csv_fields(&fields[1..1 + CSV_FIELDS.len()])?;
+                records.push(ExportRecord {
+                    pipeline: fields[0].to_string(),
+                    record: record.ok_or(invalid_data(format!("line {}: empty record", i + 1)))?,
+                    server: RecordData::from_csv_fields(&fields[1 + CSV_FIELDS.len()..])?,
+                });
+            }
+        }
+        ExportFormat::JsonLines => {
+            for line in reader.lines() {
+                let line = line?;
+                if !line.is_empty() {
+                    records.push(serde_json::from_str(&line).map_err(invalid_data)?);
+                }
+            }
+        }
+        ExportFormat::Bincode => {
+            let mut len = [0u8; 4];
+            loop {
+                match reader.read_exact(&mut len) {
+                    Ok(()) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                }
+                let mut buf = vec![0u8; u32::from_le_bytes(len) as usize];
+                reader.read_exact(&mut buf)?;
+                records.push(bincode::deserialize(&buf).map_err(invalid_data)?);
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn record_data(uid: u64, role: &str, states: &[&str], deltas: &[u64]) -> RecordData {
+        RecordData {
+            uid,
+            role: role.to_string(),
+            ip: 0x0a00_0001,
+            sock_port: 40000,
+            port: 80,
+            server_index: 3,
+            sent_payload_packets: 10,
+            recv_payload_packets: 9,
+            release_cause: "ActiveClose".to_string(),
+            first_stamp: if states.is_empty() { None } else { Some(1_000_000) },
+            states: states.iter().map(|s| s.to_string()).collect(),
+            deltas: deltas.to_vec(),
+        }
+    }
+
+    fn records() -> Vec<ExportRecord> {
+        vec![
+            ExportRecord {
+                pipeline: "1-0-0".to_string(),
+                record: record_data(1, "Client", &["Listen", "SynSent", "Established"], &[0, 1200, 1500]),
+                server: Some(record_data(2, "Server", &["Listen", "SynReceived"], &[0, 700])),
+            },
+            // no time stamps and no server record
+            ExportRecord {
+                pipeline: "2-0-1".to_string(),
+                record: record_data(3, "Client", &[], &[]),
+                server: None,
+            },
+        ]
+    }
+
+    fn round_trip(format: ExportFormat) {
+        let file = env::temp_dir().join(format!("round_trip_{:?}", format)).to_string_lossy().into_owned();
+        let config = RecordExportConfig {
+            format: Some(format),
+            file: Some(file.clone()),
+        };
+        let mut writer = RecordWriter::create(&config).unwrap();
+        for record in &records() {
+            writer.write_record(record).unwrap();
+        }
+        writer.flush().unwrap();
+        assert_eq!(writer.count(), 2);
+        assert_eq!(read_records(&file, format).unwrap(), records());
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        round_trip(ExportFormat::Csv);
+    }
+
+    #[test]
+    fn json_lines_round_trip() {
+        round_trip(ExportFormat::JsonLines);
+    }
+
+    #[test]
+    fn bincode_round_trip() {
+        round_trip(ExportFormat::Bincode);
+    }
+
+    #[test]
+    fn text_cannot_be_read() {
+        let file = env::temp_dir().join("round_trip_text").to_string_lossy().into_owned();
+        let config = RecordExportConfig {
+            format: Some(ExportFormat::Text),
+            file: Some(file.clone()),
+        };
+        let mut writer = RecordWriter::create(&config).unwrap();
+        assert!(writer.write_record(&records()[0]).is_err());
+        assert_eq!(writer.count(), 0);
+        assert_eq!(read_records(&file, ExportFormat::Text).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        fs::remove_file(&file).unwrap();
+    }
+}
@@ -19,17 +19,31 @@ extern crate bincode;
 extern crate serde_json;
 extern crate netfcts;
 extern crate ipnet;
+extern crate rand;
+extern crate libc;
 extern crate core;
 
 pub mod nftraffic;
 pub mod run_test;
+pub mod statistics;
+pub mod export;
+pub mod replay;
 mod cmanager;
+mod congestion;
+mod tcp_options;
+mod syn_cookies;
+mod targets;
+mod pcap;
+mod arrival;
+mod profile;
 
 pub use netfcts::tcp_common::{CData, L234Data, ReleaseCause, UserData, TcpRole, TcpState, TcpCounter, TcpStatistics};
 pub use netfcts::conrecord::ConRecord;
 pub use netfcts::recstore::TEngineStore;
 
 pub use cmanager::{Connection};
+pub use targets::{Targets, TargetRange};
+pub use statistics::{EngineStatistics, EngineCounter};
 
 use eui48::MacAddress;
 use uuid::Uuid;
@@ -38,14 +52,19 @@ use e2d2::scheduler::*;
 use e2d2::interface::{PmdPort, Pdu};
 
 use nftraffic::setup_generator;
+use profile::LoadSeries;
+use cmanager::MAX_IP_POOL_SIZE;
 use netfcts::tasks::*;
 use netfcts::comm::{MessageFrom, MessageTo, PipelineId};
 use netfcts::{new_port_queues_for_core, physical_ports_for_core, RunConfiguration};
 use netfcts::utils::Timeouts;
 
+use ipnet::Ipv4Net;
+
 use std::net::Ipv4Addr;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::cmp;
 
 pub trait FnPayload =
     Fn(&mut Pdu, &mut Connection, Option<CData>, &mut bool) -> usize + Sized + Send + Sync + Clone + 'static;
@@ -56,33 +75,518 @@ pub struct Configuration {
     pub targets: Vec<TargetConfig>,
     pub engine: EngineConfig,
     pub test_size: Option<usize>,
+    /// millis of the steady state of a run, which ends after warm_up + duration + cool_down instead of after
+    /// test_size connections
+    pub duration: Option<u64>,
+    /// millis before the steady state, defaults to 0
+    pub warm_up: Option<u64>,
+    /// millis after the steady state, in which no new client connections are opened, defaults to 0
+    pub cool_down: Option<u64>,
+    /// load profile of the client side, given as [[phase]] tables, which run one after the other
+    pub phase: Option<Vec<PhaseConfig>>,
+    /// load profile of the client side, given as CSV file with the rows second,cps,concurrent,payloads_per_conn for
+    /// the whole engine, replaces the phases
+    pub load_series: Option<String>,
+}
+
+impl Configuration {
+    /// number of client connections per pipeline, without test_size unlimited with a duration or a load profile,
+    /// otherwise 128
+    pub fn test_size(&self) -> usize {
+        if self.duration.is_some() || self.phase.is_some() || self.load_series.is_some() {
+            self.test_size.unwrap_or(usize::max_value())
+        } else {
+            self.test_size.unwrap_or(128)
+        }
+    }
+
+    pub fn warm_up(&self) -> u64 {
+        self.warm_up.unwrap_or(0)
+    }
+
+    pub fn cool_down(&self) -> u64 {
+        self.cool_down.unwrap_or(0)
+    }
+
+    /// millis from the start to the end of a run with a duration
+    pub fn run_time(&self) -> Option<u64> {
+        self.duration.map(|duration| self.warm_up() + duration + self.cool_down())
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PhaseConfig {
+    /// name in the report, defaults to phase_<index>
+    pub id: Option<String>,
+    /// in millis
+    pub duration: u64,
+    /// connections per second and pipeline at the start and at the end of the phase, in between the rate is
+    /// interpolated linearly. start_cps defaults to cps_limit, end_cps to start_cps.
+    pub start_cps: Option<u64>,
+    pub end_cps: Option<u64>,
+    /// maximum of concurrent client connections per pipeline, interpolated like the rate. start_concurrency defaults
+    /// to max_open, end_concurrency to start_concurrency.
+    pub start_concurrency: Option<usize>,
+    pub end_concurrency: Option<usize>,
 }
 
 
 #[derive(Deserialize, Clone)]
 pub struct EngineConfig {
     pub timeouts: Option<Timeouts>,
+    /// timeouts of the connection phases, which are not configured, default to timeouts.established
+    pub state_timeouts: Option<StateTimeoutsConfig>,
     pub port: u16,
     pub cps_limit: Option<u64>,
     pub max_open: Option<usize>,
     pub detailed_records: Option<bool>,
     pub fin_by_client: Option<usize>,
     pub fin_by_server: Option<usize>,
+    /// if present, lost SYN, payload and FIN segments are retransmitted
+    pub retransmission: Option<RetransmissionConfig>,
+    /// congestion control of the client side, requires retransmissions (defaults are used, if these are not configured)
+    pub congestion_control: Option<CongestionControlType>,
+    /// initial congestion window in segments, defaults to 10 (RFC 6928)
+    pub initial_cwnd: Option<u32>,
+    /// the client sends its payloads back-to-back as far as the windows allow, instead of waiting for the reply of the DUT
+    pub bulk_transfer: Option<bool>,
+    /// options offered in client SYNs and accepted from SYNs of the DUT, without options are neither sent nor evaluated
+    pub tcp_options: Option<TcpOptionsConfig>,
+    /// connections which time out are reset, defaults to true
+    pub rst_on_timeout: Option<bool>,
+    /// sizing of the connection table of the server side
+    pub server_table: Option<ServerTableConfig>,
+    /// the server side answers SYNs with a cookie and creates the connection only with the final ACK of the handshake
+    pub syn_cookies: Option<bool>,
+    /// 2MSL in millis, client ports of actively closed connections are reused only after this time. Without time_wait
+    /// the connections skip TIME_WAIT and their ports are reused immediately.
+    pub time_wait: Option<u64>,
+    /// source addresses of client connections as CIDR, e.g. "10.0.10.0/24", each pipeline uses all hosts of the net
+    pub ip_pool: Option<String>,
+    /// order in which client ports are allocated and reused, defaults to Shuffled
+    pub port_allocation: Option<PortAllocationConfig>,
+    /// listen ports of the server side, without services each pipeline listens on the highest port of its port range
+    pub services: Option<Vec<ServiceConfig>>,
+    /// how client connections are distributed over the targets, defaults to round robin
+    pub target_selection: Option<TargetSelectionConfig>,
+    /// connection records are shipped to the master thread while the test runs and written to disk, requires
+    /// detailed_records
+    pub record_streaming: Option<RecordStreamingConfig>,
+    /// format and file of the connection records
+    pub record_export: Option<RecordExportConfig>,
+    /// packets of selected connections are written to a pcap file per pipeline
+    pub capture: Option<CaptureConfig>,
+    /// the client payload is taken from TCP streams of a pcap file
+    pub replay: Option<ReplayConfig>,
+    /// process of the arrivals of client connections at the mean rate cps_limit, defaults to batches of 32 SYNs
+    pub arrival: Option<ArrivalConfig>,
 }
 
 impl EngineConfig {
     pub fn cps_limit(&self) -> u64 {
         self.cps_limit.unwrap_or(10000000)
     }
+
+    pub fn initial_cwnd(&self) -> u32 {
+        self.initial_cwnd.unwrap_or(10)
+    }
+
+    pub fn bulk_transfer(&self) -> bool {
+        self.bulk_transfer.unwrap_or(false)
+    }
+
+    pub fn rst_on_timeout(&self) -> bool {
+        self.rst_on_timeout.unwrap_or(true)
+    }
+
+    pub fn syn_cookies(&self) -> bool {
+        self.syn_cookies.unwrap_or(false)
+    }
+
+    /// the hosts of ip_pool, empty if no pool is configured. Panics, if the pool exceeds MAX_IP_POOL_SIZE hosts.
+    pub fn ip_pool(&self) -> Vec<u32> {
+        match self.ip_pool {
+            Some(ref cidr) => {
+                let net: Ipv4Net = cidr.parse().expect("ip_pool must be a CIDR, e.g. 10.0.10.0/24");
+                let hosts: Vec<u32> = net.hosts().take(MAX_IP_POOL_SIZE + 1).map(u32::from).collect();
+                if hosts.len() > MAX_IP_POOL_SIZE {
+                    panic!("ip_pool {} has more than {} hosts, use a prefix of /16 or longer", cidr, MAX_IP_POOL_SIZE);
+                }
+                hosts
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum CongestionControlType {
+    NewReno,
+    Cubic,
+}
+
+/// all times in millis
+#[derive(Deserialize, Clone, Default)]
+pub struct RetransmissionConfig {
+    /// used until a round trip time sample is available
+    pub initial_rto: Option<u64>,
+    pub min_rto: Option<u64>,
+    pub max_rto: Option<u64>,
+    /// number of retransmissions of a segment, before the connection is given up
+    pub max_retries: Option<u8>,
+    /// number of retransmissions of a SYN, respectively SYN-ACK, before the connection attempt is given up, defaults to
+    /// max_retries
+    pub syn_retries: Option<u8>,
+}
+
+impl RetransmissionConfig {
+    pub fn initial_rto(&self) -> u64 {
+        self.initial_rto.unwrap_or(1000)
+    }
+
+    pub fn min_rto(&self) -> u64 {
+        self.min_rto.unwrap_or(200)
+    }
+
+    pub fn max_rto(&self) -> u64 {
+        self.max_rto.unwrap_or(8000)
+    }
+
+    pub fn max_retries(&self) -> u8 {
+        self.max_retries.unwrap_or(5)
+    }
+
+    pub fn syn_retries(&self) -> u8 {
+        self.syn_retries.unwrap_or(self.max_retries())
+    }
+}
+
+/// all times in millis
+#[derive(Deserialize, Clone, Default)]
+pub struct StateTimeoutsConfig {
+    /// from sending the SYN, respectively receiving it, until the connection is established
+    pub handshake: Option<u64>,
+    /// maximum time without a segment from the DUT in the states Established and CloseWait
+    pub idle: Option<u64>,
+    /// states FinWait1, FinWait2 and Closing
+    pub fin_wait: Option<u64>,
+    pub last_ack: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PortAllocation {
+    /// ports in ascending order, released ports are reused last
+    Sequential,
+    /// ports in random order, released ports are reused last
+    Shuffled,
+    /// the port released last is reused first
+    Lifo,
+    /// each port is drawn randomly from the free ports
+    Random,
+    /// as Shuffled, but a released port is reused only after min_reuse_age
+    Lru,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct PortAllocationConfig {
+    pub strategy: Option<PortAllocation>,
+    /// seed of the strategy Random, without a seed each run draws different ports
+    pub seed: Option<u64>,
+    /// strategy Lru: minimum time in millis between the release of a port and its reuse, defaults to 0
+    pub min_reuse_age: Option<u64>,
+}
+
+impl PortAllocationConfig {
+    pub fn strategy(&self) -> PortAllocation {
+        self.strategy.unwrap_or(PortAllocation::Shuffled)
+    }
+
+    pub fn min_reuse_age(&self) -> u64 {
+        self.min_reuse_age.unwrap_or(0)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ServiceResponse {
+    /// each payload of the DUT is sent back
+    Echo,
+    /// payloads of the DUT are only acknowledged
+    Ack,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ServiceConfig {
+    pub id: Option<String>,
+    pub port: u16,
+    /// the server closes the connection after this number of payloads, defaults to fin_by_server of the engine
+    pub fin_by_server: Option<usize>,
+    /// defaults to Echo
+    pub response: Option<ServiceResponse>,
+}
+
+impl ServiceConfig {
+    pub fn response(&self) -> ServiceResponse {
+        self.response.unwrap_or(ServiceResponse::Echo)
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct ServerTableConfig {
+    /// connections allocated at start, defaults to 0xFFFF
+    pub capacity: Option<usize>,
+    /// the table doubles its size, when it is full, up to this number of connections. Defaults to capacity, i.e. the
+    /// table does not grow.
+    pub max_capacity: Option<usize>,
+}
+
+impl ServerTableConfig {
+    pub fn capacity(&self) -> usize {
+        self.capacity.unwrap_or(0xFFFF)
+    }
+
+    pub fn max_capacity(&self) -> usize {
+        self.max_capacity.unwrap_or(self.capacity())
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum RecordOverflow {
+    /// new connections are not recorded
+    Drop,
+    /// only every sample_rate-th new connection is recorded, further buffers are allocated for these up to twice the
+    /// number of buffers, then the samples are dropped too
+    Sample,
+    /// no new client connections are opened and SYNs of the DUT are dropped
+    Block,
+}
+
+/// The master pairs the shipped client and server records of a connection by their uuid. Per side at most
+/// 2 * buffers * buffer_size records wait for their counterpart, beyond that the record which waits longest is written
+/// without it. The summary counts of the records are kept while streaming, the per-pipeline cycle statistics of the
+/// records are not.
+#[derive(Deserialize, Clone, Default)]
+pub struct RecordStreamingConfig {
+    /// records per buffer, defaults to 0x10000
+    pub buffer_size: Option<usize>,
+    /// buffers per pipeline and side, i.e. the active one and those waiting for shipping, defaults to 2
+    pub buffers: Option<usize>,
+    /// applies when all buffers are in use, defaults to Drop
+    pub overflow: Option<RecordOverflow>,
+    /// defaults to 10
+    pub sample_rate: Option<usize>,
+    /// interval in millis between shipments, defaults to 1000
+    pub interval: Option<u64>,
+}
+
+impl RecordStreamingConfig {
+    pub fn buffer_size(&self) -> usize {
+        cmp::max(self.buffer_size.unwrap_or(0x10000), 1)
+    }
+
+    pub fn buffers(&self) -> usize {
+        cmp::max(self.buffers.unwrap_or(2), 1)
+    }
+
+    pub fn overflow(&self) -> RecordOverflow {
+        self.overflow.unwrap_or(RecordOverflow::Drop)
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        cmp::max(self.sample_rate.unwrap_or(10), 1)
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval.unwrap_or(1000)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    /// human readable, not meant for post-processing
+    Text,
+    Csv,
+    JsonLines,
+    /// bincode encoded records, each preceded by its length
+    Bincode,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct RecordExportConfig {
+    /// defaults to Text
+    pub format: Option<ExportFormat>,
+    /// defaults to "c_records" with an extension for the format
+    pub file: Option<String>,
+}
+
+impl RecordExportConfig {
+    pub fn format(&self) -> ExportFormat {
+        self.format.unwrap_or(ExportFormat::Text)
+    }
+
+    pub fn file(&self) -> String {
+        match self.file {
+            Some(ref file) => file.clone(),
+            None => match self.format() {
+                ExportFormat::Text => "c_records.txt".to_string(),
+                ExportFormat::Csv => "c_records.csv".to_string(),
+                ExportFormat::JsonLines => "c_records.jsonl".to_string(),
+                ExportFormat::Bincode => "c_records.bin".to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct CaptureConfig {
+    /// share of the connections captured, in (0, 1], defaults to 1. The connections are selected by a hash of their
+    /// sockets, so the share is met only approximately.
+    pub sample_ratio: Option<f64>,
+    /// only connections with an address in this net, e.g. "10.0.0.0/24"
+    pub net: Option<String>,
+    /// only connections with this port on either side
+    pub port: Option<u16>,
+    /// bytes captured per packet, defaults to 1518
+    pub snaplen: Option<u32>,
+    /// packets per pipeline, defaults to 1000000
+    pub max_packets: Option<usize>,
+    /// directory of the pcap files, defaults to the working directory
+    pub dir: Option<String>,
+}
+
+impl CaptureConfig {
+    pub fn sample_ratio(&self) -> f64 {
+        self.sample_ratio.unwrap_or(1.0)
+    }
+
+    pub fn snaplen(&self) -> u32 {
+        self.snaplen.unwrap_or(1518)
+    }
+
+    pub fn max_packets(&self) -> usize {
+        self.max_packets.unwrap_or(1000000)
+    }
+
+    pub fn dir(&self) -> String {
+        self.dir.clone().unwrap_or(".".to_string())
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ArrivalProcess {
+    /// batches of 32 SYNs
+    Batched,
+    /// equidistant SYNs
+    Constant,
+    /// exponentially distributed gaps between SYNs
+    Poisson,
+    /// bursts of burst_size SYNs, followed by a pause
+    OnOff,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct ArrivalConfig {
+    pub process: Option<ArrivalProcess>,
+    /// OnOff: SYNs per burst, defaults to 32
+    pub burst_size: Option<usize>,
+    /// Poisson: seed of the random gaps, without a seed each run differs
+    pub seed: Option<u64>,
+}
+
+impl ArrivalConfig {
+    pub fn process(&self) -> ArrivalProcess {
+        self.process.unwrap_or(ArrivalProcess::Batched)
+    }
+
+    pub fn burst_size(&self) -> usize {
+        cmp::max(self.burst_size.unwrap_or(32), 1)
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct ReplayConfig {
+    /// pcap file with Ethernet frames, pcapng is not supported
+    pub file: String,
+    /// indices of the replayed TCP streams in the order of their first packet, defaults to all streams with client
+    /// payload
+    pub streams: Option<Vec<usize>>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct TcpOptionsConfig {
+    pub mss: Option<u16>,
+    /// shift count of our receive window
+    pub window_scale: Option<u8>,
+    pub sack_permitted: Option<bool>,
+}
+
+impl TcpOptionsConfig {
+    pub fn mss(&self) -> u16 {
+        self.mss.unwrap_or(1460)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum TargetSelection {
+    RoundRobin,
+    Random,
+    /// random, proportional to the weights of the targets
+    WeightedRandom,
+    /// the target is given by the client socket, weights determine the share of the hash ring of a target
+    ConsistentHash,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct TargetSelectionConfig {
+    pub mode: Option<TargetSelection>,
+    /// seed for the random modes, without a seed each run selects differently
+    pub seed: Option<u64>,
+}
+
+impl TargetSelectionConfig {
+    pub fn mode(&self) -> TargetSelection {
+        self.mode.unwrap_or(TargetSelection::RoundRobin)
+    }
 }
 
 #[derive(Deserialize, Clone)]
 pub struct TargetConfig {
     pub id: String,
-    pub ip: Ipv4Addr,
+    /// a single host, either ip or ipnet must be given
+    pub ip: Option<Ipv4Addr>,
+    /// all hosts of a net, e.g. "10.0.0.0/22"
+    pub ipnet: Option<String>,
+    /// MAC address of the next hop for all hosts of the target
     pub mac: Option<MacAddress>,
     pub linux_if: Option<String>,
+    /// the destination port, respectively the first port of a range up to last_port
     pub port: u16,
+    pub last_port: Option<u16>,
+    /// relative share of the connections of each socket of the target, used by the weighted selection modes,
+    /// defaults to 1
+    pub weight: Option<u32>,
+}
+
+impl TargetConfig {
+    pub fn weight(&self) -> u32 {
+        self.weight.unwrap_or(1)
+    }
+
+    /// the first host and the number of hosts
+    pub fn hosts(&self) -> (u32, u32) {
+        match (self.ip, &self.ipnet) {
+            (Some(ip), None) => (u32::from(ip), 1),
+            (None, Some(cidr)) => {
+                let net: Ipv4Net = cidr.parse().expect("ipnet of target must be a CIDR, e.g. 10.0.0.0/22");
+                let (network, broadcast) = (u32::from(net.network()), u32::from(net.broadcast()));
+                if net.prefix_len() >= 31 {
+                    (network, broadcast - network + 1)
+                } else {
+                    (network + 1, broadcast - network - 1)
+                }
+            }
+            _ => panic!("target {} needs either ip or ipnet", self.id),
+        }
+    }
 }
 
 pub fn setup_pipelines<FPL>(
@@ -90,11 +594,20 @@ pub fn setup_pipelines<FPL>(
     pmd_ports: HashMap<String, Arc<PmdPort>>,
     sched: &mut StandaloneScheduler,
     run_configuration: RunConfiguration<Configuration, TEngineStore>,
-    servers: Vec<L234Data>,
+    servers: Targets,
     f_set_payload: Box<FPL>,
 ) where
     FPL: FnPayload,
 {
+    let load_series = run_configuration.engine_configuration.load_series.as_ref().map(|file| {
+        // the load is split evenly across all pipelines, i.e. the rx queues of the physical ports
+        let pipelines = pmd_ports
+            .values()
+            .filter(|p| p.is_physical() && p.kni_name().is_some())
+            .map(|p| p.rx_cores.as_ref().map_or(0, |cores| cores.len()))
+            .sum();
+        LoadSeries::from_csv(file, pipelines).unwrap_or_else(|e| panic!("cannot read load series {}: {}", file, e))
+    });
     for pmd_port in physical_ports_for_core(core, &pmd_ports) {
         debug!("setup_pipelines for {} on core {}:", pmd_port.name(), core);
         let mut kni_port = None;
@@ -153,6 +666,7 @@ pub fn setup_pipelines<FPL>(
                 run_configuration.clone(),
                 servers.clone(),
                 f_set_payload.clone(),
+                load_series.clone(),
             );
         }
     }
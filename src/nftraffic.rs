@@ -4,18 +4,35 @@ use e2d2::allocators::CacheAligned;
 use e2d2::interface::*;
 use e2d2::queues::{new_mpsc_queue_pair, new_mpsc_queue_pair_with_size};
 
-use std::sync::mpsc::channel;
-use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::net::{Ipv4Addr, SocketAddrV4};
 use std::arch::x86_64::_rdtsc;
+use std::collections::VecDeque;
+use std::cmp;
 
 use uuid::Uuid;
+use eui48::MacAddress;
 use bincode::{deserialize};
 use separator::Separatable;
 
 use netfcts::tcp_common::{TcpState, TcpStatistics, TcpCounter, TcpRole, CData, L234Data, ReleaseCause, tcp_payload_size};
-use cmanager::{Connection, ConnectionManagerC, ConnectionManagerS};
-use {Configuration};
+use cmanager::{
+    Connection, ConnectionManagerC, ConnectionManagerS, RtoParameters, StateTimeouts, Segment, PendingRst, ServerKey,
+    RecordBuffers, seq_lt,
+};
+use statistics::{EngineCounter, EngineStatistics, print_engine_counters};
+use statistics::{CounterSnapshot, PhaseRecorder, RateRecorder, print_phase_counters, print_rate_samples};
+use statistics::print_steady_state;
+use congestion::{self, new_congestion_control};
+use syn_cookies::SynCookies;
+use pcap::PacketCapture;
+use targets::{Targets, TargetSelector};
+use arrival::ArrivalModel;
+use profile::{LoadProfile, LoadSeries, LoadTarget, RunWindow};
+use tcp_options::{TcpOptions, NegotiatedOptions, tcp_option_bytes, append_tcp_options, write_tcp_options};
+use tcp_options::{split_payload, trim_payload, set_payload, RCV_WINDOW};
+use {Configuration, EngineConfig, RetransmissionConfig, ServiceResponse};
 #[cfg(feature = "profiling")]
 use netfcts::utils::TimeAdder;
 use {PipelineId, MessageFrom, MessageTo, TaskType};
@@ -39,6 +56,94 @@ const TIMER_WHEEL_RESOLUTION_MS: u64 = 10;
 const TIMER_WHEEL_SLOTS: usize = 1002;
 const TIMER_WHEEL_SLOT_CAPACITY: usize = 2500;
 const SEQN_SHIFT: usize = 4;
+/// segment size used by the congestion control, if the MSS of the DUT is unknown
+const MSS: u32 = 1460;
+/// services are tracked in SERVICES_OWNED
+const MAX_SERVICES: usize = 64;
+
+/// bit i is set, when a pipeline listens on the i-th service of the engine configuration
+static SERVICES_OWNED: AtomicU64 = AtomicU64::new(0);
+
+/// number of pipelines, whose run window has not finished yet
+static OPEN_RUN_WINDOWS: AtomicUsize = AtomicUsize::new(0);
+
+/// true, when the run windows of all pipelines have finished. The windows start with the first SYN of their pipeline,
+/// so a run with a duration ends, when the last pipeline has passed its cool-down. Must be called after the pipelines
+/// are set up.
+pub fn run_windows_finished() -> bool {
+    OPEN_RUN_WINDOWS.load(Ordering::SeqCst) == 0
+}
+
+/// logs an error for each configured service, on which no pipeline listens, because its port is not within the port
+/// range of a pipeline. Must be called after the pipelines are set up, returns false, if a service is not served.
+pub fn check_services(engine_config: &EngineConfig) -> bool {
+    let owned = SERVICES_OWNED.load(Ordering::SeqCst);
+    let mut all_owned = true;
+    for (i, service) in engine_config.services.iter().flatten().enumerate() {
+        if owned & (1 << i) == 0 {
+            error!(
+                "no pipeline listens on port {} of service {:?}, with flow steering based on TCP ports a service port \
+                 must be within the port range of a pipeline",
+                service.port, service.id
+            );
+            all_owned = false;
+        }
+    }
+    all_owned
+}
+
+/// what the client sends in reply to a payload of the DUT
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reply {
+    Payload,
+    Fin,
+    /// the next payload does not fit into the send window, we only acknowledge
+    Blocked,
+    /// in bulk transfer mode payload of the DUT is only acknowledged
+    Ack,
+}
+
+/// a listen port of the server side and its behavior
+#[derive(Debug, Clone, Copy)]
+struct Service {
+    port: u16,
+    fin_by_server: usize,
+    response: ServiceResponse,
+}
+
+/// sends record buffers of the client and the server side to the master, pairwise in one message per pair
+fn send_records(
+    tx: &Sender<MessageFrom<TEngineStore>>,
+    pipeline_id: &PipelineId,
+    c_records: Vec<TEngineStore>,
+    s_records: Vec<TEngineStore>,
+) {
+    let mut c_records = c_records.into_iter();
+    let mut s_records = s_records.into_iter();
+    loop {
+        match (c_records.next(), s_records.next()) {
+            (None, None) => break,
+            (c, s) => tx.send(MessageFrom::CRecords(pipeline_id.clone(), c, s)).unwrap(),
+        }
+    }
+}
+
+/// copies the counters of the record buffers into the engine counters
+fn update_record_counters(
+    cm_c: &ConnectionManagerC,
+    cm_s: &ConnectionManagerS,
+    ext_counter_c: &mut EngineCounter,
+    ext_counter_s: &mut EngineCounter,
+) {
+    if let Some(records) = cm_c.records() {
+        ext_counter_c[EngineStatistics::RecordsDropped] = records.dropped();
+        ext_counter_c[EngineStatistics::RecordsBlocked] = records.blocked();
+    }
+    if let Some(records) = cm_s.records() {
+        ext_counter_s[EngineStatistics::RecordsDropped] = records.dropped();
+        ext_counter_s[EngineStatistics::RecordsBlocked] = records.blocked();
+    }
+}
 
 pub fn setup_generator<FPL>(
     core: i32,
@@ -46,8 +151,9 @@ pub fn setup_generator<FPL>(
     kni: CacheAligned<PortQueue>,
     sched: &mut StandaloneScheduler,
     run_configuration: RunConfiguration<Configuration, TEngineStore>,
-    servers: Vec<L234Data>,
+    servers: Targets,
     f_set_payload: Box<FPL>,
+    load_series: Option<LoadSeries>,
 ) where
     FPL: FnPayload,
 {
@@ -72,18 +178,92 @@ pub fn setup_generator<FPL>(
     let tx = run_configuration.remote_sender.clone();
 
     let detailed_records = engine_config.detailed_records.unwrap_or(false);
+    // without streaming, the records stay in a single buffer until the end of the test
+    let record_streaming = if detailed_records {
+        engine_config.record_streaming.clone()
+    } else {
+        None
+    };
+    let record_buffers = || {
+        if detailed_records {
+            Some(match record_streaming {
+                Some(ref s) => RecordBuffers::new(s.buffer_size(), s.buffers(), s.overflow(), s.sample_rate()),
+                None => RecordBuffers::unbuffered(),
+            })
+        } else {
+            None
+        }
+    };
     let mut cm_c = ConnectionManagerC::new(
         pipeline_id.clone(),
         pci.port_queue.clone(),
         l4flow_for_this_core,
-        detailed_records,
+        engine_config.ip_pool(),
+        &engine_config.port_allocation.clone().unwrap_or_default(),
+        system_data.cpu_clock,
+        record_buffers(),
     );
-    let mut cm_s = ConnectionManagerS::new(detailed_records);
+    let server_table = engine_config.server_table.clone().unwrap_or_default();
+    let mut cm_s = ConnectionManagerS::new(record_buffers(), server_table.capacity(), server_table.max_capacity());
 
-    let mut timeouts = Timeouts::default_or_some(&engine_config.timeouts);
-    let max_open = engine_config.max_open.unwrap_or(cm_c.available_ports_count());
+    let timeouts = Timeouts::default_or_some(&engine_config.timeouts);
     let _fin_by_client = engine_config.fin_by_client.unwrap_or(1000);
     let fin_by_server = engine_config.fin_by_server.unwrap_or(1);
+    // with port based flow steering a service is handled by the pipeline which owns its port
+    let services: Vec<Service> = match engine_config.services {
+        Some(ref configs) => {
+            assert!(configs.len() <= MAX_SERVICES, "at most {} services are supported", MAX_SERVICES);
+            configs
+                .iter()
+                .enumerate()
+                .filter(|&(_, config)| cm_c.owns_tcp_port(config.port))
+                .map(|(i, config)| {
+                    SERVICES_OWNED.fetch_or(1 << i, Ordering::SeqCst);
+                    Service {
+                        port: config.port,
+                        fin_by_server: config.fin_by_server.unwrap_or(fin_by_server),
+                        response: config.response(),
+                    }
+                })
+                .collect()
+        }
+        None => vec![Service {
+            port: cm_c.listen_port(),
+            fin_by_server,
+            response: ServiceResponse::Echo,
+        }],
+    };
+    for service in &services {
+        cm_c.reserve_port(service.port);
+        info!("{} listening on port {}: {:?}", pipeline_id, service.port, service);
+    }
+    let mut max_open = engine_config.max_open.unwrap_or(cm_c.available_socks_count());
+    let mut target_selector = TargetSelector::new(&servers, &engine_config.target_selection.clone().unwrap_or_default());
+    // with a load profile, rate and concurrency follow its phases
+    let mut load_profile = match load_series {
+        Some(ref series) => Some(LoadProfile::from_series(series, max_open, system_data.cpu_clock)),
+        None => run_configuration
+            .engine_configuration
+            .phase
+            .as_ref()
+            .map(|phases| LoadProfile::new(phases, engine_config.cps_limit(), max_open, system_data.cpu_clock)),
+    };
+    let mut load_target: Option<LoadTarget> = None;
+    let mut phase_recorder = PhaseRecorder::new();
+    // a load series is reported per second
+    let mut rate_recorder = load_series.as_ref().map(|_| RateRecorder::new(system_data.cpu_clock));
+    // a run with a duration is measured in its steady state
+    let mut run_window = run_configuration.engine_configuration.duration.map(|duration| {
+        let configuration = &run_configuration.engine_configuration;
+        OPEN_RUN_WINDOWS.fetch_add(1, Ordering::SeqCst);
+        RunWindow::new(configuration.warm_up(), duration, configuration.cool_down(), system_data.cpu_clock)
+    });
+    let mut window_recorder = PhaseRecorder::new();
+    let mut arrival = ArrivalModel::new(
+        &engine_config.arrival.clone().unwrap_or_default(),
+        load_profile.as_ref().map_or(engine_config.cps_limit(), |profile| profile.max_cps()),
+        system_data.cpu_clock,
+    );
 
     let mut wheel_c = TimerWheel::new(
         TIMER_WHEEL_SLOTS,
@@ -95,6 +275,39 @@ pub fn setup_generator<FPL>(
         system_data.cpu_clock * TIMER_WHEEL_RESOLUTION_MS / 1000,
         TIMER_WHEEL_SLOT_CAPACITY,
     );
+
+    let congestion_control = engine_config.congestion_control.map(|cc_type| new_congestion_control(cc_type));
+    let initial_cwnd = engine_config.initial_cwnd();
+    let syn_options = engine_config.tcp_options.as_ref().map(|config| TcpOptions::from_config(config));
+    // the server side answers SYNs statelessly
+    let syn_cookies = if engine_config.syn_cookies() {
+        Some(SynCookies::new(system_data.cpu_clock))
+    } else {
+        None
+    };
+    let cookie_mss = syn_options.as_ref().and_then(|options| options.mss).unwrap_or(MSS as u16);
+    let bulk_transfer = engine_config.bulk_transfer();
+    // the congestion control relies on the retransmission queue
+    let mut rto_params = match engine_config.retransmission {
+        Some(ref config) => Some(RtoParameters::from_config(config)),
+        None if congestion_control.is_some() => Some(RtoParameters::from_config(&RetransmissionConfig::default())),
+        None => None,
+    };
+    if let Some(ref cc) = congestion_control {
+        info!("{} congestion control= {}, bulk transfer= {}", pipeline_id, cc.name(), bulk_transfer);
+    }
+    // without retransmissions these wheels stay empty, but the connection managers expect them
+    let rto_slot_capacity = if rto_params.is_some() { TIMER_WHEEL_SLOT_CAPACITY } else { 1 };
+    let mut wheel_rto_c = TimerWheel::new(
+        TIMER_WHEEL_SLOTS,
+        system_data.cpu_clock * TIMER_WHEEL_RESOLUTION_MS / 1000,
+        rto_slot_capacity,
+    );
+    let mut wheel_rto_s = TimerWheel::new(
+        TIMER_WHEEL_SLOTS,
+        system_data.cpu_clock * TIMER_WHEEL_RESOLUTION_MS / 1000,
+        rto_slot_capacity,
+    );
     info!(
         "{} wheel cycle= {} millis, cpu-clock= {}",
         pipeline_id,
@@ -102,17 +315,47 @@ pub fn setup_generator<FPL>(
         system_data.cpu_clock,
     );
 
-    // check that we do not overflow the wheel:
-    if timeouts.established.is_some() {
-        let timeout = timeouts.established.unwrap();
-        if timeout > wheel_c.get_max_timeout_cycles() - wheel_c.resolution() / 2 {
+    // converts timeouts to cycles and checks that we do not overflow the wheel:
+    let max_timeout = wheel_c.get_max_timeout_cycles() * 1000 / system_data.cpu_clock - TIMER_WHEEL_RESOLUTION_MS / 2;
+    let timeout_cycles = |name: &str, millis: u64| {
+        if millis > max_timeout {
             warn!(
-                "timeout defined in configuration file overflows timer wheel: reset to {} millis",
-                wheel_c.get_max_timeout_cycles() * 1000 / system_data.cpu_clock - TIMER_WHEEL_RESOLUTION_MS / 2
+                "timeout {} defined in configuration file overflows timer wheel: reset to {} millis",
+                name, max_timeout
             );
-            timeouts.established = Some(wheel_c.get_max_timeout_cycles() - wheel_c.resolution() / 2);
+            max_timeout * system_data.cpu_clock / 1000
+        } else {
+            millis * system_data.cpu_clock / 1000
+        }
+    };
+    let established = timeouts.established.unwrap();
+    let state_timeouts = {
+        let config = engine_config.state_timeouts.clone().unwrap_or_default();
+        StateTimeouts {
+            handshake: timeout_cycles("handshake", config.handshake.unwrap_or(established)),
+            idle: timeout_cycles("idle", config.idle.unwrap_or(established)),
+            fin_wait: timeout_cycles("fin_wait", config.fin_wait.unwrap_or(established)),
+            last_ack: timeout_cycles("last_ack", config.last_ack.unwrap_or(established)),
+        }
+    };
+    // 2MSL in cycles, client ports wait this long in TIME_WAIT before they are reused, None skips TIME_WAIT
+    let time_wait = engine_config.time_wait.map(|millis| timeout_cycles("time_wait", millis));
+    // the final state of a client connection after an active close
+    let close_state_c = if time_wait.is_some() { TcpState::TimeWait } else { TcpState::Closed };
+    if let Some(ref mut params) = rto_params {
+        let max_rto = (wheel_rto_c.get_max_timeout_cycles() * 1000 / system_data.cpu_clock) as u32
+            - TIMER_WHEEL_RESOLUTION_MS as u32 / 2;
+        if params.max > max_rto {
+            warn!(
+                "max_rto defined in configuration file overflows timer wheel: reset to {} millis",
+                max_rto
+            );
+            params.max = max_rto;
+            params.initial = cmp::min(params.initial, max_rto);
         }
     }
+    // zero window probes are paced by the retransmission timer, also if retransmissions are switched off
+    let persist_params = rto_params.unwrap_or(RtoParameters::from_config(&RetransmissionConfig::default()));
 
 
     // setting up a a reverse message channel between this pipeline and the main program thread
@@ -132,6 +375,15 @@ pub fn setup_generator<FPL>(
     let mut start_stamp: u64 = 0;
     let mut stop_stamp: u64 = 0;
     let mut counter_s = TcpCounter::new();
+    let mut ext_counter_c = EngineCounter::new();
+    let mut ext_counter_s = EngineCounter::new();
+    // connections with expired retransmission timer, waiting for the RetransmitInjector
+    let mut rexmit_c: VecDeque<u32> = VecDeque::with_capacity(64);
+    let mut rexmit_s: VecDeque<ServerKey> = VecDeque::with_capacity(64);
+    // resets for timed out connections, also sent by the RetransmitInjector
+    let rst_on_timeout = engine_config.rst_on_timeout();
+    let mut rst_c: VecDeque<PendingRst> = VecDeque::with_capacity(64);
+    let mut rst_s: VecDeque<PendingRst> = VecDeque::with_capacity(64);
 
     struct HoldingTime {
         // in cycles
@@ -198,7 +450,7 @@ pub fn setup_generator<FPL>(
             syn_producer,
             &me,
             0,
-            system_data.cpu_clock / engine_config.cps_limit() * 32,
+            arrival.injector_interval(),
             1u16,
         )
         .set_start_delay(system_data.cpu_clock / 100),
@@ -225,12 +477,33 @@ pub fn setup_generator<FPL>(
         .unwrap();
     let payload_injector_ready_flag = sched.get_ready_flag(&injector_uuid).unwrap();
 
+    let (rexmit_producer, rexmit_consumer) = new_mpsc_queue_pair_with_size(64);
+    let injector_uuid = install_task(
+        sched,
+        "RetransmitInjector",
+        PacketInjector::new(
+            rexmit_producer,
+            &me,
+            0,
+            system_data.cpu_clock / engine_config.cps_limit() * 32,
+            3u16,
+        )
+        .set_start_delay(system_data.cpu_clock / 100),
+    );
+    tx.send(MessageFrom::Task(pipeline_id.clone(), injector_uuid, TaskType::TcpGenerator))
+        .unwrap();
+    let rexmit_injector_ready_flag = sched.get_ready_flag(&injector_uuid).unwrap();
+
     // set up the generator producing timer tick packets with our private EtherType
     let (producer_timerticks, consumer_timerticks) = new_mpsc_queue_pair();
     let tick_generator = TickGenerator::new(producer_timerticks, &me, system_data.cpu_clock / 100); // 10 ms
     assert!(wheel_c.resolution() >= tick_generator.tick_length());
     let wheel_tick_reduction_factor = wheel_c.resolution() / tick_generator.tick_length();
     let mut ticks = 0;
+    // ticks between two shipments of connection records
+    let record_ship_ticks = record_streaming
+        .as_ref()
+        .map(|s| cmp::max(s.interval() * system_data.cpu_clock / 1000 / tick_generator.tick_length(), 1));
     let uuid_task = install_task(sched, "TickGenerator", tick_generator);
     tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_task, TaskType::TickGenerator))
         .unwrap();
@@ -240,6 +513,7 @@ pub fn setup_generator<FPL>(
         vec![
             box syn_consumer,
             box payload_consumer,
+            box rexmit_consumer,
             box consumer_timerticks.set_urgent(),
             box receive_pci,
         ],
@@ -259,6 +533,17 @@ pub fn setup_generator<FPL>(
     let uuid_l4groupby_clone = uuid_l4groupby.clone();
     let pipeline_id_clone = pipeline_id.clone();
 
+    let mut capture = match engine_config.capture {
+        Some(ref config) => match PacketCapture::new(config, &pipeline_id, system_data.cpu_clock) {
+            Ok(capture) => Some(capture),
+            Err(e) => {
+                error!("{}: cannot set up packet capture: {}", pipeline_id, e);
+                None
+            }
+        },
+        None => None,
+    };
+
     #[cfg(feature = "profiling")]
     let sample_size = nr_connections as u64 / 2;
     #[cfg(feature = "profiling")]
@@ -306,15 +591,38 @@ pub fn setup_generator<FPL>(
 
         let payload_injector_runs = || payload_injector_ready_flag.load(Ordering::SeqCst);
 
+        let rexmit_injector_start = || {
+            debug!("{} (re-)starting the retransmit injector at {}", thread_id, now());
+            rexmit_injector_ready_flag.store(true, Ordering::SeqCst);
+        };
+
+        let rexmit_injector_stop = || {
+            debug!("{}: stopping the retransmit injector at {}", thread_id, now());
+            rexmit_injector_ready_flag.store(false, Ordering::SeqCst);
+        };
+
+        let rexmit_injector_runs = || rexmit_injector_ready_flag.load(Ordering::SeqCst);
+
         #[inline]
-        fn syn_received(p: &mut Pdu, c: &mut Connection) {
+        fn syn_received(p: &mut Pdu, c: &mut Connection, options: Option<&TcpOptions>) {
             c.push_state(TcpState::SynReceived);
             let client_ip = p.headers().ip(1).src();
+            c.set_peer_addresses(p.headers().mac(0).src, p.headers().ip(1).dst());
             // debug!("checksum in = {:X}",p.get_header().checksum());
+            let answer = match options {
+                Some(ours) => {
+                    let received = TcpOptions::parse(tcp_option_bytes(p));
+                    let answer = ours.answer(&received);
+                    c.set_negotiated(NegotiatedOptions::negotiate(&answer, &received));
+                    Some(answer)
+                }
+                None => None,
+            };
             remove_tcp_options(p);
             make_reply_packet(p, 1);
             //generate seq number:
             c.seqn_nxt = (unsafe { _rdtsc() } << 8) as u32;
+            c.seqn_una = c.seqn_nxt;
             {
                 let tcp = p.headers_mut().tcp_mut(2);
                 tcp.set_seq_num(c.seqn_nxt);
@@ -322,29 +630,117 @@ pub fn setup_generator<FPL>(
                 c.set_sock((client_ip, tcp.dst_port()));
             }
             c.seqn_nxt = c.seqn_nxt.wrapping_add(1);
+            if let Some(ref answer) = answer {
+                write_tcp_options(p, answer);
+            }
             prepare_checksum_and_ttl(p);
             //trace!("(SYN-)ACK to client, L3: { }, L4: { }", h.ip, h.tcp);
         }
 
+        /// answers a SYN without allocating a connection, only the MSS option of the DUT is kept in the cookie
+        #[inline]
+        fn syn_cookie_reply(p: &mut Pdu, cookies: &SynCookies, mss: u16, now: u64) {
+            let received = TcpOptions::parse(tcp_option_bytes(p));
+            let cookie = {
+                let ip = p.headers().ip(1);
+                let tcp = p.headers().tcp(2);
+                cookies.generate(
+                    (ip.src(), tcp.src_port()),
+                    (ip.dst(), tcp.dst_port()),
+                    tcp.seq_num(),
+                    received.mss,
+                    now,
+                )
+            };
+            remove_tcp_options(p);
+            make_reply_packet(p, 1);
+            p.headers_mut().tcp_mut(2).set_seq_num(cookie);
+            write_tcp_options(
+                p,
+                &TcpOptions {
+                    mss: Some(mss),
+                    ..TcpOptions::default()
+                },
+            );
+            prepare_checksum_and_ttl(p);
+        }
+
+        /// sets up a server connection from the final ACK of a handshake, which carries a valid cookie
+        #[inline]
+        fn syn_cookie_accepted(p: &Pdu, c: &mut Connection, mss: u16) {
+            c.push_state(TcpState::SynReceived);
+            c.set_peer_addresses(p.headers().mac(0).src, p.headers().ip(1).dst());
+            let tcp = p.headers().tcp(2);
+            c.ackn_nxt = tcp.seq_num();
+            c.seqn_nxt = tcp.ack_num();
+            c.seqn_una = c.seqn_nxt;
+            let mut negotiated = NegotiatedOptions::new();
+            negotiated.snd_mss = mss;
+            c.set_negotiated(negotiated);
+        }
+
         #[inline]
         fn synack_received(p: &mut Pdu, c: &mut Connection) {
+            remove_tcp_options(p);
             make_reply_packet(p, 1);
             {
                 let tcp = p.headers_mut().tcp_mut(2);
                 c.ackn_nxt = tcp.ack_num();
+                c.ackn_initial = c.ackn_nxt;
                 tcp.unset_syn_flag();
                 tcp.set_seq_num(c.seqn_nxt);
             }
             prepare_checksum_and_ttl(p);
         }
 
-        /// sends payload of p to client, if b_fin is true, sets FIN flag
+        /// cuts the payload of p down to the MSS of the DUT, the rest is kept on the connection for further segments
         #[inline]
-        fn s_reply_with_payload(p: &mut Pdu, c: &mut Connection, b_fin: bool) {
+        fn limit_to_mss(p: &mut Pdu, c: &mut Connection) {
+            if let Some(mss) = c.snd_mss() {
+                if tcp_payload_size(p) > mss as usize {
+                    split_payload(p, mss as usize, c.unsent_mut());
+                }
+            }
+        }
+
+        /// cuts the payload of p down to the n bytes, which the window of the DUT admits. The bytes beyond are sent
+        /// first with the next segment, if p was filled from the unsent payload, this still holds them.
+        #[inline]
+        fn limit_to_window(p: &mut Pdu, c: &mut Connection, n: usize, b_unsent: bool) {
+            let payload_sz = tcp_payload_size(p);
+            if payload_sz > n {
+                if !b_unsent {
+                    let rest = p.get_payload(2)[n..payload_sz].to_vec();
+                    c.unsent_mut().splice(0..0, rest);
+                }
+                trim_payload(p, n);
+            }
+        }
+
+        /// sends payload of p to client, if b_fin is true, sets FIN flag. Payload beyond the MSS of the DUT waits on the
+        /// connection for the next ACKs of the DUT, the FIN is then sent with the last segment. Returns true, if the FIN
+        /// was set.
+        #[inline]
+        fn s_reply_with_payload(p: &mut Pdu, c: &mut Connection, b_fin: bool) -> bool {
             make_reply_packet(p, 0);
+            if c.has_unsent() {
+                // the payload of p queues up behind the waiting payload
+                let payload_sz = tcp_payload_size(p);
+                c.unsent_mut().extend_from_slice(&p.get_payload(2)[..payload_sz]);
+                let n = cmp::min(c.unsent().len(), c.snd_mss().unwrap() as usize);
+                // p may be a pure ACK of the DUT, whose buffer may lack the tail room for a full segment, then the
+                // payload waits for the next segment of the DUT
+                if set_payload(p, &c.unsent()[..n]).is_ok() {
+                    c.consume_unsent(n);
+                }
+            } else {
+                limit_to_mss(p, c);
+            }
+            let b_fin = b_fin && !c.has_unsent();
             {
                 let tcp = p.headers_mut().tcp_mut(2);
-                c.ackn_nxt = tcp.ack_num();
+                // c.ackn_nxt may include reassembled segments
+                tcp.set_ack_num(c.ackn_nxt);
                 tcp.set_seq_num(c.seqn_nxt);
                 tcp.unset_psh_flag();
                 if b_fin {
@@ -355,6 +751,7 @@ pub fn setup_generator<FPL>(
             c.seqn_nxt = c.seqn_nxt.wrapping_add(payload_sz as u32 + if b_fin { 1 } else { 0 });
             //if b_fin && h.tcp_payload_len()==0 { c.seqn_nxt = c.seqn_nxt.wrapping_add(1); }
             prepare_checksum_and_ttl(p);
+            b_fin
         }
 
         #[inline]
@@ -362,26 +759,31 @@ pub fn setup_generator<FPL>(
             p: &mut Pdu,
             c: &mut Connection,
             me: &L234Data,
-            servers: &Vec<L234Data>,
+            servers: &Targets,
             pipeline_id: &PipelineId,
             syn_counter: &mut usize,
+            options: Option<&TcpOptions>,
         ) {
             p.headers_mut().mac_mut(0).set_etype(0x0800); // overwrite private ethertype tag
-            c.set_server_index(*syn_counter as usize % servers.len());
-            set_header(&servers[c.server_index()], c.port(), p, &me.mac, me.ip);
+            set_header(&servers.get(c.server_index()), c.port(), p, &me.mac, c.client_ip());
 
             //generate seq number:
             c.seqn_nxt = (unsafe { _rdtsc() } << SEQN_SHIFT) as u32;
+            c.seqn_una = c.seqn_nxt;
             {
                 let htcp = p.headers_mut().tcp_mut(2);
                 htcp.set_seq_num(c.seqn_nxt);
                 c.seqn_nxt = c.seqn_nxt.wrapping_add(1);
+                c.seqn_initial = c.seqn_nxt;
                 htcp.set_syn_flag();
-                htcp.set_window_size(5840); // 4* MSS(1460)
+                htcp.set_window_size(RCV_WINDOW as u16);
                 htcp.set_ack_num(0u32);
                 htcp.unset_ack_flag();
                 htcp.unset_psh_flag();
             }
+            if let Some(options) = options {
+                write_tcp_options(p, options);
+            }
             prepare_checksum_and_ttl(p);
 
             *syn_counter += 1;
@@ -391,16 +793,16 @@ pub fn setup_generator<FPL>(
         }
 
         #[inline]
-        fn generate_fin(p: &mut Pdu, c: &mut Connection, me: &L234Data, servers: &Vec<L234Data>) {
+        fn generate_fin(p: &mut Pdu, c: &mut Connection, me: &L234Data, servers: &Targets) {
             p.headers_mut().mac_mut(0).set_etype(0x0800); // overwrite private ethertype tag
-            set_header(&servers[c.server_index()], c.port(), p, &me.mac, me.ip);
+            set_header(&servers.get(c.server_index()), c.port(), p, &me.mac, c.client_ip());
             {
                 let tcp = p.headers_mut().tcp_mut(2);
                 tcp.set_seq_num(c.seqn_nxt);
                 c.seqn_nxt = c.seqn_nxt.wrapping_add(1);
                 tcp.set_fin_flag();
                 tcp.unset_syn_flag();
-                tcp.set_window_size(5840); // 4* MSS(1460)
+                tcp.set_window_size(c.negotiated().rcv_window());
                 tcp.set_ack_num(c.ackn_nxt);
                 tcp.set_ack_flag();
                 tcp.unset_psh_flag();
@@ -409,13 +811,13 @@ pub fn setup_generator<FPL>(
         }
 
         #[inline]
-        fn prepare_payload_packet(c: &mut Connection, p: &mut Pdu, me: &L234Data, servers: &Vec<L234Data>) {
+        fn prepare_payload_packet(c: &mut Connection, p: &mut Pdu, me: &L234Data, servers: &Targets) {
             p.headers_mut().mac_mut(0).set_etype(0x0800); // overwrite private ethertype tag
-            set_header(&servers[c.server_index()], c.port(), p, &me.mac, me.ip);
+            set_header(&servers.get(c.server_index()), c.port(), p, &me.mac, c.client_ip());
             let tcp = p.headers_mut().tcp_mut(2);
             tcp.set_seq_num(c.seqn_nxt);
             tcp.unset_syn_flag();
-            tcp.set_window_size(5840); // 4* MSS(1460)
+            tcp.set_window_size(c.negotiated().rcv_window());
             tcp.set_ack_num(c.ackn_nxt);
             tcp.set_ack_flag();
             tcp.set_psh_flag();
@@ -448,7 +850,13 @@ pub fn setup_generator<FPL>(
         }
 
         #[inline]
-        fn active_close(p: &mut Pdu, c: &mut Connection, counter: &mut TcpCounter, state: &TcpState) -> bool {
+        fn active_close(
+            p: &mut Pdu,
+            c: &mut Connection,
+            counter: &mut TcpCounter,
+            state: &TcpState,
+            final_state: TcpState,
+        ) -> bool {
             let mut tcp_closed = false;
             {
                 let tcp = p.headers_mut().tcp_mut(2);
@@ -462,7 +870,7 @@ pub fn setup_generator<FPL>(
                     );
                     */
                     counter[TcpStatistics::RecvFinPssv] += 1;
-                    c.push_state(TcpState::Closed);
+                    c.push_state(final_state);
                     tcp_closed = true;
                 } else {
                     // no ACK
@@ -477,7 +885,7 @@ pub fn setup_generator<FPL>(
                     if *state == TcpState::FinWait1 {
                         c.push_state(TcpState::Closing);
                     } else if *state == TcpState::FinWait2 {
-                        c.push_state(TcpState::Closed);
+                        c.push_state(final_state);
                         tcp_closed = true
                     }
                 }
@@ -496,6 +904,247 @@ pub fn setup_generator<FPL>(
             tcp_closed
         }
 
+        /// replies with a pure ACK carrying our current ackn, e.g. a duplicate ACK for a gap in the received data
+        #[inline]
+        fn send_ack(p: &mut Pdu, c: &Connection) {
+            make_reply_packet(p, 0);
+            {
+                let tcp = p.headers_mut().tcp_mut(2);
+                tcp.unset_fin_flag();
+                tcp.unset_psh_flag();
+                tcp.set_ack_flag();
+                tcp.set_seq_num(c.seqn_nxt);
+                tcp.set_ack_num(c.ackn_nxt);
+            }
+            strip_payload(p);
+            prepare_checksum_and_ttl(p);
+        }
+
+        /// true for states in which the peer may still send data
+        #[inline]
+        fn accepts_data(state: TcpState) -> bool {
+            match state {
+                TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 => true,
+                _ => false,
+            }
+        }
+
+        /// holds back the payload and FIN of a segment ahead of ackn_nxt and answers with a duplicate ACK for the gap
+        #[inline]
+        fn out_of_order_received(p: &mut Pdu, c: &mut Connection, counter: &mut EngineCounter) {
+            let payload_sz = tcp_payload_size(p);
+            let b_fin = p.headers().tcp(2).fin_flag();
+            if payload_sz > 0 || b_fin {
+                if c.buffer_out_of_order(p.headers().tcp(2).seq_num(), payload_sz as u32, b_fin) {
+                    counter[EngineStatistics::OutOfOrder] += 1;
+                } else {
+                    counter[EngineStatistics::ReassemblyOverflow] += 1;
+                }
+            }
+            send_ack(p, c);
+        }
+
+        /// delivers buffered segments, which became contiguous with the payload of p. A FIN of the last of these segments
+        /// is moved onto p, as if p carried it, so that the state machine processes it.
+        #[inline]
+        fn reassemble(p: &mut Pdu, c: &mut Connection, counter: &mut TcpCounter) {
+            let (segments, b_fin) = c.reassemble();
+            for _ in 0..segments {
+                counter[TcpStatistics::RecvPayload] += 1;
+                c.inc_recv_payload_pkts();
+            }
+            if b_fin {
+                let payload_sz = tcp_payload_size(p);
+                let tcp = p.headers_mut().tcp_mut(2);
+                tcp.set_seq_num(c.ackn_nxt.wrapping_sub(payload_sz as u32));
+                tcp.set_fin_flag();
+            }
+        }
+
+        /// rebuilds an unacknowledged segment from scratch, ackn is None for a SYN of the client. The window applies to
+        /// segments other than SYN and SYN-ACK. Returns false, if the payload does not fit into the tail room of p.
+        #[inline]
+        fn retransmit(
+            p: &mut Pdu,
+            segment: &Segment,
+            ackn: Option<u32>,
+            window: u16,
+            peer: &L234Data,
+            port: u16,
+            me_mac: &MacAddress,
+            ip: u32,
+        ) -> bool {
+            p.headers_mut().mac_mut(0).set_etype(0x0800); // overwrite private ethertype tag
+            set_header(peer, port, p, me_mac, ip);
+            {
+                let tcp = p.headers_mut().tcp_mut(2);
+                tcp.set_seq_num(segment.seqn);
+                if segment.syn {
+                    tcp.set_syn_flag();
+                } else {
+                    tcp.unset_syn_flag();
+                }
+                if segment.fin {
+                    tcp.set_fin_flag();
+                } else {
+                    tcp.unset_fin_flag();
+                }
+                if let Some(ackn) = ackn {
+                    tcp.set_ack_num(ackn);
+                    tcp.set_ack_flag();
+                } else {
+                    tcp.set_ack_num(0u32);
+                    tcp.unset_ack_flag();
+                }
+                if segment.payload.len() > 0 {
+                    tcp.set_psh_flag();
+                } else {
+                    tcp.unset_psh_flag();
+                }
+                tcp.set_window_size(if segment.syn { RCV_WINDOW as u16 } else { window });
+            }
+            if segment.options.len() > 0 {
+                append_tcp_options(p, &segment.options);
+            }
+            if segment.payload.len() > 0 && set_payload(p, &segment.payload).is_err() {
+                return false;
+            }
+            if p.data_len() < MIN_FRAME_SIZE {
+                let n_padding_bytes = MIN_FRAME_SIZE - p.data_len();
+                p.increase_payload_size(n_padding_bytes);
+            }
+            prepare_checksum_and_ttl(p);
+            true
+        }
+
+        /// notes down the segment in p for retransmission and (re-)starts the retransmission timer of a client connection
+        #[inline]
+        fn track_segment_c(
+            p: &mut Pdu,
+            c: &mut Connection,
+            wheel_rto: &mut TimerWheel<u32>,
+            cpu_clock: u64,
+            params: &RtoParameters,
+        ) {
+            let payload_sz = tcp_payload_size(p);
+            let tcp = p.headers().tcp(2);
+            c.push_unacked(
+                tcp.seq_num(),
+                tcp.syn_flag(),
+                tcp.fin_flag(),
+                &p.get_payload(2)[..payload_sz],
+                if tcp.syn_flag() { tcp_option_bytes(p) } else { &[] },
+                unsafe { _rdtsc() },
+            );
+            // the timer runs for the oldest segment in flight (RFC 6298, 5.1)
+            if !c.rto_armed() {
+                c.arm_rto_c(wheel_rto, cpu_clock, params);
+            }
+        }
+
+        /// same as track_segment_c for server connections, which in addition note down the addresses of the reply
+        #[inline]
+        fn track_segment_s(
+            p: &mut Pdu,
+            c: &mut Connection,
+            wheel_rto: &mut TimerWheel<ServerKey>,
+            cpu_clock: u64,
+            params: &RtoParameters,
+        ) {
+            let payload_sz = tcp_payload_size(p);
+            let tcp = p.headers().tcp(2);
+            {
+                let segment = c.push_unacked(
+                    tcp.seq_num(),
+                    tcp.syn_flag(),
+                    tcp.fin_flag(),
+                    &p.get_payload(2)[..payload_sz],
+                    if tcp.syn_flag() { tcp_option_bytes(p) } else { &[] },
+                    unsafe { _rdtsc() },
+                );
+                segment.peer_mac = p.headers().mac(0).dst;
+                segment.local_ip = p.headers().ip(1).src();
+            }
+            if !c.rto_armed() {
+                c.arm_rto_s(wheel_rto, cpu_clock, params);
+            }
+        }
+
+        /// pauses sending of payload, if nothing is in flight the persist timer is started to probe the window of the DUT
+        #[inline]
+        fn block_c(c: &mut Connection, wheel_rto: &mut TimerWheel<u32>, cpu_clock: u64, params: &RtoParameters) {
+            c.set_blocked(true);
+            if c.all_acked() && !c.in_persist() {
+                c.set_persist(true);
+                c.arm_rto_c(wheel_rto, cpu_clock, params);
+            }
+        }
+
+        /// an ACK with an already acknowledged seqn, the DUT answers with its current window
+        #[inline]
+        fn zero_window_probe(p: &mut Pdu, c: &Connection, me: &L234Data, servers: &Targets) {
+            p.headers_mut().mac_mut(0).set_etype(0x0800); // overwrite private ethertype tag
+            set_header(&servers.get(c.server_index()), c.port(), p, &me.mac, c.client_ip());
+            {
+                let tcp = p.headers_mut().tcp_mut(2);
+                tcp.set_seq_num(c.seqn_una.wrapping_sub(1));
+                tcp.unset_syn_flag();
+                tcp.unset_fin_flag();
+                tcp.unset_psh_flag();
+                tcp.set_window_size(c.negotiated().rcv_window());
+                tcp.set_ack_num(c.ackn_nxt);
+                tcp.set_ack_flag();
+            }
+            prepare_checksum_and_ttl(p)
+        }
+
+        /// the DUT as peer of a server connection
+        #[inline]
+        fn server_side_peer(sock: &(u32, u16), mac: MacAddress) -> L234Data {
+            L234Data {
+                mac,
+                ip: sock.0,
+                port: sock.1,
+                server_id: String::new(),
+                index: 0,
+            }
+        }
+
+        /// a RST for a connection, whose state is already gone
+        #[inline]
+        fn generate_rst(p: &mut Pdu, rst: &PendingRst, peer: &L234Data, port: u16, me_mac: &MacAddress, ip: u32) {
+            p.headers_mut().mac_mut(0).set_etype(0x0800); // overwrite private ethertype tag
+            set_header(peer, port, p, me_mac, ip);
+            {
+                let tcp = p.headers_mut().tcp_mut(2);
+                tcp.set_seq_num(rst.seqn);
+                tcp.unset_syn_flag();
+                tcp.unset_fin_flag();
+                tcp.unset_psh_flag();
+                tcp.set_rst_flag();
+                tcp.set_window_size(0);
+                if let Some(ackn) = rst.ackn {
+                    tcp.set_ack_num(ackn);
+                    tcp.set_ack_flag();
+                } else {
+                    tcp.set_ack_num(0u32);
+                    tcp.unset_ack_flag();
+                }
+            }
+            prepare_checksum_and_ttl(p);
+        }
+
+        #[inline]
+        fn count_retransmission(segment: &Segment, counter: &mut EngineCounter) {
+            if segment.syn {
+                counter[EngineStatistics::RetransmittedSyn] += 1;
+            } else if segment.payload.len() > 0 {
+                counter[EngineStatistics::RetransmittedPayload] += 1;
+            } else if segment.fin {
+                counter[EngineStatistics::RetransmittedFin] += 1;
+            }
+        }
+
         #[inline]
         ///increments ack4fin counter, checks for stop_stamp and updates holding time counter
         fn recv_ack4fin(
@@ -512,31 +1161,71 @@ pub fn setup_generator<FPL>(
             hold.add_hold(c.seqn_nxt >> SEQN_SHIFT);
         }
 
+        /// acknowledges the payload of p without sending payload
+        #[inline]
+        fn ack_payload(p: &mut Pdu, c: &mut Connection) {
+            strip_payload(p);
+            make_reply_packet(p, 0);
+            {
+                let tcp = p.headers_mut().tcp_mut(2);
+                tcp.set_seq_num(c.seqn_nxt);
+                tcp.set_ack_num(c.ackn_nxt);
+                tcp.unset_psh_flag();
+            }
+            prepare_checksum_and_ttl(p);
+        }
+
         // *****  the closure starts here with processing
 
         #[cfg(feature = "profiling")]
         let timestamp_entry = unsafe { _rdtsc() };
 
         let c_recv_payload = |p: &mut Pdu, c: &mut Connection| {
+            if bulk_transfer {
+                // our payload is sent by the injector
+                ack_payload(p, c);
+                return Reply::Ack;
+            }
+            if c.has_unsent() {
+                // the injector sends the rest of our last payload first
+                ack_payload(p, c);
+                return Reply::Ack;
+            }
             let mut b_fin = false;
             f_set_payload(p, c, None, &mut b_fin);
+            limit_to_mss(p, c);
             if !b_fin {
-                c.inc_sent_payload_pkts();
+                if tcp_payload_size(p) == 0 {
+                    // the payload function has nothing to send yet
+                    ack_payload(p, c);
+                    return Reply::Ack;
+                }
+                let sendable = c.sendable(tcp_payload_size(p) as u32) as usize;
+                let reply = if sendable == 0 {
+                    // the payload function is asked again, when the window opens
+                    strip_payload(p);
+                    c.clear_unsent();
+                    Reply::Blocked
+                } else {
+                    limit_to_window(p, c, sendable, false);
+                    c.inc_sent_payload_pkts();
+                    Reply::Payload
+                };
                 p.headers_mut().tcp_mut(2).set_seq_num(c.seqn_nxt);
                 let payload_sz = tcp_payload_size(p);
                 c.seqn_nxt = c.seqn_nxt.wrapping_add(payload_sz as u32);
                 make_reply_packet(p, 0);
                 p.headers_mut().tcp_mut(2).set_ack_num(c.ackn_nxt);
                 prepare_checksum_and_ttl(p);
+                reply
             } else {
                 generate_fin(p, c, &me, &servers);
                 c.set_release_cause(ReleaseCause::ActiveClose);
                 c.push_state(TcpState::FinWait1);
+                Reply::Fin
             }
-            b_fin
         };
 
-        let pipeline_ip = cm_c.ip();
         let tcp_port_base = cm_c.tcp_port_base();
         let b_private_etype;
         {
@@ -558,7 +1247,7 @@ pub fn setup_generator<FPL>(
             let ip_header = pdu.headers().ip(1);
             if !b_private_etype {
                 // everything other than TCP, and everything not addressed to us we send to KNI, i.e. group 2
-                if ip_header.protocol() != 6 || ip_header.dst() != pipeline_ip && ip_header.dst() != me.ip {
+                if ip_header.protocol() != 6 || !cm_c.owns_ip(ip_header.dst()) && ip_header.dst() != me.ip {
                     return 2;
                 }
             }
@@ -571,22 +1260,31 @@ pub fn setup_generator<FPL>(
 
         let src_sock = (pdu.headers().ip(1).src(), pdu.headers().tcp(2).src_port());
         let dst_sock = (pdu.headers().ip(1).dst(), pdu.headers().tcp(2).dst_port());
+        // server connections are identified by the socket of the DUT and the listen port
+        let server_key = (src_sock.0, src_sock.1, dst_sock.1);
+        let service = services.iter().find(|service| service.port == dst_sock.1).cloned();
 
         //check ports
-        if !b_private_etype && pdu.headers().tcp(2).dst_port() != me.port && pdu.headers().tcp(2).dst_port() < tcp_port_base
-        {
+        if !b_private_etype && dst_sock.1 != me.port && dst_sock.1 < tcp_port_base && service.is_none() {
             return 2;
         }
+        // packets from the DUT are captured on arrival, our packets when they leave towards the DUT
+        if !b_private_etype && capture.is_some() {
+            capture.as_mut().unwrap().capture(pdu);
+        }
 
         // if set by the following tcp state machine,
         // the port/connection becomes released/ready afterwards
         // this is cumbersome, but we must make the  borrow checker happy
         let mut b_release_connection_c = false;
+        let mut b_time_wait_c = false;
         let mut b_release_connection_s = false;
+        // a state transition replaces the timeout of the connection
+        let mut reschedule_c: Option<(u32, u64)> = None;
+        let mut reschedule_s: Option<u64> = None;
         let mut ready_connection = None;
-        let server_listen_port = cm_c.listen_port();
 
-        let nr_connections = run_configuration.engine_configuration.test_size.unwrap_or(128);
+        let nr_connections = run_configuration.engine_configuration.test_size();
         // check if we got a packet from generator
         match (pdu.headers().mac(0).etype(), pdu.headers().tcp(2).dst_port()) {
             // SYN injection
@@ -594,11 +1292,50 @@ pub fn setup_generator<FPL>(
                 if counter_c[TcpStatistics::SentSyn] == 0 {
                     start_stamp = unsafe { _rdtsc() };
                 }
-                if counter_c[TcpStatistics::SentSyn] < nr_connections {
+                if let Some(ref mut window) = run_window {
+                    if !window.started() {
+                        let now = unsafe { _rdtsc() };
+                        window.start(now);
+                        window_recorder.start(CounterSnapshot::new(
+                            now,
+                            &counter_c,
+                            &counter_s,
+                            &ext_counter_c,
+                            &ext_counter_s,
+                        ));
+                    }
+                }
+                if let Some(ref mut profile) = load_profile {
+                    if !profile.started() {
+                        // the first phase starts with the injector
+                        let now = unsafe { _rdtsc() };
+                        profile.start(now);
+                        phase_recorder.start(CounterSnapshot::new(
+                            now,
+                            &counter_c,
+                            &counter_s,
+                            &ext_counter_c,
+                            &ext_counter_s,
+                        ));
+                        if let Some(ref mut recorder) = rate_recorder {
+                            recorder.start(now, counter_c[TcpStatistics::SentSyn]);
+                        }
+                        let target = profile.target(now);
+                        arrival.set_rate(target.cps, now);
+                        max_open = target.max_open;
+                        load_target = Some(target);
+                    }
+                }
+                let b_cool_down = run_window.as_ref().map_or(false, |window| window.cooling_down());
+                if counter_c[TcpStatistics::SentSyn] < nr_connections && !b_cool_down {
                     //info!("syn= {}, ack= {}, open= {}", counter_c[TcpStatistics::SentSyn], counter_c[TcpStatistics::RecvSynAck], cm_c.concurrent_connections());
                     //assert!(counter_c[TcpStatistics::SentSyn]- counter_c[TcpStatistics::RecvSynAck] <= max_open);
-                    if cm_c.concurrent_connections() < max_open {
+                    if !arrival.due(unsafe { _rdtsc() }) {
+                        // no connection is due, the packet is dropped
+                    } else if cm_c.concurrent_connections() < max_open {
                         if let Some(c) = cm_c.create(TcpRole::Client) {
+                            c.set_server_index(target_selector.select(c.client_ip(), c.port()));
+                            c.set_payload_limit(load_target.and_then(|target| target.payloads));
                             generate_syn(
                                 pdu,
                                 c,
@@ -606,14 +1343,19 @@ pub fn setup_generator<FPL>(
                                 &servers,
                                 &pipeline_id_clone,
                                 &mut counter_c[TcpStatistics::SentSyn],
+                                syn_options.as_ref(),
                             );
                             c.push_state(TcpState::SynSent);
-                            c.wheel_slot_and_index =
-                                wheel_c.schedule(&(timeouts.established.unwrap() * system_data.cpu_clock / 1000), c.port());
+                            c.wheel_slot_and_index = wheel_c.schedule(&state_timeouts.handshake, c.key());
+                            if let Some(ref params) = rto_params {
+                                track_segment_c(pdu, c, &mut wheel_rto_c, system_data.cpu_clock, params);
+                            }
                             group_index = 1;
                             #[cfg(feature = "profiling")]
                             time_adders[4].add_diff(unsafe { _rdtsc() } - timestamp_entry);
                         }
+                    } else if arrival.open_loop() {
+                        ext_counter_c[EngineStatistics::ArrivalsSkipped] += 1;
                     }
                 } else {
                     if syn_injector_runs() {
@@ -629,9 +1371,19 @@ pub fn setup_generator<FPL>(
                 if let Some(c) = cm_c.get_ready_connection() {
                     prepare_payload_packet(c, pdu, &me, &servers);
                     let mut b_fin = false;
-                    cdata.client_port = c.port();
-                    cdata.uuid = c.uid();
-                    f_set_payload(pdu, c, Some(cdata), &mut b_fin);
+                    // the rest of a payload, which exceeded the MSS of the DUT, is sent before the next payload
+                    let b_unsent = c.has_unsent();
+                    if b_unsent {
+                        let n = cmp::min(c.unsent().len(), c.snd_mss().unwrap() as usize);
+                        if let Err(e) = set_payload(pdu, &c.unsent()[..n]) {
+                            error!("{} client: cannot send rest of payload on port {}: {}", thread_id, c.port(), e);
+                        }
+                    } else {
+                        cdata.client_port = c.port();
+                        cdata.uuid = c.uid();
+                        f_set_payload(pdu, c, Some(cdata), &mut b_fin);
+                        limit_to_mss(pdu, c);
+                    }
                     /*
                     let pp = c.sent_payload_pkts();
                     if pp < 1 {
@@ -645,7 +1397,7 @@ pub fn setup_generator<FPL>(
                         c.inc_sent_payload_pkts();
                         counter_c[TcpStatistics::SentPayload] += 1;
                         // requeue
-                        ready_connection = Some(c.port());
+                        ready_connection = Some(c.key());
                         group_index = 1;
                     } else if pp == fin_by_client && c.state() < TcpState::CloseWait {
                         generate_fin(&mut pdu, c, &mut hs, &me, &servers, &mut counter_c[TcpStatistics::SentFin]);
@@ -654,25 +1406,62 @@ pub fn setup_generator<FPL>(
                         group_index = 1;
                     }
                     */
-                    if !b_fin {
-                        c.inc_sent_payload_pkts();
-                        counter_c[TcpStatistics::SentPayload] += 1;
-                        c.seqn_nxt = c.seqn_nxt.wrapping_add(tcp_payload_size(pdu) as u32);
-                        if pdu.data_len() < MIN_FRAME_SIZE {
-                            let n_padding_bytes = MIN_FRAME_SIZE - pdu.data_len();
-                            debug!("padding with {} 0x0 bytes", n_padding_bytes);
-                            pdu.increase_payload_size(n_padding_bytes);
+                    let sendable = c.sendable(tcp_payload_size(pdu) as u32) as usize;
+                    if !b_fin && tcp_payload_size(pdu) == 0 {
+                        // the payload function waits for the DUT, the packet is dropped
+                        trace!("{} client: no payload to send on port {}", thread_id, c.port());
+                    } else if !b_fin && sendable == 0 {
+                        // the DUT cannot take the payload, we drop the packet and continue when the window opens
+                        debug!(
+                            "{} client: window of DUT is full on port {}, send window= {}",
+                            thread_id,
+                            c.port(),
+                            c.send_window()
+                        );
+                        if !b_unsent {
+                            // the payload function is asked again, when the window opens
+                            c.clear_unsent();
                         }
-                        prepare_checksum_and_ttl(pdu);
-                        // requeue
-                        // ready_connection = Some(c.port());
-                        group_index = 1;
+                        block_c(c, &mut wheel_rto_c, system_data.cpu_clock, &persist_params);
+                        ext_counter_c[EngineStatistics::WindowBlocked] += 1;
                     } else {
-                        generate_fin(pdu, c, &me, &servers);
-                        counter_c[TcpStatistics::SentFin] += 1;
-                        c.set_release_cause(ReleaseCause::ActiveClose);
-                        c.push_state(TcpState::FinWait1);
-                        group_index = 1;
+                        if !b_fin {
+                            limit_to_window(pdu, c, sendable, b_unsent);
+                            if b_unsent {
+                                c.consume_unsent(tcp_payload_size(pdu));
+                            } else {
+                                c.inc_sent_payload_pkts();
+                            }
+                            counter_c[TcpStatistics::SentPayload] += 1;
+                            c.seqn_nxt = c.seqn_nxt.wrapping_add(tcp_payload_size(pdu) as u32);
+                            if pdu.data_len() < MIN_FRAME_SIZE {
+                                let n_padding_bytes = MIN_FRAME_SIZE - pdu.data_len();
+                                debug!("padding with {} 0x0 bytes", n_padding_bytes);
+                                pdu.increase_payload_size(n_padding_bytes);
+                            }
+                            prepare_checksum_and_ttl(pdu);
+                            if bulk_transfer {
+                                // requeue, as long as the windows allow for more payload
+                                if c.usable_window() > 0 {
+                                    ready_connection = Some(c.key());
+                                } else {
+                                    c.set_blocked(true);
+                                }
+                            } else if c.payload_pending() {
+                                ready_connection = Some(c.key());
+                            }
+                            group_index = 1;
+                        } else {
+                            generate_fin(pdu, c, &me, &servers);
+                            counter_c[TcpStatistics::SentFin] += 1;
+                            c.set_release_cause(ReleaseCause::ActiveClose);
+                            c.push_state(TcpState::FinWait1);
+                            reschedule_c = Some((c.key(), state_timeouts.fin_wait));
+                            group_index = 1;
+                        }
+                        if let Some(ref params) = rto_params {
+                            track_segment_c(pdu, c, &mut wheel_rto_c, system_data.cpu_clock, params);
+                        }
                     }
                     #[cfg(feature = "profiling")]
                     time_adders[5].add_diff(unsafe { _rdtsc() } - timestamp_entry);
@@ -685,6 +1474,151 @@ pub fn setup_generator<FPL>(
                 //    cm_c.set_ready_connection(port, &payload_injector_ready_flag);
                 //}
             }
+            // retransmission of segments with expired retransmission timer, zero window probes and resets
+            (PRIVATE_ETYPE_PACKET, 3) => {
+                let mut give_up_c = None;
+                let mut give_up_s = None;
+                if let Some(key) = rexmit_c.pop_front() {
+                    if let Some(c) = cm_c.get_mut_by_key(key) {
+                        if c.in_persist() {
+                            zero_window_probe(pdu, c, &me, &servers);
+                            ext_counter_c[EngineStatistics::ZeroWindowProbe] += 1;
+                            c.persist_backoff(&persist_params);
+                            c.arm_rto_c(&mut wheel_rto_c, system_data.cpu_clock, &persist_params);
+                            group_index = 1;
+                        } else if c.unacked().is_some() {
+                            // segments are only noted down with retransmission parameters
+                            let params = rto_params.as_ref().unwrap();
+                            if let Some(ref cc) = congestion_control {
+                                if c.state() >= TcpState::Established {
+                                    let flight_size = c.flight_size();
+                                    let first = c.unacked().unwrap().retransmissions() == 0;
+                                    let mss = c.snd_mss().map_or(MSS, |mss| mss as u32);
+                                    let (cwnd, state) = c.cwnd_and_congestion_mut();
+                                    congestion::on_timeout(&**cc, cwnd, state, flight_size, mss, first);
+                                }
+                            }
+                            if c.backoff(params) {
+                                let sent = {
+                                    let ackn = if c.state() == TcpState::SynSent { None } else { Some(c.ackn_nxt) };
+                                    let window = c.negotiated().rcv_window();
+                                    let segment = c.unacked().unwrap();
+                                    let sent = retransmit(
+                                        pdu,
+                                        segment,
+                                        ackn,
+                                        window,
+                                        &servers.get(c.server_index()),
+                                        c.port(),
+                                        &me.mac,
+                                        c.client_ip(),
+                                    );
+                                    if sent {
+                                        count_retransmission(segment, &mut ext_counter_c);
+                                    }
+                                    sent
+                                };
+                                if sent {
+                                    if c.state() == TcpState::SynSent {
+                                        // each SYN shows up in the ConRecord with its time stamp
+                                        c.push_state(TcpState::SynSent);
+                                    }
+                                    group_index = 1;
+                                } else {
+                                    ext_counter_c[EngineStatistics::RetransmissionSkipped] += 1;
+                                }
+                                // a skipped retransmission is tried again, when the timer expires
+                                c.arm_rto_c(&mut wheel_rto_c, system_data.cpu_clock, params);
+                            } else {
+                                debug!(
+                                    "{} client: giving up {:?} after {} retransmissions",
+                                    thread_id,
+                                    c.sock(),
+                                    c.unacked().unwrap().retransmissions()
+                                );
+                                ext_counter_c[EngineStatistics::RetransmissionLimit] += 1;
+                                match c.pending_rst() {
+                                    Some(ref rst) if rst_on_timeout => {
+                                        let peer = servers.get(rst.server_index);
+                                        generate_rst(pdu, rst, &peer, rst.sock.1, &me.mac, rst.sock.0);
+                                        counter_c[TcpStatistics::SentRst] += 1;
+                                        c.set_release_cause(ReleaseCause::ActiveRst);
+                                        group_index = 1;
+                                    }
+                                    _ => c.set_release_cause(ReleaseCause::Timeout),
+                                }
+                                c.push_state(TcpState::Closed);
+                                give_up_c = Some(key);
+                            }
+                        }
+                    }
+                } else if let Some(key) = rexmit_s.pop_front() {
+                    let params = rto_params.as_ref().unwrap();
+                    if let Some(c) = cm_s.get_mut(&key) {
+                        if c.unacked().is_some() {
+                            if c.backoff(params) {
+                                {
+                                    let segment = c.unacked().unwrap();
+                                    let peer = server_side_peer(&(key.0, key.1), segment.peer_mac);
+                                    if retransmit(
+                                        pdu,
+                                        segment,
+                                        Some(c.ackn_nxt),
+                                        c.negotiated().rcv_window(),
+                                        &peer,
+                                        key.2,
+                                        &me.mac,
+                                        segment.local_ip,
+                                    ) {
+                                        count_retransmission(segment, &mut ext_counter_s);
+                                        group_index = 1;
+                                    } else {
+                                        ext_counter_s[EngineStatistics::RetransmissionSkipped] += 1;
+                                    }
+                                }
+                                c.arm_rto_s(&mut wheel_rto_s, system_data.cpu_clock, params);
+                            } else {
+                                debug!(
+                                    "{} server: giving up {:?} after {} retransmissions",
+                                    thread_id, key, params.max_retries
+                                );
+                                ext_counter_s[EngineStatistics::RetransmissionLimit] += 1;
+                                match c.pending_rst() {
+                                    Some(ref rst) if rst_on_timeout => {
+                                        let peer = server_side_peer(&rst.sock, rst.peer_mac);
+                                        generate_rst(pdu, rst, &peer, rst.local_port, &me.mac, rst.local_ip);
+                                        counter_s[TcpStatistics::SentRst] += 1;
+                                        c.set_release_cause(ReleaseCause::ActiveRst);
+                                        group_index = 1;
+                                    }
+                                    _ => c.set_release_cause(ReleaseCause::Timeout),
+                                }
+                                c.push_state(TcpState::Closed);
+                                give_up_s = Some(key);
+                            }
+                        }
+                    }
+                } else if let Some(rst) = rst_c.pop_front() {
+                    generate_rst(pdu, &rst, &servers.get(rst.server_index), rst.sock.1, &me.mac, rst.sock.0);
+                    counter_c[TcpStatistics::SentRst] += 1;
+                    // only now the socket may be reused
+                    cm_c.free_sock(&rst.sock);
+                    group_index = 1;
+                } else if let Some(rst) = rst_s.pop_front() {
+                    let peer = server_side_peer(&rst.sock, rst.peer_mac);
+                    generate_rst(pdu, &rst, &peer, rst.local_port, &me.mac, rst.local_ip);
+                    counter_s[TcpStatistics::SentRst] += 1;
+                    group_index = 1;
+                } else if rexmit_injector_runs() {
+                    rexmit_injector_stop();
+                }
+                if let Some(key) = give_up_c {
+                    cm_c.release(key, &mut wheel_c, &mut wheel_rto_c);
+                }
+                if let Some(key) = give_up_s {
+                    cm_s.release(&key, &mut wheel_s, &mut wheel_rto_s);
+                }
+            }
             (PRIVATE_ETYPE_PACKET, _) => {
                 error!("received unknown dst port from PacketInjector");
             }
@@ -717,20 +1651,128 @@ pub fn setup_generator<FPL>(
                             hold.mean(),
                             hold.max_at().0,
                             hold.max_at().1
-                        )
+                        );
+                        info!(
+                            "{} max concurrent server connections= {}, server table capacity= {}",
+                            thread_id,
+                            cm_s.max_concurrent_connections(),
+                            cm_s.capacity(),
+                        );
+                        update_record_counters(&cm_c, &cm_s, &mut ext_counter_c, &mut ext_counter_s);
+                        print_engine_counters(&pipeline_id_clone, &ext_counter_c, &ext_counter_s);
+                        if let Some(ref profile) = load_profile {
+                            if profile.started() && !profile.finished() {
+                                // the current phase is reported up to now
+                                phase_recorder.end_phase(
+                                    profile.phase_name(profile.current()),
+                                    CounterSnapshot::new(
+                                        unsafe { _rdtsc() },
+                                        &counter_c,
+                                        &counter_s,
+                                        &ext_counter_c,
+                                        &ext_counter_s,
+                                    ),
+                                );
+                            }
+                            match rate_recorder {
+                                Some(ref recorder) => print_rate_samples(&pipeline_id_clone, recorder.samples()),
+                                None => {
+                                    print_phase_counters(&pipeline_id_clone, phase_recorder.phases(), system_data.cpu_clock)
+                                }
+                            }
+                        }
+                        if let Some(ref window) = run_window {
+                            if window.started() && !window.finished() {
+                                // the current section is reported up to now
+                                window_recorder.end_phase(
+                                    window.section_name(window.current()),
+                                    CounterSnapshot::new(
+                                        unsafe { _rdtsc() },
+                                        &counter_c,
+                                        &counter_s,
+                                        &ext_counter_c,
+                                        &ext_counter_s,
+                                    ),
+                                );
+                            }
+                            print_phase_counters(&pipeline_id_clone, window_recorder.phases(), system_data.cpu_clock);
+                            // the sections are recorded in order, the second is the steady state
+                            if let Some(steady) = window_recorder.phases().get(1) {
+                                print_steady_state(&pipeline_id_clone, steady, system_data.cpu_clock);
+                            }
+                        }
+                        if let Some(ref capture) = capture {
+                            if capture.dropped() > 0 {
+                                warn!(
+                                    "{}: {} packets not captured, the pcap writer was too slow",
+                                    pipeline_id_clone,
+                                    capture.dropped()
+                                );
+                            }
+                        }
                     }
                     Ok(MessageTo::FetchCRecords) => {
                         //trace!("{} got FetchCrecords", thread_id);
-                        tx_clone
-                            .send(MessageFrom::CRecords(
-                                pipeline_id_clone.clone(),
-                                cm_c.fetch_c_records(),
-                                cm_s.fetch_c_records(),
-                            ))
-                            .unwrap();
+                        send_records(&tx_clone, &pipeline_id_clone, cm_c.fetch_c_records(), cm_s.fetch_c_records());
                     }
                     _ => {}
                 }
+                if let Some(ref mut window) = run_window {
+                    let now = unsafe { _rdtsc() };
+                    window_recorder.observe(cm_c.concurrent_connections());
+                    let ended = window.advance(now);
+                    if !ended.is_empty() {
+                        update_record_counters(&cm_c, &cm_s, &mut ext_counter_c, &mut ext_counter_s);
+                        if window.finished() {
+                            OPEN_RUN_WINDOWS.fetch_sub(1, Ordering::SeqCst);
+                        }
+                    }
+                    for i in ended {
+                        info!("{} end of {}", thread_id, window.section_name(i));
+                        window_recorder.end_phase(
+                            window.section_name(i),
+                            CounterSnapshot::new(now, &counter_c, &counter_s, &ext_counter_c, &ext_counter_s),
+                        );
+                    }
+                }
+                if let Some(ref mut profile) = load_profile {
+                    if profile.started() && !profile.finished() {
+                        let now = unsafe { _rdtsc() };
+                        phase_recorder.observe(cm_c.concurrent_connections());
+                        let ended = profile.advance(now);
+                        if !ended.is_empty() {
+                            update_record_counters(&cm_c, &cm_s, &mut ext_counter_c, &mut ext_counter_s);
+                        }
+                        for i in ended {
+                            debug!("{} end of phase {}", thread_id, profile.phase_name(i));
+                            phase_recorder.end_phase(
+                                profile.phase_name(i),
+                                CounterSnapshot::new(now, &counter_c, &counter_s, &ext_counter_c, &ext_counter_s),
+                            );
+                        }
+                        // the rate is retargeted with each tick, which ramps it in steps of 10 ms
+                        let target = profile.target(now);
+                        arrival.set_rate(target.cps, now);
+                        max_open = target.max_open;
+                        load_target = Some(target);
+                        if let Some(ref mut recorder) = rate_recorder {
+                            recorder.observe(
+                                now,
+                                counter_c[TcpStatistics::SentSyn],
+                                cm_c.concurrent_connections(),
+                                target.cps,
+                                target.max_open,
+                            );
+                        }
+                        if profile.finished() && syn_injector_runs() {
+                            info!("{} load profile finished", thread_id);
+                            syn_injector_stop();
+                        }
+                    }
+                }
+                if record_ship_ticks.map_or(false, |n| ticks % n == 0) {
+                    send_records(&tx_clone, &pipeline_id_clone, cm_c.ship_c_records(), cm_s.ship_c_records());
+                }
                 // check if we are ready and both time stamps are set:
                 if start_stamp > 0 && stop_stamp > 0 {
                     tx_clone
@@ -741,8 +1783,20 @@ pub fn setup_generator<FPL>(
                 }
                 // check for timeouts
                 if ticks % wheel_tick_reduction_factor == 0 {
-                    cm_c.release_timeouts(unsafe { &_rdtsc() }, &mut wheel_c);
-                    cm_s.release_timeouts(unsafe { &_rdtsc() }, &mut wheel_s);
+                    let (resets_c, resets_s) = if rst_on_timeout {
+                        (Some(&mut rst_c), Some(&mut rst_s))
+                    } else {
+                        (None, None)
+                    };
+                    cm_c.release_timeouts(unsafe { &_rdtsc() }, &mut wheel_c, &mut wheel_rto_c, &state_timeouts, resets_c);
+                    cm_s.release_timeouts(unsafe { &_rdtsc() }, &mut wheel_s, &mut wheel_rto_s, &state_timeouts, resets_s);
+                    cm_c.rto_timeouts(unsafe { &_rdtsc() }, &mut wheel_rto_c, &mut rexmit_c);
+                    cm_s.rto_timeouts(unsafe { &_rdtsc() }, &mut wheel_rto_s, &mut rexmit_s);
+                    if (rexmit_c.len() > 0 || rexmit_s.len() > 0 || rst_c.len() > 0 || rst_s.len() > 0)
+                        && !rexmit_injector_runs()
+                    {
+                        rexmit_injector_start();
+                    }
                 }
                 #[cfg(feature = "profiling")]
                 {
@@ -757,25 +1811,69 @@ pub fn setup_generator<FPL>(
                     }
                 }
             }
-            (ETYPE_IPV4, dst_port) if dst_port == server_listen_port => {
+            (ETYPE_IPV4, _) if service.is_some() => {
                 //
                 // **** server side ****
                 //
-                let opt_c = if pdu.headers().tcp(2).syn_flag() {
+                let service = service.unwrap();
+                let opt_c = if pdu.headers().tcp(2).syn_flag() && syn_cookies.is_some() && !cm_s.contains(&server_key) {
+                    // stateless answer, the connection is created with the final ACK of the handshake
+                    counter_s[TcpStatistics::RecvSyn] += 1;
+                    syn_cookie_reply(pdu, syn_cookies.as_ref().unwrap(), cookie_mss, unsafe { _rdtsc() });
+                    counter_s[TcpStatistics::SentSynAck] += 1;
+                    ext_counter_s[EngineStatistics::SynCookie] += 1;
+                    group_index = 1;
+                    None
+                } else if pdu.headers().tcp(2).syn_flag() {
                     debug!(
                         "{} server: got SYN with src = {:?} on server listen port 0x{:x}",
-                        thread_id, src_sock, server_listen_port
+                        thread_id, src_sock, dst_sock.1
                     );
                     counter_s[TcpStatistics::RecvSyn] += 1;
-                    let c = cm_s.get_mut_or_insert(&src_sock);
+                    let c = cm_s.get_mut_or_insert(&server_key);
+                    if c.is_none() && !cm_s.records_blocking() {
+                        ext_counter_s[EngineStatistics::ServerTableFull] += 1;
+                    }
                     #[cfg(feature = "profiling")]
                     time_adders[11].add_diff(unsafe { _rdtsc() } - timestamp_entry);
                     c
+                } else if syn_cookies.is_some()
+                    && pdu.headers().tcp(2).ack_flag()
+                    && !pdu.headers().tcp(2).rst_flag()
+                    && !cm_s.contains(&server_key)
+                {
+                    let (seqn, ackn) = (pdu.headers().tcp(2).seq_num(), pdu.headers().tcp(2).ack_num());
+                    match syn_cookies
+                        .as_ref()
+                        .unwrap()
+                        .validate(src_sock, dst_sock, seqn, ackn, unsafe { _rdtsc() })
+                    {
+                        Some(mss) => match cm_s.insert(&server_key) {
+                            Some(c) => {
+                                syn_cookie_accepted(pdu, c, mss);
+                                c.set_server_index(rxq as usize); // we misuse this field for the queue number
+                                c.wheel_slot_and_index = wheel_s.schedule(&state_timeouts.handshake, server_key);
+                                Some(c)
+                            }
+                            None => {
+                                if !cm_s.records_blocking() {
+                                    ext_counter_s[EngineStatistics::ServerTableFull] += 1;
+                                }
+                                None
+                            }
+                        },
+                        None => {
+                            ext_counter_s[EngineStatistics::InvalidSynCookie] += 1;
+                            None
+                        }
+                    }
                 } else {
-                    cm_s.get_mut(&src_sock)
+                    cm_s.get_mut(&server_key)
                 };
 
                 match opt_c {
+                    // a SYN answered with a cookie needs no state, a full table is counted
+                    None if group_index == 1 || pdu.headers().tcp(2).syn_flag() => (),
                     None => warn!(
                         "no state for this packet on server port: src_sock= {:?}, tcp = {:?} ",
                         src_sock,
@@ -786,7 +1884,15 @@ pub fn setup_generator<FPL>(
                         //check seqn
                         if old_s_state != TcpState::Listen && pdu.headers().tcp(2).seq_num() != c.ackn_nxt {
                             let diff = pdu.headers().tcp(2).seq_num() as i64 - c.ackn_nxt as i64;
-                            if diff > 0 {
+                            if seq_lt(c.ackn_nxt, pdu.headers().tcp(2).seq_num())
+                                && accepts_data(old_s_state)
+                                && !pdu.headers().tcp(2).syn_flag()
+                                && !pdu.headers().tcp(2).rst_flag()
+                            {
+                                debug!("{} server: out-of-order segment, seqn differs by {}", thread_id, diff);
+                                out_of_order_received(pdu, c, &mut ext_counter_s);
+                                group_index = 1;
+                            } else if diff > 0 {
                                 warn!(
                                     "{} server: unexpected seqn (packet loss?) in state {:?}, seqn differs by {}\ntcp = {}",
                                     thread_id,
@@ -804,6 +1910,19 @@ pub fn setup_generator<FPL>(
                                 );
                             }
                         } else {
+                            c.touch(unsafe { _rdtsc() });
+                            if let Some(ref params) = rto_params {
+                                if pdu.headers().tcp(2).ack_flag()
+                                    && c.ack_received(
+                                        pdu.headers().tcp(2).ack_num(),
+                                        unsafe { _rdtsc() },
+                                        system_data.cpu_clock,
+                                        params,
+                                    )
+                                {
+                                    c.disarm_rto_s(&mut wheel_rto_s);
+                                }
+                            }
                             // process payload
                             let payload_sz = tcp_payload_size(pdu);
                             let b_payload = old_s_state >= TcpState::Established && payload_sz > 0;
@@ -823,19 +1942,26 @@ pub fn setup_generator<FPL>(
                                     }
                                 }
                                 c.ackn_nxt = pdu.headers().tcp(2).seq_num().wrapping_add(payload_sz as u32);
+                                // the segment may close a gap
+                                reassemble(pdu, c, &mut counter_s);
                             }
+                            // the ACKs of the DUT clock out echoed payload, which exceeded the MSS of the DUT
+                            let b_unsent = !b_payload && old_s_state == TcpState::Established && c.has_unsent() && {
+                                let tcp = pdu.headers().tcp(2);
+                                tcp.ack_flag() && !tcp.syn_flag() && !tcp.fin_flag() && !tcp.rst_flag()
+                            };
 
                             if pdu.headers().tcp(2).syn_flag() {
                                 // check flags
                                 if old_s_state == TcpState::Listen {
                                     // replies with a SYN-ACK to client:
-                                    syn_received(pdu, c);
+                                    syn_received(pdu, c, syn_options.as_ref());
                                     c.set_server_index(rxq as usize); // we misuse this field for the queue number
-                                    c.wheel_slot_and_index = wheel_s.schedule(
-                                        &(timeouts.established.unwrap() * system_data.cpu_clock / 1000),
-                                        c.sock().unwrap(),
-                                    );
+                                    c.wheel_slot_and_index = wheel_s.schedule(&state_timeouts.handshake, server_key);
                                     counter_s[TcpStatistics::SentSynAck] += 1;
+                                    if let Some(ref params) = rto_params {
+                                        track_segment_s(pdu, c, &mut wheel_rto_s, system_data.cpu_clock, params);
+                                    }
                                     group_index = 1;
                                     #[cfg(feature = "profiling")]
                                     time_adders[1].add_diff(unsafe { _rdtsc() } - timestamp_entry);
@@ -845,7 +1971,7 @@ pub fn setup_generator<FPL>(
                             } else if pdu.headers().tcp(2).fin_flag() {
                                 //trace!("server: received FIN");
                                 if old_s_state >= TcpState::FinWait1 {
-                                    if active_close(pdu, c, &mut counter_s, &old_s_state) {
+                                    if active_close(pdu, c, &mut counter_s, &old_s_state, TcpState::Closed) {
                                         b_release_connection_s = true;
                                     }
                                     if pdu.headers().tcp(2).ack_flag() && pdu.headers().tcp(2).ack_num() == c.seqn_nxt {
@@ -855,6 +1981,9 @@ pub fn setup_generator<FPL>(
                                 } else {
                                     // DUT wants to close connection
                                     passive_close(pdu, c, &thread_id, &mut counter_s);
+                                    if let Some(ref params) = rto_params {
+                                        track_segment_s(pdu, c, &mut wheel_rto_s, system_data.cpu_clock, params);
+                                    }
                                     group_index = 1;
                                 }
                                 #[cfg(feature = "profiling")]
@@ -897,8 +2026,14 @@ pub fn setup_generator<FPL>(
                                 }
                             }
 
-                            if b_payload && old_s_state == TcpState::Established {
-                                let b_fin = c.recv_payload_pkts() >= fin_by_server;
+                            if (b_payload || b_unsent) && old_s_state == TcpState::Established {
+                                let b_fin = c.recv_payload_pkts() >= service.fin_by_server;
+                                let b_echo = service.response == ServiceResponse::Echo;
+                                if !b_echo {
+                                    strip_payload(pdu);
+                                }
+                                // sets also c.ackn_nxt
+                                let b_fin = s_reply_with_payload(pdu, &mut c, b_fin);
                                 if b_fin {
                                     //trace!("server: reply with payload and FIN");
                                     counter_s[TcpStatistics::SentFin] += 1;
@@ -907,13 +2042,22 @@ pub fn setup_generator<FPL>(
                                 } else {
                                     //trace!("server: reply with payload");
                                 }
-                                // sets also c.ackn_nxt
-                                s_reply_with_payload(pdu, &mut c, b_fin);
-                                counter_s[TcpStatistics::SentPayload] += 1;
-                                c.inc_sent_payload_pkts();
+                                if b_echo && tcp_payload_size(pdu) > 0 {
+                                    counter_s[TcpStatistics::SentPayload] += 1;
+                                    c.inc_sent_payload_pkts();
+                                }
+                                // a pure ACK is not retransmitted
+                                if b_echo || b_fin {
+                                    if let Some(ref params) = rto_params {
+                                        track_segment_s(pdu, c, &mut wheel_rto_s, system_data.cpu_clock, params);
+                                    }
+                                }
                                 group_index = 1;
                             }
                         }
+                        if c.state() != old_s_state && old_s_state != TcpState::Listen {
+                            reschedule_s = state_timeouts.for_state(c.state());
+                        }
 
                         #[cfg(feature = "profiling")]
                         time_adders[3].add_diff(unsafe { _rdtsc() } - timestamp_entry);
@@ -929,16 +2073,16 @@ pub fn setup_generator<FPL>(
                 if !cm_c.owns_tcp_port(client_port) {
                     error!("flow steering failed {}", pdu.headers().tcp(2));
                 }
-                let c = cm_c.get_mut_by_port(client_port);
+                let c = cm_c.get_mut_by_sock(&dst_sock);
                 #[cfg(feature = "profiling")]
                 time_adders[0].add_diff(unsafe { _rdtsc() } - timestamp_entry);
                 match c {
                     None => {
                         warn!(
-                            "{} @ {} client: engine has no state for port {} ({}-{}), sending to KNI i/f",
+                            "{} @ {} client: engine has no state for {:?} ({}-{}), sending to KNI i/f",
                             thread_id,
                             unsafe { _rdtsc() }.separated_string(),
-                            client_port,
+                            dst_sock,
                             pdu.headers().ip(1),
                             pdu.headers().tcp(2),
                             //Ipv4Addr::from(hs.ip.dst()),
@@ -954,7 +2098,21 @@ pub fn setup_generator<FPL>(
                         //check seqn
                         if old_c_state != TcpState::SynSent && pdu.headers().tcp(2).seq_num() != c.ackn_nxt {
                             let diff = pdu.headers().tcp(2).seq_num() as i64 - c.ackn_nxt as i64;
-                            if diff > 0 {
+                            if old_c_state == TcpState::TimeWait && diff == -1 && pdu.headers().tcp(2).fin_flag() {
+                                // repeat our ACK and restart the 2MSL timer
+                                send_ack(pdu, c);
+                                counter_c[TcpStatistics::SentAck4Fin] += 1;
+                                b_time_wait_c = true;
+                                group_index = 1;
+                            } else if seq_lt(c.ackn_nxt, pdu.headers().tcp(2).seq_num())
+                                && accepts_data(old_c_state)
+                                && !pdu.headers().tcp(2).syn_flag()
+                                && !pdu.headers().tcp(2).rst_flag()
+                            {
+                                debug!("{} client: out-of-order segment, seqn differs by {}", thread_id, diff);
+                                out_of_order_received(pdu, c, &mut ext_counter_c);
+                                group_index = 1;
+                            } else if diff > 0 {
                                 warn!(
                                     "{} client: unexpected sequence number (packet loss?) in state {:?}, seqn differs by {}\ntcp = { }",
                                     thread_id, old_c_state, diff, pdu.headers().tcp(2)
@@ -969,14 +2127,98 @@ pub fn setup_generator<FPL>(
                                 );
                             }
                         } else {
+                            c.touch(unsafe { _rdtsc() });
                             //check for payload
                             let payload_sz = tcp_payload_size(pdu);
+                            let mut b_fast_retransmit = false;
+                            if pdu.headers().tcp(2).ack_flag() {
+                                let (seqn, ackn, window) = {
+                                    let tcp = pdu.headers().tcp(2);
+                                    (tcp.seq_num(), tcp.ack_num(), (tcp.window_size() as u32) << c.negotiated().snd_wscale)
+                                };
+                                let (old_una, old_window) = (c.seqn_una, c.send_window());
+                                if old_c_state == TcpState::SynSent {
+                                    // the window of a SYN-ACK is not scaled
+                                    c.init_send_window(seqn, ackn, window);
+                                    if let Some(ref offered) = syn_options {
+                                        let received = TcpOptions::parse(tcp_option_bytes(pdu));
+                                        c.set_negotiated(NegotiatedOptions::negotiate(offered, &received));
+                                    }
+                                    if congestion_control.is_some() {
+                                        c.cwnd = initial_cwnd * c.snd_mss().map_or(MSS, |mss| mss as u32);
+                                    }
+                                } else {
+                                    c.update_send_window(seqn, ackn, window);
+                                }
+                                let now = unsafe { _rdtsc() };
+                                if let Some(ref params) = rto_params {
+                                    if c.ack_received(ackn, now, system_data.cpu_clock, params) {
+                                        // restart the timer for the remaining segments (RFC 6298, 5.3)
+                                        if c.unacked().is_some() {
+                                            c.arm_rto_c(&mut wheel_rto_c, system_data.cpu_clock, params);
+                                        } else {
+                                            c.disarm_rto_c(&mut wheel_rto_c);
+                                        }
+                                    }
+                                }
+                                if let Some(ref cc) = congestion_control {
+                                    let mss = c.snd_mss().map_or(MSS, |mss| mss as u32);
+                                    let b_control = {
+                                        let tcp = pdu.headers().tcp(2);
+                                        tcp.syn_flag() || tcp.fin_flag()
+                                    };
+                                    if old_c_state != TcpState::SynSent && seq_lt(old_una, ackn) {
+                                        let acked = ackn.wrapping_sub(old_una);
+                                        let flight_size = c.flight_size();
+                                        let srtt = c.srtt();
+                                        let (cwnd, state) = c.cwnd_and_congestion_mut();
+                                        if congestion::on_new_ack(
+                                            &**cc,
+                                            cwnd,
+                                            state,
+                                            ackn,
+                                            acked,
+                                            flight_size,
+                                            mss,
+                                            now,
+                                            system_data.cpu_clock,
+                                            srtt,
+                                        ) {
+                                            // partial ack: retransmit, if we can re-use this packet
+                                            b_fast_retransmit = payload_sz == 0 && !b_control;
+                                        }
+                                    } else if ackn == old_una
+                                        && payload_sz == 0
+                                        && !b_control
+                                        && window == old_window
+                                        && !c.all_acked()
+                                    {
+                                        let (seqn_nxt, flight_size) = (c.seqn_nxt, c.flight_size());
+                                        let (cwnd, state) = c.cwnd_and_congestion_mut();
+                                        b_fast_retransmit =
+                                            congestion::on_dup_ack(&**cc, cwnd, state, seqn_nxt, flight_size, mss);
+                                    }
+                                }
+                                if c.blocked() && c.usable_window() > 0 {
+                                    c.set_blocked(false);
+                                    if c.in_persist() {
+                                        c.set_persist(false);
+                                        c.disarm_rto_c(&mut wheel_rto_c);
+                                    }
+                                    // a payload of the DUT is answered directly, otherwise we continue with the injector
+                                    if old_c_state == TcpState::Established && (payload_sz == 0 || bulk_transfer) {
+                                        ready_connection = Some(c.key());
+                                    }
+                                }
+                            }
                             let b_payload = old_c_state >= TcpState::Established && payload_sz > 0;
                             if b_payload {
                                 counter_c[TcpStatistics::RecvPayload] += 1;
                                 c.inc_recv_payload_pkts();
                                 //trace!("client: got payload, count= {}", c.sent_payload_pkts());
                                 c.ackn_nxt = pdu.headers().tcp(2).seq_num().wrapping_add(payload_sz as u32);
+                                // the segment may close a gap
+                                reassemble(pdu, c, &mut counter_c);
                             }
 
                             if pdu.headers().tcp(2).ack_flag() && pdu.headers().tcp(2).syn_flag() {
@@ -984,7 +2226,7 @@ pub fn setup_generator<FPL>(
                                 counter_c[TcpStatistics::RecvSynAck] += 1;
                                 if old_c_state == TcpState::SynSent {
                                     c.push_state(TcpState::Established);
-                                    ready_connection = Some(c.port());
+                                    ready_connection = Some(c.key());
                                     debug!(
                                         "{} client: connection for port {} to DUT ({:?}) established ",
                                         thread_id,
@@ -1011,12 +2253,15 @@ pub fn setup_generator<FPL>(
                                             &mut hold,
                                         );
                                     }
-                                    if active_close(pdu, c, &mut counter_c, &old_c_state) {
-                                        b_release_connection_c = true;
+                                    if active_close(pdu, c, &mut counter_c, &old_c_state, close_state_c) {
+                                        b_time_wait_c = true;
                                     }
                                     group_index = 1;
                                 } else {
                                     passive_close(pdu, c, &thread_id, &mut counter_c);
+                                    if let Some(ref params) = rto_params {
+                                        track_segment_c(pdu, c, &mut wheel_rto_c, system_data.cpu_clock, params);
+                                    }
                                     group_index = 1;
                                 }
                                 #[cfg(feature = "profiling")]
@@ -1054,7 +2299,7 @@ pub fn setup_generator<FPL>(
                                         );
                                     }
                                     TcpState::Closing => {
-                                        c.push_state(TcpState::Closed);
+                                        c.push_state(close_state_c);
                                         recv_ack4fin(
                                             c,
                                             &mut counter_c[TcpStatistics::RecvAck4Fin],
@@ -1062,23 +2307,61 @@ pub fn setup_generator<FPL>(
                                             &mut stop_stamp,
                                             &mut hold,
                                         );
-                                        b_release_connection_c = true;
+                                        b_time_wait_c = true;
                                     }
                                     TcpState::Established if b_payload => {
-                                        if !c_recv_payload(pdu, c) {
-                                            counter_c[TcpStatistics::SentPayload] += 1;
-                                        } else {
-                                            counter_c[TcpStatistics::SentFin] += 1;
+                                        let b_track = match c_recv_payload(pdu, c) {
+                                            Reply::Payload => {
+                                                counter_c[TcpStatistics::SentPayload] += 1;
+                                                if c.payload_pending() {
+                                                    ready_connection = Some(c.key());
+                                                }
+                                                true
+                                            }
+                                            Reply::Fin => {
+                                                counter_c[TcpStatistics::SentFin] += 1;
+                                                true
+                                            }
+                                            Reply::Blocked => {
+                                                ext_counter_c[EngineStatistics::WindowBlocked] += 1;
+                                                block_c(c, &mut wheel_rto_c, system_data.cpu_clock, &persist_params);
+                                                false
+                                            }
+                                            Reply::Ack => false,
+                                        };
+                                        if let Some(ref params) = rto_params {
+                                            if b_track {
+                                                track_segment_c(pdu, c, &mut wheel_rto_c, system_data.cpu_clock, params);
+                                            }
                                         }
                                         group_index = 1;
                                     }
                                     _ => (),
                                 }
                             } else if b_payload && old_c_state == TcpState::Established {
-                                if !c_recv_payload(pdu, c) {
-                                    counter_c[TcpStatistics::SentPayload] += 1;
-                                } else {
-                                    counter_c[TcpStatistics::SentFin] += 1;
+                                let b_track = match c_recv_payload(pdu, c) {
+                                    Reply::Payload => {
+                                        counter_c[TcpStatistics::SentPayload] += 1;
+                                        if c.payload_pending() {
+                                            ready_connection = Some(c.key());
+                                        }
+                                        true
+                                    }
+                                    Reply::Fin => {
+                                        counter_c[TcpStatistics::SentFin] += 1;
+                                        true
+                                    }
+                                    Reply::Blocked => {
+                                        ext_counter_c[EngineStatistics::WindowBlocked] += 1;
+                                        block_c(c, &mut wheel_rto_c, system_data.cpu_clock, &persist_params);
+                                        false
+                                    }
+                                    Reply::Ack => false,
+                                };
+                                if let Some(ref params) = rto_params {
+                                    if b_track {
+                                        track_segment_c(pdu, c, &mut wheel_rto_c, system_data.cpu_clock, params);
+                                    }
                                 }
                                 group_index = 1;
                             } else if !pdu.headers().tcp(2).ack_flag() {
@@ -1092,6 +2375,40 @@ pub fn setup_generator<FPL>(
                                 );
                                 group_index = 2;
                             }
+                            // a pure ACK, which is not needed by the state machine, carries the fast retransmit
+                            if b_fast_retransmit && group_index == 0 && c.unacked().is_some() {
+                                remove_tcp_options(pdu);
+                                strip_payload(pdu);
+                                let sent = {
+                                    let segment = c.unacked().unwrap();
+                                    let sent = retransmit(
+                                        pdu,
+                                        segment,
+                                        Some(c.ackn_nxt),
+                                        c.negotiated().rcv_window(),
+                                        &servers.get(c.server_index()),
+                                        c.port(),
+                                        &me.mac,
+                                        c.client_ip(),
+                                    );
+                                    if sent {
+                                        count_retransmission(segment, &mut ext_counter_c);
+                                    }
+                                    sent
+                                };
+                                if sent {
+                                    c.note_retransmission();
+                                    c.arm_rto_c(&mut wheel_rto_c, system_data.cpu_clock, rto_params.as_ref().unwrap());
+                                    ext_counter_c[EngineStatistics::FastRetransmit] += 1;
+                                    group_index = 1;
+                                } else {
+                                    // the retransmission timer is still running
+                                    ext_counter_c[EngineStatistics::RetransmissionSkipped] += 1;
+                                }
+                            }
+                        }
+                        if c.state() != old_c_state {
+                            reschedule_c = state_timeouts.for_state(c.state()).map(|timeout| (c.key(), timeout));
                         }
                     }
                 }
@@ -1102,22 +2419,37 @@ pub fn setup_generator<FPL>(
         // here we check if we shall release the connection state,
         // need this cumbersome way because of borrow checker for the connection managers
         if b_release_connection_s {
-            cm_s.release(&src_sock, &mut wheel_s);
+            cm_s.release(&server_key, &mut wheel_s, &mut wheel_rto_s);
             #[cfg(feature = "profiling")]
             time_adders[10].add_diff(unsafe { _rdtsc() } - timestamp_entry);
         }
         if b_release_connection_c {
-            debug!("releasing client connection on port {}", dst_sock.1);
-            cm_c.release(dst_sock.1, &mut wheel_c);
+            debug!("releasing client connection {:?}", dst_sock);
+            cm_c.release(cm_c.key(&dst_sock).unwrap(), &mut wheel_c, &mut wheel_rto_c);
             #[cfg(feature = "profiling")]
             time_adders[9].add_diff(unsafe { _rdtsc() } - timestamp_entry);
         }
-        if let Some(sport) = ready_connection {
-            //trace!("{} connection on port {} is ready", thread_id, sport);
-            cm_c.set_ready_connection(sport, &payload_injector_ready_flag);
+        if b_time_wait_c {
+            match time_wait {
+                Some(timeout) => cm_c.time_wait(cm_c.key(&dst_sock).unwrap(), &mut wheel_c, &mut wheel_rto_c, timeout),
+                None => cm_c.release(cm_c.key(&dst_sock).unwrap(), &mut wheel_c, &mut wheel_rto_c),
+            }
+        }
+        if let Some((key, timeout)) = reschedule_c {
+            cm_c.reschedule(key, &mut wheel_c, timeout);
+        }
+        if let Some(timeout) = reschedule_s {
+            cm_s.reschedule(&server_key, &mut wheel_s, timeout);
+        }
+        if let Some(key) = ready_connection {
+            //trace!("{} connection {} is ready", thread_id, key);
+            cm_c.set_ready_connection(key, &payload_injector_ready_flag);
             #[cfg(feature = "profiling")]
             time_adders[6].add_diff(unsafe { _rdtsc() } - timestamp_entry);
         }
+        if group_index == 1 && capture.is_some() {
+            capture.as_mut().unwrap().capture(pdu);
+        }
         group_index
     };
 
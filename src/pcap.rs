@@ -0,0 +1,256 @@
+use std::arch::x86_64::_rdtsc;
+use std::cmp;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, Write, BufWriter};
+use std::mem;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use e2d2::interface::Pdu;
+use fnv::FnvHasher;
+use ipnet::Ipv4Net;
+use libc;
+
+use netfcts::comm::PipelineId;
+
+use CaptureConfig;
+
+/// magic number of pcap files with nanosecond time stamps
+const PCAP_MAGIC_NSEC: u32 = 0xa1b2_3c4d;
+const LINKTYPE_ETHERNET: u32 = 1;
+const MAC_HEADER_SIZE: usize = 14;
+const WRITE_BUFFER_SIZE: usize = 1 << 20;
+/// frames queued for the writer thread of a pipeline, further frames are dropped
+const CAPTURE_QUEUE_SIZE: usize = 16384;
+/// granularity of the sample ratio
+const SAMPLE_RESOLUTION: u64 = 1_000_000;
+
+/// writes Ethernet frames into a pcap file, the time stamps are derived from the TSC
+pub struct PcapWriter {
+    f: BufWriter<File>,
+    snaplen: u32,
+    cpu_clock: u64,
+    /// TSC and wall clock time in nanos, when the file was created
+    tsc0: u64,
+    nanos0: u64,
+}
+
+impl PcapWriter {
+    pub fn create<P: AsRef<Path>>(path: P, snaplen: u32, cpu_clock: u64) -> io::Result<PcapWriter> {
+        let mut f = BufWriter::with_capacity(WRITE_BUFFER_SIZE, File::create(path)?);
+        f.write_all(&PCAP_MAGIC_NSEC.to_le_bytes())?;
+        f.write_all(&2u16.to_le_bytes())?; // version 2.4
+        f.write_all(&4u16.to_le_bytes())?;
+        f.write_all(&0i32.to_le_bytes())?; // time zone, UTC
+        f.write_all(&0u32.to_le_bytes())?; // accuracy of time stamps
+        f.write_all(&snaplen.to_le_bytes())?;
+        f.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Ok(PcapWriter {
+            f,
+            snaplen,
+            cpu_clock,
+            tsc0: unsafe { _rdtsc() },
+            nanos0: since_epoch.as_secs() * 1_000_000_000 + since_epoch.subsec_nanos() as u64,
+        })
+    }
+
+    /// a frame, which may already be truncated, together with its original length
+    pub fn write_frame(&mut self, tsc: u64, frame: &[u8], orig_len: usize) -> io::Result<()> {
+        let elapsed = tsc.saturating_sub(self.tsc0) as u128;
+        let nanos = self.nanos0 + (elapsed * 1_000_000_000 / self.cpu_clock as u128) as u64;
+        let incl_len = frame.len().min(self.snaplen as usize);
+        self.f.write_all(&((nanos / 1_000_000_000) as u32).to_le_bytes())?;
+        self.f.write_all(&((nanos % 1_000_000_000) as u32).to_le_bytes())?;
+        self.f.write_all(&(incl_len as u32).to_le_bytes())?;
+        self.f.write_all(&(orig_len as u32).to_le_bytes())?;
+        self.f.write_all(&frame[..incl_len])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.f.flush()
+    }
+}
+
+/// a frame copied from a packet, truncated to the snaplen
+struct Frame {
+    tsc: u64,
+    orig_len: usize,
+    bytes: Vec<u8>,
+}
+
+/// moves the calling thread from the core of the pipeline to the cores of the main thread, i.e. the master core of DPDK
+fn leave_pipeline_core() {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        let size = mem::size_of::<libc::cpu_set_t>();
+        if libc::sched_getaffinity(libc::getpid(), size, &mut set) != 0 || libc::sched_setaffinity(0, size, &set) != 0 {
+            warn!("pcap writer stays on the core of its pipeline: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+/// the writer thread: writes the queued frames and returns their buffers, the file is flushed, whenever the queue runs
+/// empty. The thread ends with an error or when the PacketCapture is dropped.
+fn write_frames(mut writer: PcapWriter, frames: Receiver<Frame>, recycled: SyncSender<Vec<u8>>) {
+    leave_pipeline_core();
+    loop {
+        let frame = match frames.try_recv() {
+            Ok(frame) => frame,
+            Err(TryRecvError::Empty) => {
+                if let Err(e) = writer.flush() {
+                    error!("cannot flush pcap file: {}, capturing stopped", e);
+                    return;
+                }
+                match frames.recv() {
+                    Ok(frame) => frame,
+                    Err(_) => return,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        if let Err(e) = writer.write_frame(frame.tsc, &frame.bytes, frame.orig_len) {
+            error!("cannot write packet to pcap file: {}, capturing stopped", e);
+            return;
+        }
+        // if the pipeline has enough buffers, this one is freed
+        let _ = recycled.try_send(frame.bytes);
+    }
+    if let Err(e) = writer.flush() {
+        error!("cannot flush pcap file: {}", e);
+    }
+}
+
+/// captures the packets of selected connections of a pipeline into its own pcap file. The pipeline only copies the
+/// packets into a bounded queue, a writer thread outside of the core of the pipeline writes them to the file. Packets,
+/// which do not fit into the queue, are dropped and counted.
+pub struct PacketCapture {
+    frames: SyncSender<Frame>,
+    /// buffers of written frames for reuse
+    recycled: Receiver<Vec<u8>>,
+    snaplen: usize,
+    /// connections with a hash of their sockets modulo SAMPLE_RESOLUTION below this threshold are captured, None
+    /// captures all
+    sample_threshold: Option<u64>,
+    net: Option<Ipv4Net>,
+    port: Option<u16>,
+    max_packets: usize,
+    packets: usize,
+    /// packets not captured, because the queue was full
+    dropped: usize,
+}
+
+impl PacketCapture {
+    pub fn new(config: &CaptureConfig, pipeline_id: &PipelineId, cpu_clock: u64) -> io::Result<PacketCapture> {
+        let net = match config.net {
+            Some(ref net) => Some(
+                net.parse::<Ipv4Net>()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?,
+            ),
+            None => None,
+        };
+        let sample_ratio = config.sample_ratio();
+        if !(sample_ratio > 0.0 && sample_ratio <= 1.0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("sample_ratio {} is not in (0, 1]", sample_ratio),
+            ));
+        }
+        let file_name = format!("capture_{}_{}_{}.pcap", pipeline_id.core, pipeline_id.port_id, pipeline_id.rxq);
+        let path = Path::new(&config.dir()).join(file_name);
+        info!("{}: capturing packets into {}", pipeline_id, path.display());
+        let writer = PcapWriter::create(&path, config.snaplen(), cpu_clock)?;
+        let (frames_tx, frames_rx) = sync_channel(CAPTURE_QUEUE_SIZE);
+        let (recycled_tx, recycled_rx) = sync_channel(CAPTURE_QUEUE_SIZE);
+        thread::Builder::new()
+            .name(format!("pcap-{}", pipeline_id))
+            .spawn(move || write_frames(writer, frames_rx, recycled_tx))?;
+        Ok(PacketCapture {
+            frames: frames_tx,
+            recycled: recycled_rx,
+            snaplen: config.snaplen() as usize,
+            sample_threshold: if sample_ratio < 1.0 {
+                Some((sample_ratio * SAMPLE_RESOLUTION as f64).ceil() as u64)
+            } else {
+                None
+            },
+            net,
+            port: config.port,
+            max_packets: config.max_packets(),
+            packets: 0,
+            dropped: 0,
+        })
+    }
+
+    /// true, if the connection between the sockets a and b is captured. The decision does not depend on the direction,
+    /// so that both directions of a connection are captured. Sampling uses a hash of the sockets instead of a counter,
+    /// as this needs no state per connection, therefore the sample ratio is met only approximately.
+    #[inline]
+    pub fn selects(&self, a: (u32, u16), b: (u32, u16)) -> bool {
+        if self.packets >= self.max_packets {
+            return false;
+        }
+        if let Some(ref net) = self.net {
+            if !net.contains(&Ipv4Addr::from(a.0)) && !net.contains(&Ipv4Addr::from(b.0)) {
+                return false;
+            }
+        }
+        if self.port.map_or(false, |port| port != a.1 && port != b.1) {
+            return false;
+        }
+        if let Some(threshold) = self.sample_threshold {
+            let (low, high) = if a < b { (a, b) } else { (b, a) };
+            let mut hasher = FnvHasher::default();
+            hasher.write_u32(low.0);
+            hasher.write_u16(low.1);
+            hasher.write_u32(high.0);
+            hasher.write_u16(high.1);
+            hasher.finish() % SAMPLE_RESOLUTION < threshold
+        } else {
+            true
+        }
+    }
+
+    /// queues a copy of the packet for the writer thread, if its connection is selected
+    #[inline]
+    pub fn capture(&mut self, pdu: &Pdu) {
+        let src = (pdu.headers().ip(1).src(), pdu.headers().tcp(2).src_port());
+        let dst = (pdu.headers().ip(1).dst(), pdu.headers().tcp(2).dst_port());
+        if !self.selects(src, dst) {
+            return;
+        }
+        let mut header = [0u8; MAC_HEADER_SIZE];
+        {
+            let mac = pdu.headers().mac(0);
+            header[0..6].copy_from_slice(mac.dst.as_bytes());
+            header[6..12].copy_from_slice(mac.src.as_bytes());
+            header[12..14].copy_from_slice(&mac.etype().to_be_bytes());
+        }
+        let data = pdu.get_payload(0);
+        let mut bytes = self.recycled.try_recv().unwrap_or_else(|_| Vec::with_capacity(self.snaplen));
+        bytes.clear();
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(&data[..cmp::min(data.len(), self.snaplen.saturating_sub(MAC_HEADER_SIZE))]);
+        bytes.truncate(self.snaplen);
+        let frame = Frame {
+            tsc: unsafe { _rdtsc() },
+            orig_len: MAC_HEADER_SIZE + data.len(),
+            bytes,
+        };
+        match self.frames.try_send(frame) {
+            Ok(()) => self.packets += 1,
+            Err(TrySendError::Full(_)) => self.dropped += 1,
+            // the writer thread stopped after an error
+            Err(TrySendError::Disconnected(_)) => self.max_packets = 0,
+        }
+    }
+
+    /// number of packets, which were not captured, because the writer thread could not keep up
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
@@ -0,0 +1,479 @@
+use std::cmp;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+use PhaseConfig;
+
+/// rate, concurrency and payloads of the client side at a point in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoadTarget {
+    /// connections per second
+    pub cps: f64,
+    pub max_open: usize,
+    /// payloads per connection, None for fin_by_client
+    pub payloads: Option<usize>,
+}
+
+/// a phase with its defaults resolved, the durations are in cycles
+struct Phase {
+    name: String,
+    duration: u64,
+    cps: (f64, f64),
+    max_open: (f64, f64),
+    payloads: Option<(f64, f64)>,
+}
+
+/// a row of a load series: at second the whole engine opens cps connections per second, keeps at most concurrent
+/// client connections open and sends payloads_per_conn payloads per connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoadSample {
+    pub second: u64,
+    pub cps: f64,
+    pub concurrent: Option<f64>,
+    pub payloads_per_conn: Option<f64>,
+}
+
+/// a load series read from a CSV file, together with the number of pipelines which share the load
+#[derive(Debug, Clone)]
+pub struct LoadSeries {
+    pub samples: Vec<LoadSample>,
+    pub pipelines: usize,
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl LoadSeries {
+    /// reads rows of second,cps,concurrent,payloads_per_conn, the last two fields may be empty. A first line, which
+    /// does not start with a number, is a header. Lines starting with # are skipped. The seconds must ascend and
+    /// payloads_per_conn must be at least one.
+    pub fn from_csv(file: &str, pipelines: usize) -> io::Result<LoadSeries> {
+        let reader = BufReader::new(File::open(file)?);
+        let mut samples: Vec<LoadSample> = Vec::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+            if i == 0 && fields[0].parse::<u64>().is_err() {
+                // header
+                continue;
+            }
+            if fields.len() < 2 || fields.len() > 4 {
+                return Err(invalid_data(format!("line {}: expected 2 to 4 fields", i + 1)));
+            }
+            let optional = |j: usize| -> io::Result<Option<f64>> {
+                match fields.get(j) {
+                    Some(f) if !f.is_empty() => Ok(Some(
+                        f.parse().map_err(|e| invalid_data(format!("line {}: {}", i + 1, e)))?,
+                    )),
+                    _ => Ok(None),
+                }
+            };
+            let sample = LoadSample {
+                second: fields[0].parse().map_err(|e| invalid_data(format!("line {}: {}", i + 1, e)))?,
+                cps: fields[1].parse().map_err(|e| invalid_data(format!("line {}: {}", i + 1, e)))?,
+                concurrent: optional(2)?,
+                payloads_per_conn: optional(3)?,
+            };
+            if samples.last().map_or(false, |last| last.second >= sample.second) {
+                return Err(invalid_data(format!("line {}: seconds must ascend", i + 1)));
+            }
+            if let Some(payloads) = sample.payloads_per_conn {
+                if payloads.is_nan() || payloads < 1.0 {
+                    return Err(invalid_data(format!("line {}: less than one payload", i + 1)));
+                }
+            }
+            samples.push(sample);
+        }
+        if samples.len() < 2 {
+            return Err(invalid_data("a load series needs at least two rows"));
+        }
+        Ok(LoadSeries {
+            samples,
+            pipelines: cmp::max(pipelines, 1),
+        })
+    }
+}
+
+#[inline]
+fn interpolate(range: (f64, f64), elapsed: u64, duration: u64) -> f64 {
+    if duration == 0 || range.0 == range.1 {
+        return range.1;
+    }
+    let f = elapsed.min(duration) as f64 / duration as f64;
+    range.0 + (range.1 - range.0) * f
+}
+
+/// time-phased load profile of the client side of a pipeline. The phases run one after the other, beginning with
+/// the first SYN. Within a phase rate and concurrency are interpolated linearly between their start and end values.
+pub struct LoadProfile {
+    phases: Vec<Phase>,
+    /// index of the current phase, phases.len() after the last phase
+    current: usize,
+    /// TSC at the start of the current phase, zero before the profile started
+    phase_start: u64,
+}
+
+impl LoadProfile {
+    /// cps_limit and max_open of the engine are the defaults of the phases
+    pub fn new(configs: &[PhaseConfig], cps_limit: u64, max_open: usize, cpu_clock: u64) -> LoadProfile {
+        let phases = configs
+            .iter()
+            .enumerate()
+            .map(|(i, config)| {
+                let start_cps = config.start_cps.unwrap_or(cps_limit);
+                let start_concurrency = config.start_concurrency.unwrap_or(max_open);
+                Phase {
+                    name: config.id.clone().unwrap_or(format!("phase_{}", i)),
+                    duration: config.duration * cpu_clock / 1000,
+                    cps: (start_cps as f64, config.end_cps.unwrap_or(start_cps) as f64),
+                    max_open: (
+                        start_concurrency as f64,
+                        config.end_concurrency.unwrap_or(start_concurrency) as f64,
+                    ),
+                    payloads: None,
+                }
+            })
+            .collect();
+        LoadProfile {
+            phases,
+            current: 0,
+            phase_start: 0,
+        }
+    }
+
+    /// each pair of consecutive samples forms a phase, the load of a sample is split evenly across the pipelines.
+    /// max_open of the engine is the default of the concurrency.
+    pub fn from_series(series: &LoadSeries, max_open: usize, cpu_clock: u64) -> LoadProfile {
+        let n = series.pipelines as f64;
+        let phases = series
+            .samples
+            .windows(2)
+            .map(|pair| {
+                let (a, b) = (&pair[0], &pair[1]);
+                let concurrent = |s: &LoadSample| s.concurrent.map_or(max_open as f64, |c| (c / n).ceil());
+                Phase {
+                    name: format!("{}s", a.second),
+                    duration: (b.second - a.second) * cpu_clock,
+                    cps: (a.cps / n, b.cps / n),
+                    max_open: (concurrent(a), concurrent(b)),
+                    payloads: match (a.payloads_per_conn, b.payloads_per_conn) {
+                        (Some(pa), Some(pb)) => Some((pa, pb)),
+                        (Some(p), None) | (None, Some(p)) => Some((p, p)),
+                        (None, None) => None,
+                    },
+                }
+            })
+            .collect();
+        LoadProfile {
+            phases,
+            current: 0,
+            phase_start: 0,
+        }
+    }
+
+    /// the highest rate of all phases, at least one connection per second
+    pub fn max_cps(&self) -> u64 {
+        let max = self.phases.iter().map(|p| p.cps.0.max(p.cps.1)).fold(0.0, f64::max);
+        cmp::max(max.ceil() as u64, 1)
+    }
+
+    pub fn started(&self) -> bool {
+        self.phase_start > 0
+    }
+
+    pub fn start(&mut self, now: u64) {
+        self.phase_start = now;
+    }
+
+    /// true after the last phase
+    pub fn finished(&self) -> bool {
+        self.current >= self.phases.len()
+    }
+
+    pub fn phase_name(&self, i: usize) -> &str {
+        &self.phases[i].name
+    }
+
+    /// index of the current phase
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// moves on to the phase, which covers now, and returns the indices of the phases which ended in between
+    pub fn advance(&mut self, now: u64) -> Vec<usize> {
+        let mut ended = Vec::new();
+        while !self.finished() && now >= self.phase_start + self.phases[self.current].duration {
+            self.phase_start += self.phases[self.current].duration;
+            ended.push(self.current);
+            self.current += 1;
+        }
+        ended
+    }
+
+    /// the target within the current phase at now, after the last phase no connections are opened. A connection sends
+    /// one payload at least.
+    pub fn target(&self, now: u64) -> LoadTarget {
+        match self.phases.get(self.current) {
+            Some(phase) => {
+                let elapsed = now.saturating_sub(self.phase_start);
+                LoadTarget {
+                    cps: interpolate(phase.cps, elapsed, phase.duration),
+                    max_open: interpolate(phase.max_open, elapsed, phase.duration).round() as usize,
+                    payloads: phase
+                        .payloads
+                        .map(|payloads| cmp::max(interpolate(payloads, elapsed, phase.duration).round() as usize, 1)),
+                }
+            }
+            None => LoadTarget {
+                cps: 0.0,
+                max_open: 0,
+                payloads: None,
+            },
+        }
+    }
+}
+
+/// names of the sections of a run with a duration
+const SECTIONS: [&str; 3] = ["warm-up", "steady state", "cool-down"];
+
+/// a run of a fixed duration: the warm-up, the steady state, which is measured, and the cool-down, in which no new
+/// connections are opened. The run starts with the first SYN.
+pub struct RunWindow {
+    /// ends of the sections in cycles after the start
+    ends: [u64; 3],
+    /// TSC at the start, zero before
+    start: u64,
+    /// index of the current section, 3 after the end
+    current: usize,
+}
+
+impl RunWindow {
+    /// times in millis
+    pub fn new(warm_up: u64, duration: u64, cool_down: u64, cpu_clock: u64) -> RunWindow {
+        let cycles = |ms: u64| ms * cpu_clock / 1000;
+        RunWindow {
+            ends: [
+                cycles(warm_up),
+                cycles(warm_up + duration),
+                cycles(warm_up + duration + cool_down),
+            ],
+            start: 0,
+            current: 0,
+        }
+    }
+
+    pub fn started(&self) -> bool {
+        self.start > 0
+    }
+
+    pub fn start(&mut self, now: u64) {
+        self.start = now;
+    }
+
+    /// true after the steady state
+    pub fn cooling_down(&self) -> bool {
+        self.current >= 2
+    }
+
+    pub fn finished(&self) -> bool {
+        self.current >= SECTIONS.len()
+    }
+
+    pub fn section_name(&self, i: usize) -> &'static str {
+        SECTIONS[i]
+    }
+
+    /// index of the current section
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// moves on to the section, which covers now, and returns the indices of the sections which ended in between
+    pub fn advance(&mut self, now: u64) -> Vec<usize> {
+        let mut ended = Vec::new();
+        while self.started() && !self.finished() && now >= self.start + self.ends[self.current] {
+            ended.push(self.current);
+            self.current += 1;
+        }
+        ended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    /// one cycle per milli
+    const CPU_CLOCK: u64 = 1000;
+
+    fn phase(duration: u64, cps: (Option<u64>, Option<u64>), concurrency: (Option<usize>, Option<usize>)) -> PhaseConfig {
+        PhaseConfig {
+            id: None,
+            duration,
+            start_cps: cps.0,
+            end_cps: cps.1,
+            start_concurrency: concurrency.0,
+            end_concurrency: concurrency.1,
+        }
+    }
+
+    fn ramp_profile() -> LoadProfile {
+        let mut ramp = phase(1000, (Some(100), Some(300)), (Some(10), Some(30)));
+        ramp.id = Some("ramp".to_string());
+        LoadProfile::new(&[ramp, phase(500, (None, None), (None, None))], 50, 20, CPU_CLOCK)
+    }
+
+    #[test]
+    fn target_is_interpolated() {
+        let mut profile = ramp_profile();
+        assert_eq!((profile.phase_name(0), profile.phase_name(1)), ("ramp", "phase_1"));
+        assert_eq!(profile.max_cps(), 300);
+        profile.start(100);
+        assert_eq!(profile.target(100).cps, 100.0);
+        assert_eq!(profile.target(100).max_open, 10);
+        assert_eq!(profile.target(600).cps, 200.0);
+        assert_eq!(profile.target(850).max_open, 25);
+        assert_eq!(profile.target(600).payloads, None);
+    }
+
+    #[test]
+    fn advance_moves_through_the_phases() {
+        let mut profile = ramp_profile();
+        profile.start(100);
+        assert!(profile.advance(1099).is_empty());
+        assert_eq!(profile.advance(1100), vec![0]);
+        assert_eq!(profile.current(), 1);
+        // the engine defaults apply
+        assert_eq!(profile.target(1300).cps, 50.0);
+        assert_eq!(profile.target(1300).max_open, 20);
+        assert_eq!(profile.advance(1600), vec![1]);
+        assert!(profile.finished());
+        assert_eq!(profile.target(1600).cps, 0.0);
+        assert_eq!(profile.target(1600).max_open, 0);
+
+        // phases, which ended in between, are reported together
+        let mut profile = ramp_profile();
+        profile.start(100);
+        assert_eq!(profile.advance(5000), vec![0, 1]);
+        assert!(profile.finished());
+    }
+
+    #[test]
+    fn series_is_split_across_the_pipelines() {
+        let sample = |second, cps, concurrent, payloads_per_conn| LoadSample {
+            second,
+            cps,
+            concurrent,
+            payloads_per_conn,
+        };
+        let series = LoadSeries {
+            samples: vec![
+                sample(0, 100.0, Some(40.0), Some(2.0)),
+                sample(10, 300.0, None, None),
+                sample(20, 300.0, Some(7.0), Some(4.0)),
+            ],
+            pipelines: 2,
+        };
+        let mut profile = LoadProfile::from_series(&series, 50, CPU_CLOCK);
+        assert_eq!((profile.phase_name(0), profile.phase_name(1)), ("0s", "10s"));
+        assert_eq!(profile.max_cps(), 150);
+        profile.start(1);
+        let target = profile.target(5001);
+        assert_eq!(target.cps, 100.0);
+        // max_open of the engine applies to the sample without concurrency
+        assert_eq!(target.max_open, 35);
+        assert_eq!(target.payloads, Some(2));
+        assert_eq!(profile.advance(10_001), vec![0]);
+        let target = profile.target(20_001);
+        assert_eq!(target.cps, 150.0);
+        // the concurrency is rounded up per pipeline
+        assert_eq!(target.max_open, 4);
+        assert_eq!(target.payloads, Some(4));
+    }
+
+    /// writes the lines to a file in the temporary directory and reads it as load series
+    fn read_series(name: &str, lines: &[&str]) -> io::Result<LoadSeries> {
+        let path = env::temp_dir().join(format!("load_series_{}", name));
+        let file = path.to_string_lossy();
+        fs::write(&path, lines.join("\n")).unwrap();
+        let series = LoadSeries::from_csv(&file, 2);
+        fs::remove_file(&path).unwrap();
+        series
+    }
+
+    fn error(name: &str, lines: &[&str]) -> String {
+        let e = read_series(name, lines).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        e.to_string()
+    }
+
+    #[test]
+    fn series_is_read_from_csv() {
+        let series = read_series("valid", &["second,cps,concurrent", "# ramp", "", "0,100,,2", "10, 300, 40"]).unwrap();
+        assert_eq!(series.pipelines, 2);
+        assert_eq!(
+            series.samples,
+            vec![
+                LoadSample {
+                    second: 0,
+                    cps: 100.0,
+                    concurrent: None,
+                    payloads_per_conn: Some(2.0),
+                },
+                LoadSample {
+                    second: 10,
+                    cps: 300.0,
+                    concurrent: Some(40.0),
+                    payloads_per_conn: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_series_are_rejected() {
+        assert_eq!(error("fields", &["0,100", "10"]), "line 2: expected 2 to 4 fields");
+        assert_eq!(error("number", &["0,100", "10,fast"]), "line 2: invalid float literal");
+        // only the first line may be a header
+        assert_eq!(
+            error("header", &["# comment", "second,cps", "0,100", "10,100"]),
+            "line 2: invalid digit found in string"
+        );
+        assert_eq!(error("ascend", &["0,100", "10,100", "10,200"]), "line 3: seconds must ascend");
+        assert_eq!(error("payloads", &["0,100,,0", "10,100"]), "line 1: less than one payload");
+        assert_eq!(
+            error("rows", &["second,cps", "0,100"]),
+            "a load series needs at least two rows"
+        );
+    }
+
+    #[test]
+    fn run_window_advances_through_the_sections() {
+        let mut window = RunWindow::new(100, 1000, 200, CPU_CLOCK);
+        // the window starts with the first SYN
+        assert!(window.advance(5000).is_empty());
+        window.start(10);
+        assert!(window.advance(109).is_empty());
+        assert_eq!(window.advance(110), vec![0]);
+        assert!(!window.cooling_down());
+        assert_eq!(window.advance(1110), vec![1]);
+        assert_eq!(window.section_name(window.current()), "cool-down");
+        assert!(window.cooling_down() && !window.finished());
+        assert_eq!(window.advance(1310), vec![2]);
+        assert!(window.finished());
+        assert!(window.advance(5000).is_empty());
+
+        // without warm-up and cool-down
+        let mut window = RunWindow::new(0, 1000, 0, CPU_CLOCK);
+        window.start(10);
+        assert_eq!(window.advance(10), vec![0]);
+        assert_eq!(window.advance(1010), vec![1, 2]);
+        assert!(window.finished());
+    }
+}
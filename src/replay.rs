@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use e2d2::interface::Pdu;
+
+use netfcts::tcp_common::{TcpState, tcp_payload_size};
+use netfcts::strip_payload;
+
+use cmanager::Connection;
+use tcp_options::set_payload;
+use ReplayConfig;
+
+/// magic numbers of pcap files with micro- and nanosecond time stamps
+const PCAP_MAGIC_USEC: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NSEC: u32 = 0xa1b2_3c4d;
+const PCAPNG_MAGIC: u32 = 0x0a0d_0d0a;
+const LINKTYPE_ETHERNET: u32 = 1;
+const PCAP_HEADER_SIZE: usize = 24;
+const RECORD_HEADER_SIZE: usize = 16;
+const ETYPE_VLAN: u16 = 0x8100;
+const ETYPE_IPV4: u16 = 0x0800;
+const IP_PROTOCOL_TCP: u8 = 6;
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_FLAG_ACK: u8 = 0x10;
+/// segment size, if the MSS of the DUT is unknown
+const DEFAULT_MSS: usize = 1460;
+
+type Socket = (u32, u16);
+
+/// client payload, which is sent when the DUT has sent server_bytes payload bytes in total
+#[derive(Clone, Debug)]
+struct Turn {
+    /// range of the payload in the client data
+    start: usize,
+    end: usize,
+    server_bytes: u32,
+}
+
+/// the client side of a captured TCP stream, split into turns
+#[derive(Clone, Debug)]
+pub struct Conversation {
+    client_data: Vec<u8>,
+    turns: Vec<Turn>,
+    /// payload bytes of the DUT, before the client closes the connection
+    fin_server_bytes: u32,
+}
+
+impl Conversation {
+    /// the turn, which contains the client data at offset
+    #[inline]
+    fn turn(&self, offset: usize) -> Option<&Turn> {
+        let i = match self.turns.binary_search_by_key(&offset, |t| t.end) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+        self.turns.get(i)
+    }
+
+    pub fn client_bytes(&self) -> usize {
+        self.client_data.len()
+    }
+
+    pub fn turns(&self) -> usize {
+        self.turns.len()
+    }
+}
+
+/// a TCP segment of a stream
+struct Segment {
+    src: Socket,
+    seq: u32,
+    flags: u8,
+    payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Stream {
+    /// sockets of the first segment
+    first: Option<(Socket, Socket)>,
+    segments: Vec<Segment>,
+}
+
+impl Stream {
+    /// the client is the sender of the first SYN, otherwise the sender of the first segment
+    fn client(&self) -> Option<Socket> {
+        self.segments
+            .iter()
+            .find(|s| s.flags & TCP_FLAG_SYN != 0 && s.flags & TCP_FLAG_ACK == 0)
+            .map(|s| s.src)
+            .or(self.first.map(|f| f.0))
+    }
+
+    /// takes the payload of each direction in sequence, retransmitted bytes are skipped
+    fn conversation(&self) -> Conversation {
+        let client = self.client();
+        let mut next_seq: [Option<u32>; 2] = [None, None];
+        let mut client_data = Vec::new();
+        let mut turns: Vec<Turn> = Vec::new();
+        let mut server_bytes = 0u32;
+        let mut fin_server_bytes = None;
+        for s in &self.segments {
+            let dir = if Some(s.src) == client { 0 } else { 1 };
+            if s.flags & TCP_FLAG_SYN != 0 {
+                next_seq[dir] = Some(s.seq.wrapping_add(1));
+                continue;
+            }
+            let next = next_seq[dir].unwrap_or(s.seq);
+            let offset = next.wrapping_sub(s.seq) as i32;
+            let payload: &[u8] = if offset < 0 {
+                warn!("replay: {} bytes are missing in the capture", -offset);
+                &s.payload
+            } else if (offset as usize) < s.payload.len() {
+                &s.payload[offset as usize..]
+            } else {
+                &[]
+            };
+            if offset < 0 || !payload.is_empty() {
+                next_seq[dir] = Some(s.seq.wrapping_add(s.payload.len() as u32));
+            } else if next_seq[dir].is_none() {
+                next_seq[dir] = Some(next);
+            }
+            if dir == 0 {
+                if !payload.is_empty() {
+                    if turns.last().map_or(true, |t| t.server_bytes != server_bytes) {
+                        turns.push(Turn {
+                            start: client_data.len(),
+                            end: client_data.len(),
+                            server_bytes,
+                        });
+                    }
+                    client_data.extend_from_slice(payload);
+                    turns.last_mut().unwrap().end = client_data.len();
+                }
+                if s.flags & TCP_FLAG_FIN != 0 && fin_server_bytes.is_none() {
+                    fin_server_bytes = Some(server_bytes);
+                }
+            } else {
+                server_bytes = server_bytes.wrapping_add(payload.len() as u32);
+            }
+        }
+        Conversation {
+            client_data,
+            turns,
+            fin_server_bytes: fin_server_bytes.unwrap_or(server_bytes),
+        }
+    }
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[inline]
+fn be16(b: &[u8]) -> u16 {
+    (b[0] as u16) << 8 | b[1] as u16
+}
+
+#[inline]
+fn be32(b: &[u8]) -> u32 {
+    (be16(b) as u32) << 16 | be16(&b[2..]) as u32
+}
+
+/// the TCP segment in an Ethernet frame, None for other frames
+fn parse_frame(frame: &[u8]) -> Option<(Socket, Socket, Segment)> {
+    let mut l3 = 14;
+    if frame.len() < l3 {
+        return None;
+    }
+    let mut etype = be16(&frame[12..14]);
+    if etype == ETYPE_VLAN && frame.len() >= l3 + 4 {
+        etype = be16(&frame[16..18]);
+        l3 += 4;
+    }
+    if etype != ETYPE_IPV4 || frame.len() < l3 + 20 {
+        return None;
+    }
+    let ip = &frame[l3..];
+    let ihl = ((ip[0] & 0x0f) as usize) * 4;
+    // the total length of the IP packet excludes the padding of short frames
+    let ip_len = (be16(&ip[2..4]) as usize).min(ip.len());
+    if ip[0] >> 4 != 4 || ip[9] != IP_PROTOCOL_TCP || ip_len < ihl + 20 {
+        return None;
+    }
+    // fragments are ignored
+    if be16(&ip[6..8]) & 0x3fff != 0 {
+        return None;
+    }
+    let tcp = &ip[ihl..ip_len];
+    let data_offset = ((tcp[12] >> 4) as usize) * 4;
+    if tcp.len() < data_offset {
+        return None;
+    }
+    let src = (be32(&ip[12..16]), be16(&tcp[0..2]));
+    let dst = (be32(&ip[16..20]), be16(&tcp[2..4]));
+    Some((
+        src,
+        dst,
+        Segment {
+            src,
+            seq: be32(&tcp[4..8]),
+            flags: tcp[13],
+            payload: tcp[data_offset..].to_vec(),
+        },
+    ))
+}
+
+/// reads all TCP streams of a pcap file with Ethernet frames, the streams are ordered by their first packet
+fn read_streams(file: &str) -> io::Result<Vec<Stream>> {
+    let mut buf = Vec::new();
+    File::open(file)?.read_to_end(&mut buf)?;
+    if buf.len() < PCAP_HEADER_SIZE {
+        return Err(invalid_data("file too short for a pcap header"));
+    }
+    let magic = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let little_endian = match magic {
+        PCAP_MAGIC_USEC | PCAP_MAGIC_NSEC => true,
+        m if m.swap_bytes() == PCAP_MAGIC_USEC || m.swap_bytes() == PCAP_MAGIC_NSEC => false,
+        PCAPNG_MAGIC => return Err(invalid_data("pcapng is not supported, convert the file to pcap")),
+        _ => return Err(invalid_data("not a pcap file")),
+    };
+    let u32_at = |i: usize| {
+        let b = [buf[i], buf[i + 1], buf[i + 2], buf[i + 3]];
+        if little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        }
+    };
+    let link_type = u32_at(20);
+    if link_type != LINKTYPE_ETHERNET {
+        return Err(invalid_data(format!("link type {} is not supported", link_type)));
+    }
+
+    let mut streams: Vec<Stream> = Vec::new();
+    let mut index: HashMap<(Socket, Socket), usize> = HashMap::new();
+    let mut pos = PCAP_HEADER_SIZE;
+    let mut packet = 0;
+    while pos + RECORD_HEADER_SIZE <= buf.len() {
+        let incl_len = u32_at(pos + 8) as usize;
+        let orig_len = u32_at(pos + 12) as usize;
+        pos += RECORD_HEADER_SIZE;
+        if pos + incl_len > buf.len() {
+            return Err(invalid_data(format!("packet {} is cut off", packet)));
+        }
+        if let Some((src, dst, segment)) = parse_frame(&buf[pos..pos + incl_len]) {
+            if incl_len < orig_len && !segment.payload.is_empty() {
+                return Err(invalid_data(format!("payload of packet {} is truncated by the snaplen", packet)));
+            }
+            let key = if src < dst { (src, dst) } else { (dst, src) };
+            let n = streams.len();
+            let i = *index.entry(key).or_insert(n);
+            if i == n {
+                streams.push(Stream {
+                    first: Some((src, dst)),
+                    ..Default::default()
+                });
+            }
+            streams[i].segments.push(segment);
+        }
+        pos += incl_len;
+        packet += 1;
+    }
+    Ok(streams)
+}
+
+/// loads the conversations of the selected streams
+pub fn load_conversations(config: &ReplayConfig) -> io::Result<Vec<Conversation>> {
+    let streams = read_streams(&config.file)?;
+    let selected: Vec<usize> = match config.streams {
+        Some(ref indices) => indices.clone(),
+        None => (0..streams.len()).collect(),
+    };
+    let mut conversations = Vec::with_capacity(selected.len());
+    for i in selected {
+        let stream = streams
+            .get(i)
+            .ok_or(invalid_data(format!("there is no stream {} in {}", i, config.file)))?;
+        let conversation = stream.conversation();
+        let (a, b) = stream.first.unwrap();
+        if conversation.client_data.is_empty() {
+            // nothing to replay
+            if config.streams.is_some() {
+                warn!("replay: stream {} has no client payload", i);
+            }
+            continue;
+        }
+        info!(
+            "replay: stream {} ({} <-> {}): {} client bytes in {} turns",
+            i,
+            SocketAddrV4::new(Ipv4Addr::from(a.0), a.1),
+            SocketAddrV4::new(Ipv4Addr::from(b.0), b.1),
+            conversation.client_bytes(),
+            conversation.turns()
+        );
+        conversations.push(conversation);
+    }
+    if conversations.is_empty() {
+        return Err(invalid_data(format!("no TCP stream with client payload in {}", config.file)));
+    }
+    Ok(conversations)
+}
+
+/// payload function of the replay: the connections are assigned round robin by their port to the conversations. The
+/// next client payload is sent, when the DUT has sent at least as many payload bytes as the server in the capture. As
+/// long as the DUT falls short, the returned payload is empty. If more payload can be sent right away, the payload is
+/// marked as pending on the connection.
+pub fn set_replay_payload(p: &mut Pdu, c: &mut Connection, conversations: &[Conversation], b_fin: &mut bool) -> usize {
+    let conversation = &conversations[c.port() as usize % conversations.len()];
+    strip_payload(p);
+    c.set_payload_pending(false);
+    if c.state() >= TcpState::CloseWait {
+        return 0;
+    }
+    let sent = c.sent_payload_bytes() as usize;
+    let received = c.recv_payload_bytes();
+    let (start, end) = match conversation.turn(sent) {
+        Some(turn) => {
+            if received < turn.server_bytes {
+                return 0;
+            }
+            // the negotiated MSS does not exceed our own MSS
+            let mss = c.snd_mss().map_or(DEFAULT_MSS, |mss| mss as usize);
+            (sent, turn.end.min(sent + mss))
+        }
+        None => {
+            *b_fin = received >= conversation.fin_server_bytes;
+            return 0;
+        }
+    };
+    let data = &conversation.client_data[start..end];
+    if let Err(e) = set_payload(p, data) {
+        error!("replay: cannot send {} bytes on port {}: {}", data.len(), c.port(), e);
+        return 0;
+    }
+    let pending = match conversation.turn(end) {
+        Some(turn) => received >= turn.server_bytes,
+        None => received >= conversation.fin_server_bytes,
+    };
+    c.set_payload_pending(pending);
+    tcp_payload_size(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: Socket = (0x0a00_0001, 40000);
+    const SERVER: Socket = (0x0a00_0002, 80);
+
+    fn segment(src: Socket, seq: u32, flags: u8, payload: &[u8]) -> Segment {
+        Segment {
+            src,
+            seq,
+            flags,
+            payload: payload.to_vec(),
+        }
+    }
+
+    fn stream(segments: Vec<Segment>) -> Stream {
+        Stream {
+            first: Some((segments[0].src, if segments[0].src == CLIENT { SERVER } else { CLIENT })),
+            segments,
+        }
+    }
+
+    fn turns(conversation: &Conversation) -> Vec<(usize, usize, u32)> {
+        conversation.turns.iter().map(|t| (t.start, t.end, t.server_bytes)).collect()
+    }
+
+    #[test]
+    fn retransmitted_bytes_are_skipped() {
+        let conversation = stream(vec![
+            segment(CLIENT, 100, TCP_FLAG_SYN, b""),
+            segment(SERVER, 500, TCP_FLAG_SYN | TCP_FLAG_ACK, b""),
+            segment(CLIENT, 101, TCP_FLAG_ACK, b"GET"),
+            // retransmission
+            segment(CLIENT, 101, TCP_FLAG_ACK, b"GET"),
+            segment(SERVER, 501, TCP_FLAG_ACK, b"hello"),
+            // retransmission, which overlaps new data
+            segment(CLIENT, 103, TCP_FLAG_ACK, b"Tab"),
+            segment(SERVER, 506, TCP_FLAG_ACK, b"done"),
+            segment(SERVER, 501, TCP_FLAG_ACK, b"hello"),
+            segment(CLIENT, 106, TCP_FLAG_ACK | TCP_FLAG_FIN, b""),
+        ])
+        .conversation();
+        assert_eq!(conversation.client_data, b"GETab".to_vec());
+        assert_eq!(turns(&conversation), vec![(0, 3, 0), (3, 5, 5)]);
+        assert_eq!(conversation.fin_server_bytes, 9);
+    }
+
+    #[test]
+    fn missing_bytes_are_skipped() {
+        // the capture starts after the handshake, the client is the sender of the first segment
+        let conversation = stream(vec![
+            segment(CLIENT, 1000, TCP_FLAG_ACK, b"abc"),
+            segment(CLIENT, 1010, TCP_FLAG_ACK, b"xy"),
+            segment(SERVER, 7, TCP_FLAG_ACK, b"ok"),
+            segment(CLIENT, 1012, TCP_FLAG_ACK, b"z"),
+        ])
+        .conversation();
+        assert_eq!(conversation.client_data, b"abcxyz".to_vec());
+        assert_eq!(turns(&conversation), vec![(0, 5, 0), (5, 6, 2)]);
+        // the client did not close the connection
+        assert_eq!(conversation.fin_server_bytes, 2);
+    }
+
+    #[test]
+    fn client_is_the_sender_of_the_syn() {
+        let s = stream(vec![
+            segment(SERVER, 500, TCP_FLAG_ACK, b""),
+            segment(CLIENT, 100, TCP_FLAG_SYN, b""),
+        ]);
+        assert_eq!(s.client(), Some(CLIENT));
+    }
+
+    #[test]
+    fn turn_contains_the_offset() {
+        let conversation = Conversation {
+            client_data: b"GETab".to_vec(),
+            turns: vec![
+                Turn {
+                    start: 0,
+                    end: 3,
+                    server_bytes: 0,
+                },
+                Turn {
+                    start: 3,
+                    end: 5,
+                    server_bytes: 5,
+                },
+            ],
+            fin_server_bytes: 9,
+        };
+        let server_bytes = |offset| conversation.turn(offset).map(|t| t.server_bytes);
+        assert_eq!(server_bytes(0), Some(0));
+        assert_eq!(server_bytes(2), Some(0));
+        assert_eq!(server_bytes(3), Some(5));
+        assert_eq!(server_bytes(4), Some(5));
+        assert_eq!(server_bytes(5), None);
+        assert_eq!(server_bytes(6), None);
+    }
+}
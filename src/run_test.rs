@@ -11,8 +11,7 @@ use std::thread;
 use std::net::{SocketAddr, SocketAddrV4, TcpListener, TcpStream, Shutdown, Ipv4Addr};
 use std::sync::mpsc::RecvTimeoutError;
 use std::collections::HashMap;
-use std::io::{Read, Write, BufWriter};
-use std::fs::File;
+use std::io::{Read, Write};
 use std::vec::Vec;
 use std::process;
 
@@ -25,7 +24,6 @@ use separator::Separatable;
 use netfcts::RunTime;
 use netfcts::comm::PipelineId;
 use netfcts::conrecord::HasTcpState;
-use netfcts::system::get_mac_from_ifname;
 use netfcts::io::print_tcp_counters;
 #[cfg(feature = "profiling")]
 use netfcts::io::print_rx_tx_counters;
@@ -33,8 +31,10 @@ use netfcts::strip_payload;
 use netfcts::tcp_common::tcp_payload_size;
 
 use setup_pipelines;
-use {CData, L234Data, Connection, Configuration};
+use nftraffic::check_services;
+use {CData, Connection, Configuration, Targets};
 use {MessageFrom, MessageTo};
+use export::RecordWriter;
 use ReleaseCause;
 use {TcpState, TcpStatistics};
 use netfcts::recstore::TEngineStore;
@@ -85,20 +85,7 @@ pub fn run_test(test_type: TestType) {
     })
     .expect("error setting Ctrl-C handler");
 
-    let l234data: Vec<L234Data> = configuration
-        .targets
-        .iter()
-        .enumerate()
-        .map(|(i, srv_cfg)| L234Data {
-            mac: srv_cfg
-                .mac
-                .unwrap_or_else(|| get_mac_from_ifname(srv_cfg.linux_if.as_ref().unwrap()).unwrap()),
-            ip: u32::from(srv_cfg.ip),
-            port: srv_cfg.port,
-            server_id: srv_cfg.id.clone(),
-            index: i,
-        })
-        .collect();
+    let targets = Targets::from_config(&configuration.targets);
 
     let fin_by_client_clone = fin_by_client.clone();
     let f_set_payload = Box::new(
@@ -137,7 +124,10 @@ pub fn run_test(test_type: TestType) {
 
     run_time.start_schedulers().expect("cannot start schedulers");
 
-    let run_configuration_cloned = run_configuration.clone();
+    let mut run_configuration_cloned = run_configuration.clone();
+    // the test evaluates all connection records at its end
+    run_configuration_cloned.engine_configuration.engine.record_streaming = None;
+    let targets_cloned = targets.clone();
 
     run_time
         .install_pipeline_on_cores(Box::new(
@@ -147,7 +137,7 @@ pub fn run_test(test_type: TestType) {
                     pmd_ports,
                     s,
                     run_configuration_cloned.clone(),
-                    l234data.clone(),
+                    targets_cloned.clone(),
                     f_set_payload.clone(),
                 );
             },
@@ -203,10 +193,10 @@ pub fn run_test(test_type: TestType) {
 
     if test_type == TestType::Client {
         // set up servers
-        for server in run_configuration.engine_configuration.targets.clone() {
+        for server in targets.iter() {
             let target_port = server.port; // moved into thread
-            let target_ip = server.ip;
-            let id = server.id;
+            let target_ip = Ipv4Addr::from(server.ip);
+            let id = server.server_id;
             thread::spawn(move || match TcpListener::bind((target_ip, target_port)) {
                 Ok(listener1) => {
                     debug!("bound server {} to {}:{}", id, target_ip, target_port);
@@ -240,6 +230,8 @@ pub fn run_test(test_type: TestType) {
 
         thread::sleep(Duration::from_millis(1000 as u64)); // wait for the servers
     }
+    assert!(check_services(&configuration.engine), "services without a pipeline");
+
     // start generator
 
     let (mtx, reply_mrx) = run_time.get_main_channel().expect("cannot get main channel");
@@ -349,11 +341,16 @@ pub fn run_test(test_type: TestType) {
         .detailed_records
         .unwrap_or(false)
     {
-        let file = match File::create("c_records.txt") {
-            Err(why) => panic!("couldn't create c_records.txt: {}", why),
-            Ok(file) => file,
+        let record_export = run_configuration
+            .engine_configuration
+            .engine
+            .record_export
+            .clone()
+            .unwrap_or_default();
+        let mut writer = match RecordWriter::create(&record_export) {
+            Err(why) => panic!("couldn't create {}: {}", record_export.file(), why),
+            Ok(writer) => writer,
         };
-        let mut f = BufWriter::new(file);
 
         assert_ne!(con_records.len(), 0);
         if test_type == TestType::Server {
@@ -364,8 +361,7 @@ pub fn run_test(test_type: TestType) {
                         let mut min = c_records.iter().last().unwrap();
                         let mut max = min;
                         c_records.iter().enumerate().for_each(|(i, c)| {
-                            let line = format!("{:6}: {}\n", i, c);
-                            f.write_all(line.as_bytes()).expect("cannot write c_records");
+                            writer.write(p, c, None).expect("cannot write c_records");
                             if c.states().last().unwrap() == &TcpState::Closed {
                                 completed_count += 1
                             }
@@ -399,13 +395,11 @@ pub fn run_test(test_type: TestType) {
             for (p, (c_records, _)) in &con_records {
                 let mut completed_count = 0;
                 info!("Pipeline {}:", p);
-                f.write_all(format!("Pipeline {}:\n", p).as_bytes())
-                    .expect("cannot write c_records");
-                c_records.as_ref().unwrap().iter().enumerate().for_each(|(i, c)| {
-                    let line = format!("{:6}: {}\n", i, c);
-                    f.write_all(line.as_bytes()).expect("cannot write c_records");
+                c_records.as_ref().unwrap().iter().for_each(|c| {
+                    writer.write(p, c, None).expect("cannot write c_records");
                     if (c.release_cause() == ReleaseCause::PassiveClose || c.release_cause() == ReleaseCause::ActiveClose)
-                        && c.states().last().unwrap() == &TcpState::Closed
+                        && (c.states().last().unwrap() == &TcpState::Closed
+                            || c.states().last().unwrap() == &TcpState::TimeWait)
                     {
                         completed_count += 1
                     };
@@ -415,7 +409,7 @@ pub fn run_test(test_type: TestType) {
             }
         }
 
-        f.flush().expect("cannot flush BufWriter");
+        writer.flush().expect("cannot flush c_records");
     }
     if test_type == TestType::Server {
         for (p, _) in &tcp_counters_from {
@@ -0,0 +1,334 @@
+use std::ops::{Index, IndexMut};
+use std::fmt;
+use std::cmp;
+
+use netfcts::comm::PipelineId;
+use netfcts::tcp_common::{TcpCounter, TcpStatistics};
+
+/// engine specific statistics, which complement the generic TcpStatistics of netfcts::tcp_common::TcpCounter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineStatistics {
+    RetransmittedSyn = 0,
+    RetransmittedPayload = 1,
+    RetransmittedFin = 2,
+    /// connections given up after the last retransmission of a segment timed out
+    RetransmissionLimit = 3,
+    /// payload held back, because it does not fit into the send window of the peer or the congestion window
+    WindowBlocked = 4,
+    ZeroWindowProbe = 5,
+    /// retransmissions triggered by duplicate or partial ACKs
+    FastRetransmit = 6,
+    /// segments received ahead of a gap, each is answered by a duplicate ACK
+    OutOfOrder = 7,
+    /// out-of-order segments dropped, because the reassembly queue was full
+    ReassemblyOverflow = 8,
+    /// SYNs answered statelessly with a cookie, respectively final ACKs with a cookie which did not validate
+    SynCookie = 9,
+    InvalidSynCookie = 10,
+    /// connections refused, because the server side connection table reached its maximum capacity
+    ServerTableFull = 11,
+    /// connections not recorded, respectively connection attempts refused, because all record buffers are in use
+    RecordsDropped = 12,
+    RecordsBlocked = 13,
+    /// arrivals of client connections, which fell due while max_open connections were open
+    ArrivalsSkipped = 14,
+    /// retransmissions skipped, because the segment did not fit into the tail room of the packet
+    RetransmissionSkipped = 15,
+    Count = 16,
+}
+
+impl EngineStatistics {
+    pub fn iter() -> impl Iterator<Item = EngineStatistics> {
+        [
+            EngineStatistics::RetransmittedSyn,
+            EngineStatistics::RetransmittedPayload,
+            EngineStatistics::RetransmittedFin,
+            EngineStatistics::RetransmissionLimit,
+            EngineStatistics::WindowBlocked,
+            EngineStatistics::ZeroWindowProbe,
+            EngineStatistics::FastRetransmit,
+            EngineStatistics::OutOfOrder,
+            EngineStatistics::ReassemblyOverflow,
+            EngineStatistics::SynCookie,
+            EngineStatistics::InvalidSynCookie,
+            EngineStatistics::ServerTableFull,
+            EngineStatistics::RecordsDropped,
+            EngineStatistics::RecordsBlocked,
+            EngineStatistics::ArrivalsSkipped,
+            EngineStatistics::RetransmissionSkipped,
+        ]
+        .iter()
+        .cloned()
+    }
+}
+
+impl fmt::Display for EngineStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EngineCounter([usize; EngineStatistics::Count as usize]);
+
+impl EngineCounter {
+    pub fn new() -> EngineCounter {
+        EngineCounter([0; EngineStatistics::Count as usize])
+    }
+}
+
+impl Index<EngineStatistics> for EngineCounter {
+    type Output = usize;
+
+    #[inline]
+    fn index(&self, stat: EngineStatistics) -> &usize {
+        &self.0[stat as usize]
+    }
+}
+
+impl IndexMut<EngineStatistics> for EngineCounter {
+    #[inline]
+    fn index_mut(&mut self, stat: EngineStatistics) -> &mut usize {
+        &mut self.0[stat as usize]
+    }
+}
+
+pub fn print_engine_counters(pipeline_id: &PipelineId, counter_to: &EngineCounter, counter_from: &EngineCounter) {
+    println!("{}: engine counters (client / server side)", pipeline_id);
+    for stat in EngineStatistics::iter() {
+        println!("{:>28} = {:9} / {:9}", stat.to_string(), counter_to[stat], counter_from[stat]);
+    }
+}
+
+/// TCP counters which are reported per phase of a load profile
+const PHASE_TCP_STATISTICS: [TcpStatistics; 10] = [
+    TcpStatistics::SentSyn,
+    TcpStatistics::RecvSyn,
+    TcpStatistics::SentSynAck,
+    TcpStatistics::RecvSynAck,
+    TcpStatistics::SentPayload,
+    TcpStatistics::RecvPayload,
+    TcpStatistics::SentFin,
+    TcpStatistics::RecvFin,
+    TcpStatistics::SentRst,
+    TcpStatistics::RecvRst,
+];
+
+/// all counters of a pipeline at a point in time
+#[derive(Clone)]
+pub struct CounterSnapshot {
+    pub tsc: u64,
+    pub tcp_c: TcpCounter,
+    pub tcp_s: TcpCounter,
+    pub ext_c: EngineCounter,
+    pub ext_s: EngineCounter,
+}
+
+impl CounterSnapshot {
+    pub fn new(
+        tsc: u64,
+        tcp_c: &TcpCounter,
+        tcp_s: &TcpCounter,
+        ext_c: &EngineCounter,
+        ext_s: &EngineCounter,
+    ) -> CounterSnapshot {
+        CounterSnapshot {
+            tsc,
+            tcp_c: tcp_c.clone(),
+            tcp_s: tcp_s.clone(),
+            ext_c: *ext_c,
+            ext_s: *ext_s,
+        }
+    }
+}
+
+/// counters of a phase of the load profile, given by the snapshots at its start and its end
+pub struct PhaseCounters {
+    pub name: String,
+    pub start: CounterSnapshot,
+    pub end: CounterSnapshot,
+    pub max_concurrent: usize,
+}
+
+/// collects the counters of the phases of a load profile
+pub struct PhaseRecorder {
+    /// counters at the start of the current phase
+    start: Option<CounterSnapshot>,
+    max_concurrent: usize,
+    phases: Vec<PhaseCounters>,
+}
+
+impl PhaseRecorder {
+    pub fn new() -> PhaseRecorder {
+        PhaseRecorder {
+            start: None,
+            max_concurrent: 0,
+            phases: Vec::new(),
+        }
+    }
+
+    pub fn start(&mut self, snapshot: CounterSnapshot) {
+        self.start = Some(snapshot);
+        self.max_concurrent = 0;
+    }
+
+    /// notes the number of concurrent client connections
+    #[inline]
+    pub fn observe(&mut self, concurrent: usize) {
+        self.max_concurrent = cmp::max(self.max_concurrent, concurrent);
+    }
+
+    /// ends the current phase with the snapshot, which also starts the next phase
+    pub fn end_phase(&mut self, name: &str, snapshot: CounterSnapshot) {
+        if let Some(start) = self.start.take() {
+            self.phases.push(PhaseCounters {
+                name: name.to_string(),
+                start,
+                end: snapshot.clone(),
+                max_concurrent: self.max_concurrent,
+            });
+        }
+        self.start(snapshot);
+    }
+
+    pub fn phases(&self) -> &[PhaseCounters] {
+        &self.phases
+    }
+}
+
+pub fn print_phase_counters(pipeline_id: &PipelineId, phases: &[PhaseCounters], cpu_clock: u64) {
+    for phase in phases {
+        let secs = (phase.end.tsc - phase.start.tsc) as f64 / cpu_clock as f64;
+        let syns = phase.end.tcp_c[TcpStatistics::SentSyn] - phase.start.tcp_c[TcpStatistics::SentSyn];
+        println!(
+            "{}: phase {}, {:.3} s, {:.0} SYNs per second, max concurrent client connections = {} (client / server side)",
+            pipeline_id,
+            phase.name,
+            secs,
+            if secs > 0.0 { syns as f64 / secs } else { 0.0 },
+            phase.max_concurrent,
+        );
+        for stat in PHASE_TCP_STATISTICS.iter() {
+            println!(
+                "{:>28} = {:9} / {:9}",
+                format!("{:?}", stat),
+                phase.end.tcp_c[*stat] - phase.start.tcp_c[*stat],
+                phase.end.tcp_s[*stat] - phase.start.tcp_s[*stat],
+            );
+        }
+        for stat in EngineStatistics::iter() {
+            println!(
+                "{:>28} = {:9} / {:9}",
+                stat.to_string(),
+                phase.end.ext_c[stat] - phase.start.ext_c[stat],
+                phase.end.ext_s[stat] - phase.start.ext_s[stat],
+            );
+        }
+    }
+}
+
+/// target and achieved load of the client side in a second of a load series
+pub struct RateSample {
+    pub second: usize,
+    /// mean of the target rate over the second
+    pub target_cps: f64,
+    pub syns: usize,
+    pub target_concurrent: usize,
+    pub max_concurrent: usize,
+}
+
+/// samples the achieved rate of SYNs per second
+pub struct RateRecorder {
+    cycles_per_second: u64,
+    /// TSC at the start of the current second, zero before the start
+    start: u64,
+    /// SYNs sent before the current second
+    syns: usize,
+    target_sum: f64,
+    ticks: usize,
+    target_concurrent: usize,
+    max_concurrent: usize,
+    samples: Vec<RateSample>,
+}
+
+impl RateRecorder {
+    pub fn new(cpu_clock: u64) -> RateRecorder {
+        RateRecorder {
+            cycles_per_second: cpu_clock,
+            start: 0,
+            syns: 0,
+            target_sum: 0.0,
+            ticks: 0,
+            target_concurrent: 0,
+            max_concurrent: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn start(&mut self, now: u64, syns: usize) {
+        self.start = now;
+        self.syns = syns;
+    }
+
+    /// notes the target and the state of the pipeline at now, syns is the total of the SYNs sent
+    pub fn observe(&mut self, now: u64, syns: usize, concurrent: usize, target_cps: f64, target_concurrent: usize) {
+        if self.start == 0 {
+            return;
+        }
+        self.target_sum += target_cps;
+        self.ticks += 1;
+        self.target_concurrent = cmp::max(self.target_concurrent, target_concurrent);
+        self.max_concurrent = cmp::max(self.max_concurrent, concurrent);
+        if now >= self.start + self.cycles_per_second {
+            self.samples.push(RateSample {
+                second: self.samples.len(),
+                target_cps: self.target_sum / self.ticks as f64,
+                syns: syns - self.syns,
+                target_concurrent: self.target_concurrent,
+                max_concurrent: self.max_concurrent,
+            });
+            self.start += self.cycles_per_second;
+            self.syns = syns;
+            self.target_sum = 0.0;
+            self.ticks = 0;
+            self.target_concurrent = 0;
+            self.max_concurrent = 0;
+        }
+    }
+
+    pub fn samples(&self) -> &[RateSample] {
+        &self.samples
+    }
+}
+
+pub fn print_rate_samples(pipeline_id: &PipelineId, samples: &[RateSample]) {
+    println!("{}: client load per second (target / achieved)", pipeline_id);
+    println!("{:>8} {:>21} {:>21}", "second", "cps", "concurrent");
+    for sample in samples {
+        println!(
+            "{:>8} {:>9.0} / {:>9} {:>9} / {:>9}",
+            sample.second, sample.target_cps, sample.syns, sample.target_concurrent, sample.max_concurrent
+        );
+    }
+}
+
+/// throughput of the client side in the steady state of a run with a duration, connections are completed, when the
+/// DUT acknowledges our FIN
+pub fn print_steady_state(pipeline_id: &PipelineId, steady: &PhaseCounters, cpu_clock: u64) {
+    let secs = (steady.end.tsc - steady.start.tsc) as f64 / cpu_clock as f64;
+    let per_second = |stat: TcpStatistics| {
+        if secs > 0.0 {
+            (steady.end.tcp_c[stat] - steady.start.tcp_c[stat]) as f64 / secs
+        } else {
+            0.0
+        }
+    };
+    println!(
+        "{}: steady state over {:.3} s: {:.0} SYNs, {:.0} established, {:.0} completed connections per second",
+        pipeline_id,
+        secs,
+        per_second(TcpStatistics::SentSyn),
+        per_second(TcpStatistics::RecvSynAck),
+        per_second(TcpStatistics::RecvAck4Fin),
+    );
+}
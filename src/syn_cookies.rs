@@ -0,0 +1,123 @@
+use std::hash::Hasher;
+
+use fnv::FnvHasher;
+use uuid::Uuid;
+
+/// MSS values, which can be encoded into a cookie (RFC 4987), the peer's MSS is rounded down to one of these
+const MSS_TABLE: [u16; 4] = [536, 1300, 1440, 1460];
+/// the time counter advances every 64 seconds
+const COUNTER_SHIFT_SECS: u64 = 64;
+/// cookies from the current and the previous counter period are accepted
+const MAX_COUNTER_AGE: u32 = 1;
+
+/// stateless generation and validation of SYN cookies. The initial sequence no of the SYN-ACK is built as follows:
+/// 5 bits time counter, 25 bits keyed hash over the sockets, the peer's initial sequence no and the counter, 2 bits MSS
+/// index.
+pub struct SynCookies {
+    secret: u64,
+    cpu_clock: u64,
+}
+
+impl SynCookies {
+    pub fn new(cpu_clock: u64) -> SynCookies {
+        // a random key per pipeline
+        let uuid = Uuid::new_v4();
+        let b = uuid.as_bytes();
+        let secret = u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]);
+        SynCookies { secret, cpu_clock }
+    }
+
+    #[inline]
+    fn counter(&self, now: u64) -> u32 {
+        (now / self.cpu_clock / COUNTER_SHIFT_SECS) as u32
+    }
+
+    #[inline]
+    fn hash(&self, src: (u32, u16), dst: (u32, u16), peer_isn: u32, counter: u32) -> u32 {
+        let mut hasher = FnvHasher::default();
+        hasher.write_u64(self.secret);
+        hasher.write_u32(src.0);
+        hasher.write_u16(src.1);
+        hasher.write_u32(dst.0);
+        hasher.write_u16(dst.1);
+        hasher.write_u32(peer_isn);
+        hasher.write_u32(counter);
+        let h = hasher.finish();
+        (h ^ (h >> 32)) as u32
+    }
+
+    /// the initial sequence no for the SYN-ACK, src is the socket of the peer, dst our socket, now in cycles
+    #[inline]
+    pub fn generate(&self, src: (u32, u16), dst: (u32, u16), peer_isn: u32, mss: Option<u16>, now: u64) -> u32 {
+        let counter = self.counter(now);
+        let mss = mss.unwrap_or(MSS_TABLE[0]);
+        let mss_index = MSS_TABLE.iter().rposition(|m| *m <= mss).unwrap_or(0) as u32;
+        ((counter & 0x1F) << 27) | (self.hash(src, dst, peer_isn, counter) & 0x07FF_FFFC) | mss_index
+    }
+
+    /// checks the acknowledgement no of the final ACK of the handshake and returns the encoded MSS, if the cookie is
+    /// valid
+    #[inline]
+    pub fn validate(&self, src: (u32, u16), dst: (u32, u16), seqn: u32, ackn: u32, now: u64) -> Option<u16> {
+        let cookie = ackn.wrapping_sub(1);
+        let peer_isn = seqn.wrapping_sub(1);
+        let current = self.counter(now);
+        let age = (current.wrapping_sub(cookie >> 27)) & 0x1F;
+        if age > MAX_COUNTER_AGE {
+            return None;
+        }
+        let counter = current.wrapping_sub(age);
+        if (self.hash(src, dst, peer_isn, counter) ^ cookie) & 0x07FF_FFFC == 0 {
+            Some(MSS_TABLE[(cookie & 0x03) as usize])
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPU_CLOCK: u64 = 1000;
+    const PERIOD: u64 = COUNTER_SHIFT_SECS * CPU_CLOCK;
+    const SRC: (u32, u16) = (0x0a00_0001, 40000);
+    const DST: (u32, u16) = (0x0a00_0002, 80);
+
+    /// the sequence and acknowledgement no of the final ACK of the handshake
+    fn final_ack(cookies: &SynCookies, mss: Option<u16>, peer_isn: u32, now: u64) -> (u32, u32) {
+        let cookie = cookies.generate(SRC, DST, peer_isn, mss, now);
+        (peer_isn.wrapping_add(1), cookie.wrapping_add(1))
+    }
+
+    #[test]
+    fn valid_cookie_returns_rounded_mss() {
+        let cookies = SynCookies::new(CPU_CLOCK);
+        for &(mss, expected) in &[(Some(1460), 1460), (Some(1400), 1300), (Some(9000), 1460), (None, 536)] {
+            let (seqn, ackn) = final_ack(&cookies, mss, 0xffff_ffff, 5 * PERIOD);
+            assert_eq!(cookies.validate(SRC, DST, seqn, ackn, 5 * PERIOD), Some(expected));
+        }
+    }
+
+    #[test]
+    fn forged_cookie_is_rejected() {
+        let cookies = SynCookies::new(CPU_CLOCK);
+        let (seqn, ackn) = final_ack(&cookies, Some(1460), 1000, PERIOD);
+        assert_eq!(cookies.validate(SRC, DST, seqn, ackn ^ 0x100, PERIOD), None);
+        assert_eq!(cookies.validate(SRC, DST, seqn + 1, ackn, PERIOD), None);
+        assert_eq!(cookies.validate(SRC, (DST.0, 443), seqn, ackn, PERIOD), None);
+        // another pipeline has another secret
+        assert_eq!(SynCookies::new(CPU_CLOCK).validate(SRC, DST, seqn, ackn, PERIOD), None);
+    }
+
+    #[test]
+    fn cookie_expires_after_the_previous_period() {
+        let cookies = SynCookies::new(CPU_CLOCK);
+        let now = 31 * PERIOD + 10;
+        let (seqn, ackn) = final_ack(&cookies, Some(1460), 1000, now);
+        assert!(cookies.validate(SRC, DST, seqn, ackn, now + PERIOD).is_some());
+        assert!(cookies.validate(SRC, DST, seqn, ackn, now + 2 * PERIOD).is_none());
+        // the 5 bits counter wraps around
+        assert!(cookies.validate(SRC, DST, seqn, ackn, now + 33 * PERIOD).is_none());
+    }
+}
@@ -0,0 +1,363 @@
+use std::cmp;
+use std::hash::Hasher;
+
+use eui48::MacAddress;
+use fnv::FnvHasher;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use netfcts::system::get_mac_from_ifname;
+use netfcts::tcp_common::L234Data;
+
+use {TargetConfig, TargetSelection, TargetSelectionConfig};
+
+/// points on the hash ring per unit of weight
+const RING_POINTS_PER_WEIGHT: u64 = 16;
+/// limits the size of the hash ring for large target ranges, the points of all ranges are then reduced by the same
+/// factor, so that the shares of the ranges stay in proportion to their weights
+const MAX_RING_POINTS: u64 = 65536;
+
+/// all combinations of a range of hosts with a range of ports, which sit behind the same next hop
+#[derive(Clone, Debug)]
+pub struct TargetRange {
+    pub id: String,
+    pub mac: MacAddress,
+    pub first_ip: u32,
+    pub n_ips: u32,
+    pub first_port: u16,
+    pub n_ports: u32,
+    /// weight of each socket of the range
+    pub weight: u32,
+}
+
+impl TargetRange {
+    #[inline]
+    fn len(&self) -> usize {
+        self.n_ips as usize * self.n_ports as usize
+    }
+}
+
+/// the target sockets of the client side. The sockets of a range are computed from their index, consecutive indices
+/// address consecutive hosts of the range.
+#[derive(Clone)]
+pub struct Targets {
+    ranges: Vec<TargetRange>,
+    /// index of the first socket of each range
+    starts: Vec<usize>,
+    len: usize,
+}
+
+impl Targets {
+    /// the MAC address is resolved once per range
+    pub fn from_config(configs: &[TargetConfig]) -> Targets {
+        let mut ranges = Vec::with_capacity(configs.len());
+        for config in configs {
+            let (first_ip, n_ips) = config.hosts();
+            let last_port = config.last_port.unwrap_or(config.port);
+            assert!(last_port >= config.port, "last_port of target {} is below port", config.id);
+            ranges.push(TargetRange {
+                id: config.id.clone(),
+                mac: config
+                    .mac
+                    .unwrap_or_else(|| get_mac_from_ifname(config.linux_if.as_ref().unwrap()).unwrap()),
+                first_ip,
+                n_ips,
+                first_port: config.port,
+                n_ports: (last_port - config.port) as u32 + 1,
+                weight: config.weight(),
+            });
+        }
+        Targets::new(ranges)
+    }
+
+    pub fn new(ranges: Vec<TargetRange>) -> Targets {
+        let mut starts = Vec::with_capacity(ranges.len());
+        let mut len = 0;
+        for range in &ranges {
+            starts.push(len);
+            len += range.len();
+        }
+        Targets { ranges, starts, len }
+    }
+
+    /// number of target sockets
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub fn ranges(&self) -> &[TargetRange] {
+        &self.ranges
+    }
+
+    /// the range of the socket with this index
+    #[inline]
+    fn range_index(&self, index: usize) -> usize {
+        match self.starts.binary_search(&index) {
+            Ok(r) => r,
+            Err(r) => r - 1,
+        }
+    }
+
+    /// the target socket with this index, server_id is left empty to avoid an allocation per packet, see id()
+    #[inline]
+    pub fn get(&self, index: usize) -> L234Data {
+        let r = self.range_index(index);
+        let range = &self.ranges[r];
+        let offset = index - self.starts[r];
+        L234Data {
+            mac: range.mac,
+            ip: range.first_ip + (offset % range.n_ips as usize) as u32,
+            port: range.first_port + (offset / range.n_ips as usize) as u16,
+            server_id: String::new(),
+            index,
+        }
+    }
+
+    #[inline]
+    pub fn id(&self, index: usize) -> &str {
+        &self.ranges[self.range_index(index)].id
+    }
+
+    /// all target sockets with their id
+    pub fn iter(&self) -> impl Iterator<Item = L234Data> + '_ {
+        (0..self.len).map(move |i| {
+            let mut target = self.get(i);
+            target.server_id = self.id(i).to_string();
+            target
+        })
+    }
+}
+
+/// selects the target of each client connection
+pub struct TargetSelector {
+    mode: TargetSelection,
+    targets: Targets,
+    /// round robin: index of the next target
+    next: usize,
+    /// weighted random: running sum of the weights of the ranges
+    cumulative: Vec<u64>,
+    /// consistent hash: (hash, range index), sorted by hash
+    ring: Vec<(u32, u32)>,
+    rng: StdRng,
+}
+
+#[inline]
+fn hash(values: &[u32]) -> u32 {
+    let mut hasher = FnvHasher::default();
+    for value in values {
+        hasher.write_u32(*value);
+    }
+    let h = hasher.finish();
+    (h ^ (h >> 32)) as u32
+}
+
+impl TargetSelector {
+    pub fn new(targets: &Targets, config: &TargetSelectionConfig) -> TargetSelector {
+        assert!(!targets.is_empty(), "no targets configured");
+        let mode = config.mode();
+        let mut cumulative = Vec::new();
+        let mut ring = Vec::new();
+        match mode {
+            TargetSelection::WeightedRandom => {
+                let mut sum = 0u64;
+                for range in targets.ranges() {
+                    sum += range.weight as u64 * range.len() as u64;
+                    cumulative.push(sum);
+                }
+                assert!(sum > 0, "the weights of all targets are zero");
+            }
+            TargetSelection::ConsistentHash => {
+                let points: Vec<f64> = targets
+                    .ranges()
+                    .iter()
+                    .map(|range| range.weight as f64 * range.len() as f64 * RING_POINTS_PER_WEIGHT as f64)
+                    .collect();
+                let scale = (MAX_RING_POINTS as f64 / points.iter().sum::<f64>()).min(1.0);
+                for (r, points) in points.iter().enumerate() {
+                    // a range with a weight keeps at least one point
+                    let n = if *points > 0.0 { cmp::max((points * scale).round() as u64, 1) } else { 0 };
+                    for point in 0..n {
+                        ring.push((hash(&[r as u32, point as u32]), r as u32));
+                    }
+                }
+                assert!(!ring.is_empty(), "the weights of all targets are zero");
+                ring.sort_unstable();
+            }
+            _ => (),
+        }
+        TargetSelector {
+            mode,
+            targets: targets.clone(),
+            next: 0,
+            cumulative,
+            ring,
+            rng: match config.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+        }
+    }
+
+    /// the index of the target for a new connection from the socket (client_ip, client_port)
+    #[inline]
+    pub fn select(&mut self, client_ip: u32, client_port: u16) -> usize {
+        match self.mode {
+            TargetSelection::RoundRobin => {
+                let i = self.next;
+                self.next = (self.next + 1) % self.targets.len();
+                i
+            }
+            TargetSelection::Random => self.rng.gen_range(0..self.targets.len()),
+            TargetSelection::WeightedRandom => {
+                let x = self.rng.gen_range(0..self.cumulative[self.cumulative.len() - 1]);
+                // the first range whose running sum exceeds x
+                let (mut lo, mut hi) = (0, self.cumulative.len());
+                while lo < hi {
+                    let mid = (lo + hi) / 2;
+                    if self.cumulative[mid] <= x {
+                        lo = mid + 1;
+                    } else {
+                        hi = mid;
+                    }
+                }
+                let below = if lo > 0 { self.cumulative[lo - 1] } else { 0 };
+                // each socket of the range covers weight values of x
+                self.targets.starts[lo] + ((x - below) / self.targets.ranges[lo].weight as u64) as usize
+            }
+            TargetSelection::ConsistentHash => {
+                let h = hash(&[client_ip, client_port as u32]);
+                let i = match self.ring.binary_search_by(|point| point.0.cmp(&h)) {
+                    Ok(i) => i,
+                    Err(i) => i,
+                };
+                let r = self.ring[i % self.ring.len()].1 as usize;
+                // within the range the socket is given by a second hash of the client socket
+                let offset = hash(&[client_ip, client_port as u32, r as u32]) as usize % self.targets.ranges[r].len();
+                self.targets.starts[r] + offset
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(id: &str, first_ip: u32, n_ips: u32, first_port: u16, n_ports: u32, weight: u32) -> TargetRange {
+        TargetRange {
+            id: id.to_string(),
+            mac: MacAddress::nil(),
+            first_ip,
+            n_ips,
+            first_port,
+            n_ports,
+            weight,
+        }
+    }
+
+    fn selector(targets: &Targets, mode: TargetSelection) -> TargetSelector {
+        TargetSelector::new(
+            targets,
+            &TargetSelectionConfig {
+                mode: Some(mode),
+                seed: Some(7),
+            },
+        )
+    }
+
+    #[test]
+    fn index_maps_to_socket() {
+        let targets = Targets::new(vec![range("a", 0x0a00_0001, 2, 80, 2, 1), range("b", 0x0b00_0001, 1, 443, 3, 1)]);
+        assert_eq!(targets.len(), 7);
+        let sockets: Vec<(u32, u16)> = targets.iter().map(|t| (t.ip, t.port)).collect();
+        assert_eq!(
+            sockets,
+            vec![
+                (0x0a00_0001, 80),
+                (0x0a00_0002, 80),
+                (0x0a00_0001, 81),
+                (0x0a00_0002, 81),
+                (0x0b00_0001, 443),
+                (0x0b00_0001, 444),
+                (0x0b00_0001, 445),
+            ]
+        );
+        assert_eq!(targets.get(5).index, 5);
+        assert_eq!((targets.id(3), targets.id(4)), ("a", "b"));
+    }
+
+    #[test]
+    fn weighted_random_follows_the_weights() {
+        let targets = Targets::new(vec![range("a", 0x0a00_0001, 1, 80, 1, 1), range("b", 0x0b00_0001, 2, 80, 1, 3)]);
+        let mut selector = selector(&targets, TargetSelection::WeightedRandom);
+        let mut counts = [0usize; 3];
+        for _ in 0..70000 {
+            counts[selector.select(0, 0)] += 1;
+        }
+        // each socket of b has three times the share of a
+        for &(i, expected) in &[(0, 10000.0), (1, 30000.0), (2, 30000.0)] {
+            assert!((counts[i] as f64 - expected).abs() < expected * 0.05, "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn consistent_hash_scales_all_ranges_alike() {
+        // both ranges exceed the size of the ring
+        let targets = Targets::new(vec![
+            range("a", 0x0a00_0000, 4096, 80, 1, 1),
+            range("b", 0x0b00_0000, 4096, 80, 1, 3),
+        ]);
+        let mut selector = selector(&targets, TargetSelection::ConsistentHash);
+        assert_eq!(selector.ring.len() as u64, MAX_RING_POINTS);
+        let mut b = 0;
+        for ip in 0..40 {
+            for port in 1000..2000 {
+                if targets.id(selector.select(0x0a0a_0000 + ip, port)) == "b" {
+                    b += 1;
+                }
+            }
+        }
+        assert!((b as f64 / 40000.0 - 0.75).abs() < 0.03, "share of b {}", b as f64 / 40000.0);
+    }
+
+    #[test]
+    fn consistent_hash_is_stable() {
+        let two = Targets::new(vec![range("a", 0x0a00_0001, 4, 80, 1, 1), range("b", 0x0b00_0001, 4, 80, 1, 1)]);
+        let mut ranges = two.ranges().to_vec();
+        ranges.push(range("c", 0x0c00_0001, 4, 80, 1, 1));
+        let three = Targets::new(ranges);
+        let mut first = selector(&two, TargetSelection::ConsistentHash);
+        let mut second = selector(&two, TargetSelection::ConsistentHash);
+        let mut extended = selector(&three, TargetSelection::ConsistentHash);
+        let mut moved = 0;
+        for port in 1000..2000 {
+            let i = first.select(0x0a0a_0001, port);
+            assert_eq!(i, first.select(0x0a0a_0001, port));
+            assert_eq!(i, second.select(0x0a0a_0001, port));
+            // an additional target only takes over clients, the others keep their target
+            let j = extended.select(0x0a0a_0001, port);
+            if j != i {
+                assert_eq!(three.id(j), "c");
+                moved += 1;
+            }
+        }
+        assert!(moved > 0 && moved < 600, "{} of 1000 clients moved", moved);
+    }
+
+    #[test]
+    fn consistent_hash_includes_the_client_address() {
+        let targets = Targets::new(vec![range("a", 0x0a00_0001, 16, 80, 1, 1)]);
+        let mut selector = selector(&targets, TargetSelection::ConsistentHash);
+        let mut selected: Vec<usize> = (0..64).map(|ip| selector.select(0x0a0a_0000 + ip, 4000)).collect();
+        selected.sort();
+        selected.dedup();
+        assert!(selected.len() > 1);
+    }
+}
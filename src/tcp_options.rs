@@ -0,0 +1,317 @@
+use std::cmp;
+use std::slice;
+
+use e2d2::interface::Pdu;
+use netfcts::tcp_common::tcp_payload_size;
+use netfcts::strip_payload;
+
+use TcpOptionsConfig;
+
+/// size of the tcp header without options
+pub const TCP_HEADER_SIZE: usize = 20;
+const MAX_OPTIONS_SIZE: usize = 40;
+
+const KIND_END: u8 = 0;
+const KIND_NOP: u8 = 1;
+const KIND_MSS: u8 = 2;
+const KIND_WINDOW_SCALE: u8 = 3;
+const KIND_SACK_PERMITTED: u8 = 4;
+
+/// RFC 7323
+pub const MAX_WINDOW_SCALE: u8 = 14;
+/// assumed, if the peer does not announce its MSS (RFC 879)
+pub const DEFAULT_MSS: u16 = 536;
+/// our receive window in bytes, 4* MSS(1460)
+pub const RCV_WINDOW: u32 = 5840;
+
+/// the options of a SYN, respectively SYN-ACK, we are interested in
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TcpOptions {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+}
+
+impl TcpOptions {
+    /// the options we offer. Timestamps are never offered, as we do not carry them on every segment (RFC 7323).
+    pub fn from_config(config: &TcpOptionsConfig) -> TcpOptions {
+        TcpOptions {
+            mss: Some(config.mss()),
+            window_scale: config.window_scale.map(|shift| cmp::min(shift, MAX_WINDOW_SCALE)),
+            sack_permitted: config.sack_permitted.unwrap_or(false),
+        }
+    }
+
+    /// unknown options, including timestamps, are skipped, a malformed option ends the parsing
+    pub fn parse(bytes: &[u8]) -> TcpOptions {
+        let mut options = TcpOptions::default();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                KIND_END => break,
+                KIND_NOP => {
+                    i += 1;
+                    continue;
+                }
+                _ => (),
+            }
+            if i + 1 >= bytes.len() || bytes[i + 1] < 2 || i + bytes[i + 1] as usize > bytes.len() {
+                break;
+            }
+            let len = bytes[i + 1] as usize;
+            let value = &bytes[i + 2..i + len];
+            match (bytes[i], len) {
+                (KIND_MSS, 4) => options.mss = Some(u16::from_be_bytes([value[0], value[1]])),
+                (KIND_WINDOW_SCALE, 3) => options.window_scale = Some(value[0]),
+                (KIND_SACK_PERMITTED, 2) => options.sack_permitted = true,
+                _ => (),
+            }
+            i += len;
+        }
+        options
+    }
+
+    /// our answer in the SYN-ACK to the options received in a SYN: window scaling and SACK are only used if offered
+    /// by both sides, timestamps of the peer are declined
+    pub fn answer(&self, received: &TcpOptions) -> TcpOptions {
+        TcpOptions {
+            mss: self.mss,
+            window_scale: if received.window_scale.is_some() { self.window_scale } else { None },
+            sack_permitted: self.sack_permitted && received.sack_permitted,
+        }
+    }
+
+    /// encodes the options into buf, the returned length is a multiple of 4
+    pub fn encode(&self, buf: &mut [u8; MAX_OPTIONS_SIZE]) -> usize {
+        let mut i = 0;
+        if let Some(mss) = self.mss {
+            buf[i..i + 2].copy_from_slice(&[KIND_MSS, 4]);
+            buf[i + 2..i + 4].copy_from_slice(&mss.to_be_bytes());
+            i += 4;
+        }
+        if self.sack_permitted {
+            buf[i..i + 4].copy_from_slice(&[KIND_NOP, KIND_NOP, KIND_SACK_PERMITTED, 2]);
+            i += 4;
+        }
+        if let Some(shift) = self.window_scale {
+            buf[i..i + 4].copy_from_slice(&[KIND_NOP, KIND_WINDOW_SCALE, 3, shift]);
+            i += 4;
+        }
+        i
+    }
+}
+
+/// the values agreed on in the handshake, which apply for the rest of the connection
+#[derive(Debug, Clone, Copy)]
+pub struct NegotiatedOptions {
+    /// maximum segment size of the peer, 0 if options are not used
+    pub snd_mss: u16,
+    /// scaling of the window received from, respectively sent to, the peer
+    pub snd_wscale: u8,
+    pub rcv_wscale: u8,
+    pub sack_permitted: bool,
+}
+
+impl NegotiatedOptions {
+    pub fn new() -> NegotiatedOptions {
+        NegotiatedOptions {
+            snd_mss: 0,
+            snd_wscale: 0,
+            rcv_wscale: 0,
+            sack_permitted: false,
+        }
+    }
+
+    /// derives the connection parameters from the options sent by us and the options received from the peer. Our
+    /// segments are not larger than our own MSS, even if the peer announces a larger one.
+    pub fn negotiate(sent: &TcpOptions, received: &TcpOptions) -> NegotiatedOptions {
+        let (snd_wscale, rcv_wscale) = match (sent.window_scale, received.window_scale) {
+            (Some(ours), Some(theirs)) => (cmp::min(theirs, MAX_WINDOW_SCALE), ours),
+            _ => (0, 0),
+        };
+        NegotiatedOptions {
+            snd_mss: cmp::min(received.mss.unwrap_or(DEFAULT_MSS), sent.mss.unwrap_or(u16::MAX)),
+            snd_wscale,
+            rcv_wscale,
+            sack_permitted: sent.sack_permitted && received.sack_permitted,
+        }
+    }
+
+    /// the window field of segments other than SYN and SYN-ACK, which the peer shifts left by rcv_wscale
+    #[inline]
+    pub fn rcv_window(&self) -> u16 {
+        cmp::max(RCV_WINDOW >> self.rcv_wscale, 1) as u16
+    }
+}
+
+/// the option bytes of the tcp header in p
+#[inline]
+pub fn tcp_option_bytes(p: &Pdu) -> &[u8] {
+    let tcp = p.headers().tcp(2);
+    let len = cmp::min((tcp.data_offset() as usize * 4).saturating_sub(TCP_HEADER_SIZE), MAX_OPTIONS_SIZE);
+    // the options directly follow the fixed part of the header
+    unsafe { slice::from_raw_parts((tcp as *const _ as *const u8).add(TCP_HEADER_SIZE), len) }
+}
+
+/// appends options to a tcp header, p must neither carry options nor payload
+#[inline]
+pub fn append_tcp_options(p: &mut Pdu, options: &[u8]) {
+    let len = options.len();
+    let ip_sz = p.headers().ip(1).length();
+    p.add_to_payload_tail(len).expect("insufficient tail room for tcp options");
+    p.headers_mut().ip_mut(1).set_length(ip_sz + len as u16);
+    p.copy_payload_from_u8_slice(options, 2); // 2 -> tcp_payload, which starts after the fixed header
+    p.headers_mut().tcp_mut(2).set_data_offset(((TCP_HEADER_SIZE + len) / 4) as u8);
+}
+
+/// appends the encoded options to a tcp header
+#[inline]
+pub fn write_tcp_options(p: &mut Pdu, options: &TcpOptions) {
+    let mut buf = [0u8; MAX_OPTIONS_SIZE];
+    let len = options.encode(&mut buf);
+    if len > 0 {
+        append_tcp_options(p, &buf[..len]);
+    }
+}
+
+/// cuts the tcp payload down to mss bytes, the bytes beyond are appended to rest for further segments
+#[inline]
+pub fn split_payload(p: &mut Pdu, mss: usize, rest: &mut Vec<u8>) {
+    let payload_sz = tcp_payload_size(p);
+    if payload_sz > mss {
+        rest.extend_from_slice(&p.get_payload(2)[mss..payload_sz]);
+        trim_payload(p, mss);
+    }
+}
+
+/// cuts the tcp payload down to n bytes, the bytes beyond are dropped
+#[inline]
+pub fn trim_payload(p: &mut Pdu, n: usize) {
+    let payload_sz = tcp_payload_size(p);
+    if payload_sz > n {
+        let cut = payload_sz - n;
+        p.trim_payload_size(cut);
+        let ip_sz = p.headers().ip(1).length();
+        p.headers_mut().ip_mut(1).set_length(ip_sz - cut as u16);
+    }
+}
+
+/// replaces the tcp payload of p by bytes
+#[inline]
+pub fn set_payload(p: &mut Pdu, bytes: &[u8]) -> Result<(), &'static str> {
+    strip_payload(p);
+    if bytes.len() > 0 {
+        let ip_sz = p.headers().ip(1).length();
+        p.add_to_payload_tail(bytes.len()).map_err(|_| "insufficient tail room")?;
+        p.headers_mut().ip_mut(1).set_length(ip_sz + bytes.len() as u16);
+        p.copy_payload_from_u8_slice(bytes, 2); // 2 -> tcp_payload
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer() -> TcpOptions {
+        TcpOptions {
+            mss: Some(1460),
+            window_scale: Some(7),
+            sack_permitted: true,
+        }
+    }
+
+    #[test]
+    fn encode_and_parse() {
+        let mut buf = [0u8; MAX_OPTIONS_SIZE];
+        let len = offer().encode(&mut buf);
+        assert_eq!(len, 12);
+        assert_eq!(&buf[..4], &[KIND_MSS, 4, 0x05, 0xb4]);
+        assert_eq!(&buf[4..8], &[KIND_NOP, KIND_NOP, KIND_SACK_PERMITTED, 2]);
+        assert_eq!(&buf[8..12], &[KIND_NOP, KIND_WINDOW_SCALE, 3, 7]);
+
+        for options in &[offer(), TcpOptions::default(), TcpOptions { sack_permitted: false, ..offer() }] {
+            let len = options.encode(&mut buf);
+            assert_eq!(len % 4, 0);
+            assert_eq!(TcpOptions::parse(&buf[..len]), *options);
+        }
+    }
+
+    #[test]
+    fn parse_skips_unknown_and_stops_at_malformed_options() {
+        // timestamps (kind 8) of the peer are skipped
+        let bytes = [KIND_NOP, KIND_NOP, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0, KIND_MSS, 4, 0x05, 0xb4];
+        assert_eq!(TcpOptions::parse(&bytes).mss, Some(1460));
+        // an unknown option of kind 30 in between
+        let bytes = [KIND_NOP, 30, 4, 0xff, 0xff, KIND_MSS, 4, 0x02, 0x18, KIND_END, KIND_SACK_PERMITTED, 2];
+        let options = TcpOptions::parse(&bytes);
+        assert_eq!(options.mss, Some(536));
+        assert!(!options.sack_permitted);
+        // the length of the window scale option exceeds the buffer
+        let bytes = [KIND_MSS, 4, 0x05, 0xb4, KIND_WINDOW_SCALE, 3];
+        let options = TcpOptions::parse(&bytes);
+        assert_eq!(options.mss, Some(1460));
+        assert_eq!(options.window_scale, None);
+        // a length below 2 would loop forever
+        let bytes = [KIND_SACK_PERMITTED, 0, KIND_MSS, 4, 0x05, 0xb4];
+        assert_eq!(TcpOptions::parse(&bytes), TcpOptions::default());
+        // a wrong length of a known option is ignored
+        let bytes = [KIND_MSS, 3, 0x05, KIND_SACK_PERMITTED, 2];
+        let options = TcpOptions::parse(&bytes);
+        assert_eq!(options.mss, None);
+        assert!(options.sack_permitted);
+    }
+
+    #[test]
+    fn answer_uses_options_offered_by_both_sides() {
+        let received = TcpOptions {
+            mss: Some(1400),
+            window_scale: Some(9),
+            sack_permitted: false,
+        };
+        let answer = offer().answer(&received);
+        assert_eq!(answer.mss, Some(1460));
+        assert_eq!(answer.window_scale, Some(7));
+        assert!(!answer.sack_permitted);
+
+        let answer = offer().answer(&TcpOptions::default());
+        assert_eq!(answer.window_scale, None);
+        assert!(!answer.sack_permitted);
+    }
+
+    #[test]
+    fn negotiate() {
+        let received = TcpOptions {
+            mss: Some(1400),
+            window_scale: Some(20),
+            sack_permitted: true,
+        };
+        let negotiated = NegotiatedOptions::negotiate(&offer(), &received);
+        assert_eq!(negotiated.snd_mss, 1400);
+        assert_eq!(negotiated.snd_wscale, MAX_WINDOW_SCALE);
+        assert_eq!(negotiated.rcv_wscale, 7);
+        assert!(negotiated.sack_permitted);
+        assert_eq!(negotiated.rcv_window(), (RCV_WINDOW >> 7) as u16);
+
+        // window scaling requires both sides
+        let negotiated = NegotiatedOptions::negotiate(&offer(), &TcpOptions::default());
+        assert_eq!(negotiated.snd_mss, DEFAULT_MSS);
+        assert_eq!((negotiated.snd_wscale, negotiated.rcv_wscale), (0, 0));
+        assert!(!negotiated.sack_permitted);
+        assert_eq!(negotiated.rcv_window(), RCV_WINDOW as u16);
+
+        // a jumbo MSS of the peer is capped by our MSS
+        let jumbo = TcpOptions {
+            mss: Some(9000),
+            ..Default::default()
+        };
+        assert_eq!(NegotiatedOptions::negotiate(&offer(), &jumbo).snd_mss, 1460);
+    }
+
+    #[test]
+    fn advertised_window_is_never_zero() {
+        let mut negotiated = NegotiatedOptions::new();
+        negotiated.rcv_wscale = MAX_WINDOW_SCALE;
+        assert_eq!(negotiated.rcv_window(), 1);
+    }
+}